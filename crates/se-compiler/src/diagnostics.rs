@@ -0,0 +1,156 @@
+use std::fmt;
+
+use crate::source::{self, SourceMap, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A message about the source code, located by span and resolved to a line
+/// and column so it can be rendered without access to the original files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub file_name: String,
+    pub line: usize,
+    pub column: usize,
+    pub source_line: String,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        message: impl Into<String>,
+        span: Span,
+        sources: &SourceMap,
+    ) -> Self {
+        let (file_name, source) = match sources.get(span.file) {
+            Some(file) => (file.name.as_str(), file.content.as_str()),
+            None => ("<unknown>", ""),
+        };
+        let (line, column) = source::line_col(source, span.start);
+
+        Diagnostic {
+            severity,
+            message: message.into(),
+            span,
+            file_name: file_name.to_owned(),
+            line,
+            column,
+            source_line: source::line_at(source, span.start).to_owned(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Renders the diagnostic with the offending source line and a caret
+    /// underline below the span, e.g.:
+    ///
+    /// ```text
+    /// error: Expected ';' at the end of statement
+    ///  --> main.se:3:13
+    ///   |
+    /// 3 |   u128 x = 5
+    ///   |             ^
+    /// ```
+    pub fn render(&self) -> String {
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        // Only underline the part of the span that sits on the first line,
+        // one caret per character rather than per byte
+        let width = self
+            .source_line
+            .chars()
+            .skip(self.column - 1)
+            .scan(0, |bytes, c| {
+                let start = *bytes;
+                *bytes += c.len_utf8();
+                (start < self.span.len()).then_some(())
+            })
+            .count()
+            .max(1);
+
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+        out.push_str(&format!(
+            "{}--> {}:{}:{}\n",
+            gutter, self.file_name, self.line, self.column
+        ));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line_number, self.source_line));
+        out.push_str(&format!(
+            "{} | {}{}\n",
+            gutter,
+            " ".repeat(self.column - 1),
+            "^".repeat(width)
+        ));
+        for note in &self.notes {
+            out.push_str(&format!("{} = note: {}\n", gutter, note));
+        }
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_snippet() {
+        let source = "$state {\n  address creator\n}\n";
+        let sources = SourceMap::new();
+        let file = sources.add("main.seh", source);
+
+        let start = source.find("creator").unwrap();
+        let span = Span::new(file, start, start + "creator".len());
+
+        let diagnostic = Diagnostic::new(
+            Severity::Error,
+            "Expected ';' at the end of the state variable declaration",
+            span,
+            &sources,
+        )
+        .with_note("state variables are declared as `type name;`");
+
+        assert_eq!((diagnostic.line, diagnostic.column), (2, 11));
+        assert_eq!(
+            diagnostic.render(),
+            "error: Expected ';' at the end of the state variable declaration\n \
+             --> main.seh:2:11\n  \
+             |\n\
+             2 |   address creator\n  \
+             |           ^^^^^^^\n  \
+             = note: state variables are declared as `type name;`\n"
+        );
+    }
+}
@@ -1 +1,1276 @@
+use std::collections::HashMap;
 
+use se_opcodes::codes::{self, Opcode};
+use se_opcodes::container::{Constant, Container, FunctionEntry, StateEntry, ValueType};
+use se_opcodes::registry::StateValue;
+
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::parser::{ASTNode, ASTNodeKind, BinaryOp, NodeId, UnaryOp, VariableType};
+use crate::semantics::{this_member, SymbolKind, SymbolTable};
+use crate::source::{SourceMap, Span};
+
+/// The register holding `this.caller`, it is never written by procedures.
+pub const CALLER: u8 = 0;
+
+/// Where a state variable is stored. Slots are numbered in declaration order
+/// and are what the `SGET`/`SSET`/`SMGET`/`SMSET` opcodes address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateSlot {
+    pub index: u8,
+    pub var_type: VariableType,
+}
+
+impl StateSlot {
+    /// The value the slot holds before it is first written. Table entries
+    /// that were never set read as the default of the table's value type,
+    /// or of the innermost value type for nested tables.
+    pub fn default_value(&self) -> StateValue<'static> {
+        let mut var_type = &self.var_type;
+        while let VariableType::Table(_, value) = var_type {
+            var_type = value;
+        }
+        default_value(var_type)
+    }
+}
+
+/// Assigns a slot to every state variable of a contract.
+#[derive(Debug, Default)]
+pub struct StateLayout {
+    slots: HashMap<String, StateSlot>,
+}
+
+impl StateLayout {
+    pub fn new(symbols: &SymbolTable, sources: &SourceMap) -> Result<Self, Box<Diagnostic>> {
+        let mut slots = HashMap::new();
+
+        for (index, symbol) in symbols.state_variables().enumerate() {
+            let SymbolKind::State { var_type, .. } = &symbol.kind else {
+                continue;
+            };
+            let Ok(index) = u8::try_from(index) else {
+                return Err(Box::new(Diagnostic::new(
+                    Severity::Error,
+                    "Too many state variables, at most 256 are supported",
+                    symbol.span,
+                    sources,
+                )));
+            };
+
+            let slot = StateSlot {
+                index,
+                var_type: var_type.clone(),
+            };
+            slots.insert(symbol.name.clone(), slot);
+        }
+
+        Ok(StateLayout { slots })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&StateSlot> {
+        self.slots.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The state variables in slot order.
+    pub fn entries(&self) -> Vec<StateEntry> {
+        let mut entries: Vec<StateEntry> = self
+            .slots
+            .iter()
+            .map(|(name, slot)| StateEntry {
+                index: slot.index,
+                name: name.clone(),
+                value_type: value_type(&slot.var_type),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.index);
+        entries
+    }
+}
+
+/// Lowers a read of `slot` into register `dst`. Reading a table entry takes
+/// the register holding the key and becomes `SMGET`, which yields the slot's
+/// default value for keys that were never written. Entries of nested tables
+/// are stored in the outer table under the keys of every level joined by
+/// `KEY`.
+pub fn lower_state_read(slot: &StateSlot, key: Option<u8>, dst: u8) -> Opcode {
+    match key {
+        Some(key) => Opcode::SMGET(slot.index, key, dst),
+        None => Opcode::SGET(slot.index, dst),
+    }
+}
+
+/// Lowers a write of register `src` into `slot`, or into the table entry
+/// for the key held in register `key`.
+pub fn lower_state_write(slot: &StateSlot, key: Option<u8>, src: u8) -> Opcode {
+    match key {
+        Some(key) => Opcode::SMSET(src, slot.index, key),
+        None => Opcode::SSET(src, slot.index),
+    }
+}
+
+/// The zero value of a type, used for unset state.
+pub fn default_value(var_type: &VariableType) -> StateValue<'static> {
+    match var_type {
+        VariableType::U128 => StateValue::Uint128(0),
+        VariableType::U8 => StateValue::Uint8(0),
+        VariableType::Bool => StateValue::Bool(false),
+        VariableType::String => StateValue::String(""),
+        VariableType::Address | VariableType::Array(_) => StateValue::ByteArray(Vec::new()),
+        VariableType::Table(..) => StateValue::Map(HashMap::new()),
+    }
+}
+
+/// The type a compiled contract records for a state variable.
+pub fn value_type(var_type: &VariableType) -> ValueType {
+    match var_type {
+        VariableType::U8 => ValueType::U8,
+        VariableType::U128 => ValueType::U128,
+        VariableType::Bool => ValueType::Bool,
+        VariableType::String => ValueType::String,
+        VariableType::Address => ValueType::Address,
+        VariableType::Array(element) => ValueType::Array(Box::new(value_type(element))),
+        VariableType::Table(key, value) => {
+            ValueType::Table(Box::new(value_type(key)), Box::new(value_type(value)))
+        }
+    }
+}
+
+/// A procedure lowered to opcodes. Its position in `Program::functions` is
+/// the index `CALL` refers to it by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledFunction {
+    pub name: String,
+    pub public: bool,
+    pub mutates: bool,
+    pub constructor_only: bool,
+    pub params: u8,
+    pub code: Vec<Opcode>,
+}
+
+/// A contract lowered to opcodes, along with the state layout and the
+/// constant pool the opcodes address.
+#[derive(Debug, Default)]
+pub struct Program {
+    pub layout: StateLayout,
+    pub functions: Vec<CompiledFunction>,
+    pub constants: Vec<Constant>,
+}
+
+impl Program {
+    /// Looks up a procedure along with its `CALL` index.
+    pub fn function(&self, name: &str) -> Option<(u8, &CompiledFunction)> {
+        self.functions
+            .iter()
+            .position(|function| function.name == name)
+            .map(|index| (index as u8, &self.functions[index]))
+    }
+
+    /// The contract as it is written to disk, without debug info.
+    pub fn container(&self) -> Container {
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| FunctionEntry {
+                name: function.name.clone(),
+                public: function.public,
+                mutates: function.mutates,
+                constructor_only: function.constructor_only,
+                params: function.params,
+                code: codes::encode(&function.code),
+            })
+            .collect();
+
+        Container {
+            compiler_version: crate::VERSION.to_owned(),
+            functions,
+            constants: self.constants.clone(),
+            state: self.layout.entries(),
+            debug: None,
+        }
+    }
+}
+
+/// Lowers every procedure of a contract that passed the semantic checks,
+/// `init` included, in declaration order.
+///
+/// Procedures share one register file. `r0` holds `this.caller`, all other
+/// registers are temporaries. Params and locals live in local slots of the
+/// procedure's own frame, `LOAD(register, slot)` writes a slot and
+/// `STORE(slot, register)` reads one. Arithmetic keeps its result in the
+/// first operand, so `ADD(1, 2)` leaves `r1 + r2` in `r1`.
+///
+/// Arguments are passed in `r1` and up, the callee stores them in its first
+/// slots before anything else. A return value is left in `r1`. Callers
+/// save the temporaries they still need in spare slots around a `CALL`.
+///
+/// `if` and `while` become conditional jumps to byte offsets in the
+/// procedure's code, and `&&` and `||` skip their right operand when the
+/// left one decides the result.
+///
+/// Registers hold values of any type, so widening a `u8` to a `u128` is free
+/// and only casts to `u8` become `NARROW`.
+///
+/// Literals that fit in a byte are loaded as immediates, other literals go
+/// into the contract's constant pool. Integer literals are loaded as the
+/// type in `literals`, which `check_types` returns for the same `root`.
+/// Consts are inlined where they are used.
+pub fn generate(
+    root: &ASTNode,
+    symbols: &SymbolTable,
+    literals: &HashMap<NodeId, VariableType>,
+    sources: &SourceMap,
+) -> Result<Program, Vec<Diagnostic>> {
+    let layout = StateLayout::new(symbols, sources).map_err(|diagnostic| vec![*diagnostic])?;
+
+    let items = match &root.kind {
+        ASTNodeKind::Root(items) => items.as_slice(),
+        _ => std::slice::from_ref(root),
+    };
+    let mut functions = Vec::new();
+    let mut consts = Vec::new();
+    for item in items {
+        match &item.kind {
+            ASTNodeKind::Procedures(declarations) => functions.extend(declarations),
+            ASTNodeKind::Function { .. } => functions.push(item),
+            ASTNodeKind::Consts(declarations) => consts.extend(declarations),
+            ASTNodeKind::ConstDeclaration { .. } => consts.push(item),
+            _ => {}
+        }
+    }
+    let consts: HashMap<&str, &ASTNode> = consts
+        .into_iter()
+        .filter_map(|declaration| match &declaration.kind {
+            ASTNodeKind::ConstDeclaration { name, value, .. } => Some((name.as_str(), &**value)),
+            _ => None,
+        })
+        .collect();
+
+    let mut diagnostics = Vec::new();
+    let mut table = HashMap::new();
+    for function in &functions {
+        let ASTNodeKind::Function { name, .. } = &function.kind else {
+            continue;
+        };
+        let Ok(index) = u8::try_from(table.len()) else {
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                "Too many procedures, at most 256 are supported",
+                function.span,
+                sources,
+            ));
+            return Err(diagnostics);
+        };
+        table.insert(name.as_str(), index);
+    }
+
+    let mut compiled = Vec::new();
+    let mut constants = Vec::new();
+    for function in functions {
+        let mut generator = FunctionGenerator {
+            sources,
+            layout: &layout,
+            table: &table,
+            consts: &consts,
+            literals,
+            constants: &mut constants,
+            locals: HashMap::new(),
+            next_slot: 0,
+            next_register: 1,
+            exhausted: false,
+            code: Vec::new(),
+            diagnostics: Vec::new(),
+        };
+        if let Some(function) = generator.function(function) {
+            compiled.push(function);
+        }
+        diagnostics.append(&mut generator.diagnostics);
+    }
+
+    if diagnostics.is_empty() {
+        Ok(Program {
+            layout,
+            functions: compiled,
+            constants,
+        })
+    } else {
+        diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+        Err(diagnostics)
+    }
+}
+
+struct FunctionGenerator<'a> {
+    sources: &'a SourceMap,
+    layout: &'a StateLayout,
+    table: &'a HashMap<&'a str, u8>, // `CALL` index by procedure name
+    consts: &'a HashMap<&'a str, &'a ASTNode>, // Value by const name
+    literals: &'a HashMap<NodeId, VariableType>, // Type of every integer literal
+    constants: &'a mut Vec<Constant>, // The contract's constant pool
+    locals: HashMap<String, u8>,     // Slot by param or local name
+    next_slot: usize,
+    next_register: usize,
+    exhausted: bool, // Whether running out of registers or slots was reported
+    code: Vec<Opcode>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl FunctionGenerator<'_> {
+    fn unsupported<T>(&mut self, span: Span, what: impl std::fmt::Display) -> Option<T> {
+        let message = format!("{} cannot be compiled yet", what);
+        let diagnostic = Diagnostic::new(Severity::Error, message, span, self.sources);
+        self.diagnostics.push(diagnostic);
+        None
+    }
+
+    fn exhausted<T>(&mut self, span: Span, what: &str) -> Option<T> {
+        if !self.exhausted {
+            self.exhausted = true;
+            let message = format!("This procedure needs more than {}", what);
+            let diagnostic = Diagnostic::new(Severity::Error, message, span, self.sources);
+            self.diagnostics.push(diagnostic);
+        }
+        None
+    }
+
+    // ============ Allocation ============
+
+    /// Allocates the next temporary. Temporaries are freed in reverse order
+    /// by resetting `next_register`.
+    fn register(&mut self, span: Span) -> Option<u8> {
+        let Ok(register) = u8::try_from(self.next_register) else {
+            return self.exhausted(span, "256 registers");
+        };
+        self.next_register += 1;
+        Some(register)
+    }
+
+    fn slot(&mut self, span: Span) -> Option<u8> {
+        let Ok(slot) = u8::try_from(self.next_slot) else {
+            return self.exhausted(span, "256 local slots");
+        };
+        self.next_slot += 1;
+        Some(slot)
+    }
+
+    /// The index of `constant` in the constant pool, adding it when it is
+    /// not there yet.
+    fn constant(&mut self, constant: Constant, span: Span) -> Option<u8> {
+        if let Some(index) = self.constants.iter().position(|c| *c == constant) {
+            return Some(index as u8);
+        }
+        let Ok(index) = u8::try_from(self.constants.len()) else {
+            if !self.exhausted {
+                self.exhausted = true;
+                let diagnostic = Diagnostic::new(
+                    Severity::Error,
+                    "Too many constants, at most 256 are supported",
+                    span,
+                    self.sources,
+                );
+                self.diagnostics.push(diagnostic);
+            }
+            return None;
+        };
+        self.constants.push(constant);
+        Some(index)
+    }
+
+    // ============ Jumps ============
+
+    /// The byte offset the next opcode is emitted at.
+    fn position(&self) -> usize {
+        self.code.iter().map(Opcode::size).sum()
+    }
+
+    fn target(&mut self, span: Span) -> Option<u16> {
+        match u16::try_from(self.position()) {
+            Ok(target) => Some(target),
+            Err(_) => self.exhausted(span, "65536 bytes of code"),
+        }
+    }
+
+    /// Emits a jump whose target is not known yet, see `patch`.
+    fn jump(&mut self, jump: Opcode) -> usize {
+        self.code.push(jump);
+        self.code.len() - 1
+    }
+
+    /// Points the jump emitted at `index` to the next opcode.
+    fn patch(&mut self, index: usize, span: Span) -> Option<()> {
+        let target = self.target(span)?;
+        self.code[index] = self.code[index].with_jump_target(target);
+        Some(())
+    }
+
+    // ============ Procedures and statements ============
+
+    fn function(&mut self, function: &ASTNode) -> Option<CompiledFunction> {
+        let ASTNodeKind::Function {
+            name,
+            public,
+            mutates,
+            constructor_only,
+            params,
+            body,
+            ..
+        } = &function.kind
+        else {
+            return None;
+        };
+
+        for (index, param) in params.iter().enumerate() {
+            let ASTNodeKind::Param { name, .. } = &param.kind else {
+                continue;
+            };
+            let register = self.register(param.span)?;
+            let slot = self.slot(param.span)?;
+            debug_assert_eq!(usize::from(register), index + 1);
+            self.locals.insert(name.clone(), slot);
+            self.code.push(Opcode::LOAD(register, slot));
+        }
+        self.next_register = 1;
+
+        let complete = self.block(body).is_some();
+
+        // A jump past the last statement needs an opcode to land on
+        let end = self.position();
+        let jumped_to = self
+            .code
+            .iter()
+            .any(|opcode| opcode.jump_target().map(usize::from) == Some(end));
+        if jumped_to || self.code.last() != Some(&Opcode::RET) {
+            self.code.push(Opcode::RET);
+        }
+
+        complete.then(|| CompiledFunction {
+            name: name.clone(),
+            public: *public,
+            mutates: *mutates,
+            constructor_only: *constructor_only,
+            params: params.len() as u8,
+            code: std::mem::take(&mut self.code),
+        })
+    }
+
+    /// Lowers the statements of a block. Locals declared in it go out of
+    /// scope at its end and their slots are reused.
+    fn block(&mut self, body: &[ASTNode]) -> Option<()> {
+        let locals = self.locals.clone();
+        let next_slot = self.next_slot;
+
+        let mut complete = true;
+        for statement in body {
+            complete &= self.statement(statement).is_some();
+            self.next_register = 1;
+        }
+
+        self.locals = locals;
+        self.next_slot = next_slot;
+        complete.then_some(())
+    }
+
+    fn statement(&mut self, statement: &ASTNode) -> Option<()> {
+        match &statement.kind {
+            ASTNodeKind::LocalVariableDeclaration { name, value, .. } => {
+                // The slot is taken after the value, which cannot refer to it
+                let value = self.expression(value)?;
+                let slot = self.slot(statement.span)?;
+                self.locals.insert(name.clone(), slot);
+                self.code.push(Opcode::LOAD(value, slot));
+            }
+            ASTNodeKind::Assignment { target, value } => self.assignment(target, value)?,
+            ASTNodeKind::Return(value) => {
+                if let Some(value) = value {
+                    // Statements start with every temporary free, so this is r1
+                    let register = self.expression(value)?;
+                    debug_assert_eq!(register, 1);
+                }
+                self.code.push(Opcode::RET);
+            }
+            ASTNodeKind::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                let condition = self.expression(condition)?;
+                let skip_body = self.jump(Opcode::JMPNOT(condition, 0));
+                self.next_register = 1;
+                self.block(body)?;
+
+                if else_body.is_empty() {
+                    self.patch(skip_body, statement.span)?;
+                } else {
+                    let skip_else = self.jump(Opcode::JMP(0));
+                    self.patch(skip_body, statement.span)?;
+                    self.block(else_body)?;
+                    self.patch(skip_else, statement.span)?;
+                }
+            }
+            ASTNodeKind::While { condition, body } => {
+                let start = self.target(statement.span)?;
+                let condition = self.expression(condition)?;
+                let exit = self.jump(Opcode::JMPNOT(condition, 0));
+                self.next_register = 1;
+                self.block(body)?;
+                self.code.push(Opcode::JMP(start));
+                self.patch(exit, statement.span)?;
+            }
+            _ => {
+                self.expression(statement)?;
+            }
+        }
+        Some(())
+    }
+
+    fn assignment(&mut self, target: &ASTNode, value: &ASTNode) -> Option<()> {
+        match &target.kind {
+            ASTNodeKind::Identifier(name) => {
+                let Some(&slot) = self.locals.get(name) else {
+                    return self.unsupported(target.span, "This assignment");
+                };
+                let value = self.expression(value)?;
+                self.code.push(Opcode::LOAD(value, slot));
+            }
+            ASTNodeKind::Member { object, property } if this_member(object) == Some("state") => {
+                let slot = self.state_slot(property, target.span)?;
+                let value = self.expression(value)?;
+                self.code.push(lower_state_write(&slot, None, value));
+            }
+            ASTNodeKind::Index { object, index } => {
+                let value = self.expression(value)?;
+                let (slot, key) = self.table_entry(object, index, target.span)?;
+                self.code.push(lower_state_write(&slot, Some(key), value));
+            }
+            _ => return self.unsupported(target.span, "This assignment"),
+        }
+        Some(())
+    }
+
+    // ============ Expressions ============
+
+    /// Lowers an expression into the next free register and returns it.
+    fn expression(&mut self, expression: &ASTNode) -> Option<u8> {
+        let span = expression.span;
+        match &expression.kind {
+            ASTNodeKind::Identifier(name) => {
+                let Some(&slot) = self.locals.get(name) else {
+                    let consts = self.consts;
+                    return match consts.get(name.as_str()) {
+                        Some(value) => self.expression(value),
+                        None => self.unsupported(span, format!("`{}`", name)),
+                    };
+                };
+                let register = self.register(span)?;
+                self.code.push(Opcode::STORE(slot, register));
+                Some(register)
+            }
+            ASTNodeKind::Member { object, property } => match this_member(object) {
+                Some("state") => {
+                    let slot = self.state_slot(property, span)?;
+                    let register = self.register(span)?;
+                    self.code.push(lower_state_read(&slot, None, register));
+                    Some(register)
+                }
+                _ => match this_member(expression) {
+                    Some("caller") => {
+                        // Copied through a slot, as nothing moves between registers
+                        let register = self.register(span)?;
+                        let slot = self.slot(span)?;
+                        self.code.push(Opcode::LOAD(CALLER, slot));
+                        self.code.push(Opcode::STORE(slot, register));
+                        self.next_slot -= 1;
+                        Some(register)
+                    }
+                    Some("block") => {
+                        let register = self.register(span)?;
+                        self.code.push(Opcode::BLOCK(register));
+                        Some(register)
+                    }
+                    Some(member) => self.unsupported(span, format!("`this.{}`", member)),
+                    None => self.unsupported(span, "This expression"),
+                },
+            },
+            ASTNodeKind::Index { object, index } => {
+                let (slot, key) = self.table_entry(object, index, span)?;
+                self.code.push(lower_state_read(&slot, Some(key), key));
+                Some(key)
+            }
+            ASTNodeKind::Binary { op, left, right } => {
+                let opcode: fn(u8, u8) -> Opcode = match op {
+                    BinaryOp::Add => Opcode::ADD,
+                    BinaryOp::Sub => Opcode::SUB,
+                    BinaryOp::Mul => Opcode::MUL,
+                    BinaryOp::Div => Opcode::DIV,
+                    BinaryOp::Mod => Opcode::MOD,
+                    BinaryOp::Pow => Opcode::EXP,
+                    BinaryOp::Eq => Opcode::EQ,
+                    BinaryOp::Ne => Opcode::NE,
+                    BinaryOp::Lt => Opcode::LT,
+                    BinaryOp::Le => Opcode::LE,
+                    BinaryOp::Gt => Opcode::GT,
+                    BinaryOp::Ge => Opcode::GE,
+                    BinaryOp::BitAnd => Opcode::AND,
+                    BinaryOp::BitOr => Opcode::OR,
+                    BinaryOp::BitXor => Opcode::XOR,
+                    BinaryOp::Shl => Opcode::SHL,
+                    BinaryOp::Shr => Opcode::SHR,
+                    BinaryOp::And | BinaryOp::Or => {
+                        return self.short_circuit(op, left, right, span);
+                    }
+                };
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+                self.code.push(opcode(left, right));
+                self.next_register -= 1;
+                Some(left)
+            }
+            ASTNodeKind::Unary {
+                op: UnaryOp::Not,
+                operand,
+            } => {
+                let value = self.expression(operand)?;
+                self.code.push(Opcode::NOT(value));
+                Some(value)
+            }
+            ASTNodeKind::Unary {
+                op: UnaryOp::Neg, ..
+            } => self.unsupported(span, "The `-` operator"),
+            ASTNodeKind::Call { callee, args } => self.call(callee, args, span),
+            ASTNodeKind::Cast { value, target } => {
+                // Registers are untyped, so only narrowing to a `u8` takes an
+                // opcode. Literals are already loaded as the target type.
+                let register = self.expression(value)?;
+                if *target == VariableType::U8 && !matches!(value.kind, ASTNodeKind::Number(_)) {
+                    self.code.push(Opcode::NARROW(register));
+                }
+                Some(register)
+            }
+            ASTNodeKind::Number(value) => {
+                let Ok(value) = value.parse::<u128>() else {
+                    return self.unsupported(span, format!("Literal `{}`", value));
+                };
+                let literals = self.literals;
+                let register = self.register(span)?;
+                let opcode = match (literals.get(&expression.id()), u8::try_from(value)) {
+                    (Some(VariableType::U8), Ok(value)) => Opcode::LOADI8(register, value),
+                    (_, Ok(value)) => Opcode::LOADI(register, value),
+                    (_, Err(_)) => {
+                        let index = self.constant(Constant::Uint128(value), span)?;
+                        Opcode::LOADC(register, index)
+                    }
+                };
+                self.code.push(opcode);
+                Some(register)
+            }
+            ASTNodeKind::Bool(value) => {
+                let register = self.register(span)?;
+                self.code.push(Opcode::LOADB(register, u8::from(*value)));
+                Some(register)
+            }
+            ASTNodeKind::StringLiteral(value) => {
+                let index = self.constant(Constant::String(value.clone()), span)?;
+                let register = self.register(span)?;
+                self.code.push(Opcode::LOADC(register, index));
+                Some(register)
+            }
+            ASTNodeKind::Array(elements) => {
+                let Some(bytes) = self.byte_array(elements) else {
+                    return self.unsupported(span, "Arrays other than `u8` literals");
+                };
+                let index = self.constant(Constant::ByteArray(bytes), span)?;
+                let register = self.register(span)?;
+                self.code.push(Opcode::LOADC(register, index));
+                Some(register)
+            }
+            // There is no source syntax for address literals, so no encoding to
+            // put into a `Constant::Address` yet
+            ASTNodeKind::Address(_) => self.unsupported(span, "Address literals"),
+            _ => self.unsupported(span, "This expression"),
+        }
+    }
+
+    /// The bytes of an array literal whose elements are all `u8` literals.
+    fn byte_array(&self, elements: &[ASTNode]) -> Option<Vec<u8>> {
+        elements
+            .iter()
+            .map(|element| match &element.kind {
+                ASTNodeKind::Number(value)
+                    if self.literals.get(&element.id()) == Some(&VariableType::U8) =>
+                {
+                    value.parse().ok()
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Lowers `&&` or `||`. The right operand is lowered into the register of
+    /// the left one and skipped when the left one decides the result.
+    fn short_circuit(
+        &mut self,
+        op: &BinaryOp,
+        left: &ASTNode,
+        right: &ASTNode,
+        span: Span,
+    ) -> Option<u8> {
+        let left = self.expression(left)?;
+        let skip = match op {
+            BinaryOp::And => self.jump(Opcode::JMPNOT(left, 0)),
+            _ => self.jump(Opcode::JMPIF(left, 0)),
+        };
+        self.next_register -= 1;
+        let right = self.expression(right)?;
+        debug_assert_eq!(left, right);
+        self.patch(skip, span)?;
+        Some(left)
+    }
+
+    /// Lowers a call, leaving its result in the next free register. The
+    /// temporaries in use are saved in spare slots while the callee runs.
+    fn call(&mut self, callee: &ASTNode, args: &[ASTNode], span: Span) -> Option<u8> {
+        let name = match &callee.kind {
+            ASTNodeKind::Identifier(name) => name.as_str(),
+            ASTNodeKind::Member { object, property } if this_member(object) == Some("scheme") => {
+                property.as_str()
+            }
+            _ => return self.unsupported(span, "This call"),
+        };
+        let Some(&index) = self.table.get(name) else {
+            return self.unsupported(span, format!("The call to `{}`", name));
+        };
+
+        let result = self.register(span)?;
+        let saved_slots = self.next_slot;
+        let mut saved = Vec::new();
+        for register in 1..result {
+            let slot = self.slot(span)?;
+            self.code.push(Opcode::LOAD(register, slot));
+            saved.push((slot, register));
+        }
+
+        // Every temporary is free now, so the arguments land in r1 and up
+        self.next_register = 1;
+        for arg in args {
+            self.expression(arg)?;
+        }
+        self.code.push(Opcode::CALL(index));
+
+        if result != 1 {
+            let slot = self.slot(span)?;
+            self.code.push(Opcode::LOAD(1, slot));
+            for (slot, register) in saved {
+                self.code.push(Opcode::STORE(slot, register));
+            }
+            self.code.push(Opcode::STORE(slot, result));
+        }
+        self.next_slot = saved_slots;
+        self.next_register = usize::from(result) + 1;
+        Some(result)
+    }
+
+    // ============ State ============
+
+    fn state_slot(&mut self, name: &str, span: Span) -> Option<StateSlot> {
+        match self.layout.get(name) {
+            Some(slot) => Some(slot.clone()),
+            None => self.unsupported(span, format!("State variable `{}`", name)),
+        }
+    }
+
+    /// The slot of the table an index expression reads from or writes to,
+    /// along with the register its key is lowered into. The keys of nested
+    /// tables are joined into one, and only whole entries can be addressed.
+    fn table_entry(
+        &mut self,
+        object: &ASTNode,
+        index: &ASTNode,
+        span: Span,
+    ) -> Option<(StateSlot, u8)> {
+        let (slot, key, entry) = self.table_key(object, index)?;
+        if matches!(entry, VariableType::Table(..)) {
+            return self.unsupported(span, "Nested tables as values");
+        }
+        Some((slot, key))
+    }
+
+    /// Lowers the key of `object[index]`, returning the state slot of the
+    /// outermost table, the key register and the type of the entry.
+    fn table_key(
+        &mut self,
+        object: &ASTNode,
+        index: &ASTNode,
+    ) -> Option<(StateSlot, u8, VariableType)> {
+        let (slot, key, table) = match &object.kind {
+            ASTNodeKind::Member {
+                object: this,
+                property,
+            } if this_member(this) == Some("state") => {
+                let slot = self.state_slot(property, object.span)?;
+                let table = slot.var_type.clone();
+                (slot, self.expression(index)?, table)
+            }
+            ASTNodeKind::Index {
+                object: inner,
+                index: inner_index,
+            } => {
+                let (slot, key, table) = self.table_key(inner, inner_index)?;
+                let next = self.expression(index)?;
+                self.code.push(Opcode::KEY(key, next));
+                self.next_register -= 1;
+                (slot, key, table)
+            }
+            _ => return self.unsupported(object.span, "Indexing values other than tables"),
+        };
+
+        match table {
+            VariableType::Table(_, entry) => Some((slot, key, *entry)),
+            _ => self.unsupported(object.span, "Indexing values other than tables"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::injector;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::presets::PresetRegistry;
+    use crate::semantics;
+
+    /// Injects the schemes of a contract and lowers it, which must pass the
+    /// semantic checks first.
+    fn generate_source(input: &str) -> Result<Program, Vec<String>> {
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+        let root = Parser::new(lexer).parse().unwrap();
+        let (root, diagnostics) = injector::inject(root, &PresetRegistry::with_builtin(), &sources);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let (symbols, diagnostics) = semantics::analyze(&root, &sources);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let (literals, diagnostics) = semantics::check_types(&root, &symbols, &sources);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        generate(&root, &symbols, &literals, &sources)
+            .map_err(|diagnostics| diagnostics.into_iter().map(|d| d.message).collect())
+    }
+
+    #[test]
+    fn test_generate() {
+        let input = r#"
+        $state {
+          mut u128 total;
+          table(address, u128) balances;
+        }
+        $program {
+          pub u128 balance() {
+            return this.state.balances[this.caller];
+          }
+
+          pub mut deposit(u128 amount) {
+            u128 updated = this.state.balances[this.caller] + amount;
+            this.state.balances[this.caller] = updated;
+            this.state.total = this.state.total + amount;
+          }
+
+          pub u128 tripled(u128 amount) {
+            return amount + twice(amount);
+          }
+
+          u128 twice(u128 amount) {
+            return amount + amount;
+          }
+        }
+        "#;
+
+        let program = generate_source(input).unwrap();
+        let names: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["balance", "deposit", "tripled", "twice"]);
+
+        use Opcode::*;
+        let (index, balance) = program.function("balance").unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(
+            balance.code,
+            vec![LOAD(0, 0), STORE(0, 1), SMGET(1, 1, 1), RET]
+        );
+
+        let (_, deposit) = program.function("deposit").unwrap();
+        assert_eq!(deposit.params, 1);
+        assert_eq!(
+            deposit.code,
+            vec![
+                LOAD(1, 0), // amount
+                LOAD(0, 1),
+                STORE(1, 1),
+                SMGET(1, 1, 1),
+                STORE(0, 2),
+                ADD(1, 2),
+                LOAD(1, 1), // updated
+                STORE(1, 1),
+                LOAD(0, 2),
+                STORE(2, 2),
+                SMSET(1, 1, 2),
+                SGET(0, 1),
+                STORE(0, 2),
+                ADD(1, 2),
+                SSET(1, 0),
+                RET,
+            ]
+        );
+
+        // r1 holds `amount` while twice() runs, so it is saved around the call
+        let (_, tripled) = program.function("tripled").unwrap();
+        assert_eq!(
+            tripled.code,
+            vec![
+                LOAD(1, 0),
+                STORE(0, 1),
+                LOAD(1, 1),
+                STORE(0, 1),
+                CALL(3),
+                LOAD(1, 2),
+                STORE(1, 1),
+                STORE(2, 2),
+                ADD(1, 2),
+                RET,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_casts() {
+        let input = r#"
+        $program {
+          pub u128 widen(u8 amount) {
+            return amount as u128;
+          }
+
+          pub u8 narrow(u128 amount) {
+            return amount as u8 + 1;
+          }
+
+          pub u8 literal() {
+            return 7 as u8;
+          }
+        }
+        "#;
+
+        let program = generate_source(input).unwrap();
+
+        use Opcode::*;
+        let (_, widen) = program.function("widen").unwrap();
+        assert_eq!(widen.code, vec![LOAD(1, 0), STORE(0, 1), RET]);
+
+        let (_, narrow) = program.function("narrow").unwrap();
+        assert_eq!(
+            narrow.code,
+            vec![
+                LOAD(1, 0),
+                STORE(0, 1),
+                NARROW(1),
+                LOADI8(2, 1),
+                ADD(1, 2),
+                RET
+            ]
+        );
+
+        let (_, literal) = program.function("literal").unwrap();
+        assert_eq!(literal.code, vec![LOADI8(1, 7), RET]);
+    }
+
+    #[test]
+    fn test_nested_tables() {
+        let input = r#"
+        $state {
+          table(address, table(address, u128)) allowances;
+        }
+        $program {
+          pub u128 allowance(address owner) {
+            return this.state.allowances[owner][this.caller];
+          }
+
+          pub mut approve(address spender, u128 amount) {
+            this.state.allowances[this.caller][spender] = amount;
+          }
+
+          pub u128 now() {
+            return this.block;
+          }
+        }
+        "#;
+
+        let program = generate_source(input).unwrap();
+
+        use Opcode::*;
+        let (_, allowance) = program.function("allowance").unwrap();
+        assert_eq!(
+            allowance.code,
+            vec![
+                LOAD(1, 0),
+                STORE(0, 1),
+                LOAD(0, 1),
+                STORE(1, 2),
+                KEY(1, 2),
+                SMGET(0, 1, 1),
+                RET
+            ]
+        );
+
+        let (_, approve) = program.function("approve").unwrap();
+        assert_eq!(
+            approve.code,
+            vec![
+                LOAD(1, 0),
+                LOAD(2, 1),
+                STORE(1, 1), // amount
+                LOAD(0, 2),
+                STORE(2, 2),
+                STORE(0, 3), // spender
+                KEY(2, 3),
+                SMSET(1, 0, 2),
+                RET,
+            ]
+        );
+
+        let (_, now) = program.function("now").unwrap();
+        assert_eq!(now.code, vec![BLOCK(1), RET]);
+    }
+
+    #[test]
+    fn test_token_preset() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = {
+                decimals = 12
+                total_supply = 10e12 * 5
+                name = ["coolium", "COOL"]
+              }
+            }
+          ]
+        }
+        init() {
+          this.scheme.distTokens(this.caller, 10e12);
+        }
+        $program {
+          pub u128 spendable(address owner) {
+            return this.scheme.allowance(owner, this.caller);
+          }
+        }
+        "#;
+
+        let program = generate_source(input).unwrap();
+        let names: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "token.distTokens",
+                "token.total_supply",
+                "token.balance_of",
+                "token.allowance",
+                "token.transfer",
+                "token.approve",
+                "token.transfer_from",
+                "init",
+                "spendable",
+            ]
+        );
+        for function in &program.functions {
+            let bytes = codes::encode(&function.code);
+            assert!(codes::decode(&bytes).is_ok(), "{}", function.name);
+        }
+
+        let container = program.container();
+        assert_eq!(Container::read(&container.write()), Ok(container));
+    }
+
+    #[test]
+    fn test_builtin_presets() {
+        let schemes = [
+            r#"preset = "nft@0.1.0" params = { name = "Kitties" symbol = "KIT" base_uri = "ipfs://kit/" }"#,
+            r#"preset = "ownable@0.1.0" params = {}"#,
+            r#"preset = "access@0.1.0" params = {}"#,
+            r#"preset = "multisig@0.1.0" params = { threshold = 2 signers = 3 }"#,
+            r#"preset = "governance@0.1.0" params = { voting_period = 100 quorum = 40 }"#,
+        ];
+
+        for scheme in schemes {
+            let input = format!("$define {{ schemes = [ {{ {} }} ] }}", scheme);
+            assert!(generate_source(&input).is_ok(), "{}", scheme);
+        }
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let input = r#"
+        $program {
+          pub u128 flip(u128 a, u128 b) {
+            return a ^^ b & a;
+          }
+        }
+        "#;
+
+        let program = generate_source(input).unwrap();
+
+        use Opcode::*;
+        let (_, flip) = program.function("flip").unwrap();
+        assert_eq!(
+            flip.code,
+            vec![
+                LOAD(1, 0),
+                LOAD(2, 1),
+                STORE(0, 1),
+                STORE(1, 2),
+                STORE(0, 3),
+                AND(2, 3),
+                XOR(1, 2),
+                RET
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let input = r#"
+        $state {
+          mut u128 total;
+        }
+        $program {
+          pub u128 max(u128 a, u128 b) {
+            if a > b {
+              return a;
+            } else {
+              return b;
+            }
+          }
+
+          pub bool within(u128 low, u128 value, u128 high) {
+            return low <= value && !(value > high);
+          }
+
+          pub mut drain(u128 step) {
+            while this.state.total >= step {
+              u128 left = this.state.total - step;
+              this.state.total = left;
+            }
+            u128 last = step;
+          }
+        }
+        "#;
+
+        let program = generate_source(input).unwrap();
+        for function in &program.functions {
+            let bytes = se_opcodes::codes::encode(&function.code);
+            assert!(
+                se_opcodes::codes::decode(&bytes).is_ok(),
+                "{}",
+                function.name
+            );
+        }
+
+        use Opcode::*;
+        let (_, max) = program.function("max").unwrap();
+        assert_eq!(
+            max.code,
+            vec![
+                LOAD(1, 0),
+                LOAD(2, 1),
+                STORE(0, 1),
+                STORE(1, 2),
+                GT(1, 2),
+                JMPNOT(1, 26), // else
+                STORE(0, 1),
+                RET,
+                JMP(30),
+                STORE(1, 1), // 26
+                RET,
+                RET, // 30
+            ]
+        );
+
+        // `value > high` is only compared when `low <= value`
+        let (_, within) = program.function("within").unwrap();
+        assert_eq!(
+            within.code,
+            vec![
+                LOAD(1, 0),
+                LOAD(2, 1),
+                LOAD(3, 2),
+                STORE(0, 1),
+                STORE(1, 2),
+                LE(1, 2),
+                JMPNOT(1, 33),
+                STORE(1, 1),
+                STORE(2, 2),
+                GT(1, 2),
+                NOT(1),
+                RET, // 33
+            ]
+        );
+
+        // `last` takes the slot `left` had inside the loop
+        let (_, drain) = program.function("drain").unwrap();
+        assert_eq!(
+            drain.code,
+            vec![
+                LOAD(1, 0),
+                SGET(0, 1), // 3
+                STORE(0, 2),
+                GE(1, 2),
+                JMPNOT(1, 37),
+                SGET(0, 1),
+                STORE(0, 2),
+                SUB(1, 2),
+                LOAD(1, 1),
+                STORE(1, 1),
+                SSET(1, 0),
+                JMP(3),
+                STORE(0, 1), // 37
+                LOAD(1, 1),
+                RET,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_lowering() {
+        let input = "$state {\n  address creator;\n  table(address, u128) balances;\n}\n";
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+        let root = Parser::new(lexer).parse().unwrap();
+        let (symbols, _) = semantics::analyze(&root, &sources);
+
+        let layout = StateLayout::new(&symbols, &sources).unwrap();
+        assert_eq!(layout.len(), 2);
+
+        let creator = layout.get("creator").unwrap();
+        assert!(matches!(
+            lower_state_read(creator, None, 3),
+            Opcode::SGET(0, 3)
+        ));
+        assert!(matches!(
+            lower_state_write(creator, None, 3),
+            Opcode::SSET(3, 0)
+        ));
+
+        let balances = layout.get("balances").unwrap();
+        assert!(matches!(
+            lower_state_read(balances, Some(1), 2),
+            Opcode::SMGET(1, 1, 2)
+        ));
+        assert!(matches!(
+            lower_state_write(balances, Some(1), 2),
+            Opcode::SMSET(2, 1, 1)
+        ));
+        assert!(matches!(balances.default_value(), StateValue::Uint128(0)));
+    }
+}
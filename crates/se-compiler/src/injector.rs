@@ -1 +1,761 @@
-// We will inject preset code into the appropriate AST Nodes here.
+use std::collections::{HashMap, HashSet};
+
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::parser::{ASTNode, ASTNodeKind, VariableType};
+use crate::presets::{PresetFragments, PresetRegistry};
+use crate::semantics::this_member;
+use crate::source::{SourceMap, Span};
+
+/// The items of one scheme, renamed into the namespace of its alias: state
+/// `balances` of the scheme `token` becomes `token.balances`. Names with a
+/// `.` cannot be declared in source, so scheme items never collide with the
+/// user's own or with those of other schemes.
+struct Injection {
+    alias: String,
+    preset: String, // As in `token@0.1.0`
+    span: Span,
+    fragments: PresetFragments,
+    state: HashSet<String>, // The unqualified names of the items
+    consts: HashSet<String>,
+    functions: HashSet<String>,
+}
+
+impl Injection {
+    fn new(alias: String, preset: String, span: Span, fragments: PresetFragments) -> Self {
+        let names = |nodes: &[ASTNode]| nodes.iter().filter_map(declared_name).collect();
+        let mut injection = Injection {
+            alias,
+            preset,
+            span,
+            state: names(&fragments.state),
+            consts: names(&fragments.consts),
+            functions: names(&fragments.functions),
+            fragments,
+        };
+        injection.qualify_fragments();
+        injection
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        format!("{}.{}", self.alias, name)
+    }
+
+    /// Renames the items of the scheme and every reference to them within
+    /// the scheme's own code.
+    fn qualify_fragments(&mut self) {
+        let mut fragments = std::mem::take(&mut self.fragments);
+        let items = fragments
+            .state
+            .iter_mut()
+            .chain(&mut fragments.consts)
+            .chain(&mut fragments.functions);
+
+        for item in items {
+            let mut locals = HashSet::new();
+            match &mut item.kind {
+                ASTNodeKind::StateVariableDeclaration { name, .. }
+                | ASTNodeKind::ConstDeclaration { name, .. } => *name = self.qualify(name),
+                ASTNodeKind::Function {
+                    name, params, body, ..
+                } => {
+                    *name = self.qualify(name);
+                    locals.extend(
+                        params
+                            .iter()
+                            .filter_map(ASTNode::as_param)
+                            .map(|(name, _)| name.to_owned()),
+                    );
+                    collect_locals(body, &mut locals);
+                }
+                _ => {}
+            }
+
+            item.visit_mut(&mut |node| match &mut node.kind {
+                ASTNodeKind::Identifier(name)
+                    if !locals.contains(name)
+                        && (self.consts.contains(name) || self.functions.contains(name)) =>
+                {
+                    *name = self.qualify(name)
+                }
+                ASTNodeKind::Member { object, property } => {
+                    let provided = match this_member(object) {
+                        Some("state") => self.state.contains(property),
+                        Some("scheme") => self.functions.contains(property),
+                        _ => false,
+                    };
+                    if provided {
+                        *property = self.qualify(property);
+                    }
+                }
+                _ => {}
+            });
+        }
+
+        self.fragments = fragments;
+    }
+}
+
+/// Instantiates the preset of every scheme in `$define` and merges the state,
+/// consts and procedures it provides into the contract. Each scheme's items
+/// are inserted right after the `$define` block, so injected state comes
+/// before the user's own in the state layout.
+///
+/// Every scheme gets its own namespace, named by its alias. User code reaches
+/// scheme procedures and consts through `this.schemes.<alias>.<name>` and
+/// scheme state through `this.state.<alias>.<name>`, which are rewritten to
+/// the qualified names here. `this.scheme.<name>` is rewritten too, as long
+/// as only one scheme provides the procedure. Procedures the user marks
+/// `override` replace the scheme's procedure of the same name and signature.
+pub fn inject(
+    root: ASTNode,
+    registry: &PresetRegistry,
+    sources: &SourceMap,
+) -> (ASTNode, Vec<Diagnostic>) {
+    let ASTNodeKind::Root(mut items) = root.kind else {
+        return (root, Vec::new());
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut aliases = HashMap::new();
+    let mut injections = Vec::new(); // Along with the index of their `$define`
+    for (index, item) in items.iter().enumerate() {
+        for injection in instantiate(item, registry, sources, &mut aliases, &mut diagnostics) {
+            injections.push((index, injection));
+        }
+    }
+
+    {
+        let mut injections: Vec<&mut Injection> = injections
+            .iter_mut()
+            .map(|(_, injection)| injection)
+            .collect();
+        for function in items.iter_mut().flat_map(declarations_mut) {
+            if matches!(
+                function.kind,
+                ASTNodeKind::Function {
+                    overrides: true,
+                    ..
+                }
+            ) {
+                apply_override(function, &mut injections, sources, &mut diagnostics);
+            }
+        }
+    }
+
+    let schemes: Vec<&Injection> = injections.iter().map(|(_, injection)| injection).collect();
+    for item in &mut items {
+        qualify_references(item, &schemes, sources, &mut diagnostics);
+    }
+
+    let mut injections = injections.into_iter().peekable();
+    let mut merged = Vec::with_capacity(items.len());
+    for (index, item) in items.into_iter().enumerate() {
+        merged.push(item);
+        while let Some((_, injection)) = injections.next_if(|(define, _)| *define == index) {
+            let PresetFragments {
+                state,
+                consts,
+                functions,
+            } = injection.fragments;
+            let blocks = [
+                ASTNodeKind::State(state),
+                ASTNodeKind::Consts(consts),
+                ASTNodeKind::Procedures(functions),
+            ];
+            for block in blocks {
+                merged.push(ASTNode::new(block, injection.span));
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+    (
+        ASTNode::new(ASTNodeKind::Root(merged), root.span),
+        diagnostics,
+    )
+}
+
+/// Checks the compiler version of a `$define` block and instantiates the
+/// preset of each of its schemes. Schemes that fail, or reuse an alias in
+/// `aliases`, are reported and skipped.
+fn instantiate(
+    define: &ASTNode,
+    registry: &PresetRegistry,
+    sources: &SourceMap,
+    aliases: &mut HashMap<String, Span>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<Injection> {
+    let ASTNodeKind::Define { version, schemes } = &define.kind else {
+        return Vec::new();
+    };
+
+    if let Some(requirement) = version {
+        if let Err(err) = registry.check_compiler_version(requirement) {
+            let message = err.to_string();
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                message,
+                define.span,
+                sources,
+            ));
+        }
+    }
+
+    let mut injections = Vec::new();
+    for scheme in schemes {
+        let ASTNodeKind::Scheme {
+            alias,
+            preset,
+            params,
+        } = &scheme.kind
+        else {
+            continue;
+        };
+        let preset = match registry.resolve_scheme(preset, scheme.span, sources) {
+            Ok(preset) => preset,
+            Err(diagnostic) => {
+                diagnostics.push(*diagnostic);
+                continue;
+            }
+        };
+
+        // Presets such as `mycorp/vesting` default to the last part
+        let alias = alias.clone().unwrap_or_else(|| {
+            let name = preset.name.rsplit('/').next().unwrap_or(&preset.name);
+            name.to_owned()
+        });
+        if let Some(first) = aliases.get(&alias) {
+            let message = format!("Alias `{}` is used by more than one scheme", alias);
+            let note = format!(
+                "the first scheme using it is at {}, set `alias = <name>` to tell them apart",
+                sources.location(*first)
+            );
+            diagnostics.push(
+                Diagnostic::new(Severity::Error, message, scheme.span, sources).with_note(note),
+            );
+            continue;
+        }
+        aliases.insert(alias.clone(), scheme.span);
+
+        match preset.instantiate(params, scheme.span, sources) {
+            Ok(fragments) => injections.push(Injection::new(
+                alias,
+                preset.to_string(),
+                scheme.span,
+                fragments,
+            )),
+            Err(errors) => diagnostics.extend(errors),
+        }
+    }
+
+    injections
+}
+
+/// Replaces the scheme procedure a user `override` names. Unqualified names
+/// must be provided by exactly one scheme. The override takes over the
+/// qualified name, so the scheme's own code calls it instead.
+fn apply_override(
+    function: &mut ASTNode,
+    injections: &mut [&mut Injection],
+    sources: &SourceMap,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let ASTNodeKind::Function { name, .. } = &function.kind else {
+        return;
+    };
+    let (alias, member) = match name.split_once('.') {
+        Some((alias, member)) => (Some(alias), member),
+        None => (None, name.as_str()),
+    };
+
+    let mut candidates: Vec<&mut &mut Injection> = injections
+        .iter_mut()
+        .filter(|injection| {
+            alias.is_none_or(|alias| injection.alias == alias)
+                && injection.functions.contains(member)
+        })
+        .collect();
+
+    let injection = match candidates.as_mut_slice() {
+        [injection] => injection,
+        [] => {
+            let message = format!("`{}` is marked `override`, but no scheme provides it", name);
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                message,
+                function.span,
+                sources,
+            ));
+            return;
+        }
+        [..] => {
+            let aliases: Vec<String> = candidates
+                .iter()
+                .map(|injection| format!("`{}`", injection.qualify(member)))
+                .collect();
+            let message = format!("`{}` is provided by more than one scheme", name);
+            let note = format!("override one of {} instead", aliases.join(", "));
+            diagnostics.push(
+                Diagnostic::new(Severity::Error, message, function.span, sources).with_note(note),
+            );
+            return;
+        }
+    };
+
+    let qualified = injection.qualify(member);
+    let functions = &mut injection.fragments.functions;
+    let Some(position) = functions
+        .iter()
+        .position(|node| declared_name(node).as_deref() == Some(qualified.as_str()))
+    else {
+        return;
+    };
+    let replaced = functions.remove(position);
+
+    if signature(function) != signature(&replaced) {
+        let message = format!(
+            "`{}` does not match the signature of the procedure it overrides",
+            name
+        );
+        let note = format!("`{}` declares `{}`", injection.preset, describe(&replaced));
+        diagnostics.push(
+            Diagnostic::new(Severity::Error, message, function.span, sources).with_note(note),
+        );
+    }
+
+    if let ASTNodeKind::Function { name, .. } = &mut function.kind {
+        *name = qualified;
+    }
+}
+
+/// Rewrites references to scheme items in user code to their qualified names.
+fn qualify_references(
+    item: &mut ASTNode,
+    schemes: &[&Injection],
+    sources: &SourceMap,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let scheme = |alias: &str| schemes.iter().find(|scheme| scheme.alias == alias);
+
+    item.visit_mut(&mut |node| {
+        let span = node.span;
+        let ASTNodeKind::Member { object, property } = &mut node.kind else {
+            return;
+        };
+
+        // `this.schemes.<alias>.<name>` and `this.state.<alias>.<name>`
+        if let ASTNodeKind::Member {
+            object: inner,
+            property: alias,
+        } = &object.kind
+        {
+            match (this_member(inner), scheme(alias)) {
+                (Some("schemes"), Some(scheme)) => {
+                    node.kind = if scheme.consts.contains(property.as_str()) {
+                        ASTNodeKind::Identifier(scheme.qualify(property))
+                    } else {
+                        this_access("scheme", scheme.qualify(property), inner.span)
+                    };
+                    return;
+                }
+                (Some("state"), Some(scheme)) => {
+                    node.kind = this_access("state", scheme.qualify(property), inner.span);
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        // `this.scheme.<name>`, as long as only one scheme provides it
+        if this_member(object) == Some("scheme") && !property.contains('.') {
+            let providers: Vec<&&Injection> = schemes
+                .iter()
+                .filter(|scheme| scheme.functions.contains(property.as_str()))
+                .collect();
+            match providers.as_slice() {
+                [scheme] => *property = scheme.qualify(property),
+                [] => {}
+                [..] => {
+                    let options: Vec<String> = providers
+                        .iter()
+                        .map(|scheme| format!("`this.schemes.{}.{}`", scheme.alias, property))
+                        .collect();
+                    let message = format!("`{}` is provided by more than one scheme", property);
+                    let note = format!("use one of {} instead", options.join(", "));
+                    diagnostics.push(
+                        Diagnostic::new(Severity::Error, message, span, sources).with_note(note),
+                    );
+
+                    // Carry on with the first to avoid follow-up errors
+                    *property = providers[0].qualify(property);
+                }
+            }
+        }
+    });
+}
+
+/// Builds `this.<member>.<property>`.
+fn this_access(member: &str, property: String, span: Span) -> ASTNodeKind {
+    let this = ASTNode::new(ASTNodeKind::Identifier("this".to_owned()), span);
+    let object = ASTNodeKind::Member {
+        object: Box::new(this),
+        property: member.to_owned(),
+    };
+    ASTNodeKind::Member {
+        object: Box::new(ASTNode::new(object, span)),
+        property,
+    }
+}
+
+/// Collects the names of the locals declared anywhere in a function body.
+fn collect_locals(body: &[ASTNode], locals: &mut HashSet<String>) {
+    for statement in body {
+        match &statement.kind {
+            ASTNodeKind::LocalVariableDeclaration { name, .. } => {
+                locals.insert(name.clone());
+            }
+            ASTNodeKind::If {
+                body, else_body, ..
+            } => {
+                collect_locals(body, locals);
+                collect_locals(else_body, locals);
+            }
+            ASTNodeKind::While { body, .. } => collect_locals(body, locals),
+            _ => {}
+        }
+    }
+}
+
+/// The state, const and function declarations among the top-level items.
+fn declarations_mut(item: &mut ASTNode) -> Vec<&mut ASTNode> {
+    match item.kind {
+        ASTNodeKind::State(ref mut nodes)
+        | ASTNodeKind::Consts(ref mut nodes)
+        | ASTNodeKind::Procedures(ref mut nodes) => nodes.iter_mut().collect(),
+        ASTNodeKind::Function { .. } => vec![item],
+        _ => Vec::new(),
+    }
+}
+
+fn declared_name(node: &ASTNode) -> Option<String> {
+    match &node.kind {
+        ASTNodeKind::StateVariableDeclaration { name, .. }
+        | ASTNodeKind::ConstDeclaration { name, .. }
+        | ASTNodeKind::Function { name, .. } => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// The parts of a procedure an override has to keep: whether it is public,
+/// mutates state or can only be called from `init()`, and its types.
+type Signature<'a> = (
+    bool,
+    bool,
+    bool,
+    Vec<&'a VariableType>,
+    Option<&'a VariableType>,
+);
+
+fn signature(node: &ASTNode) -> Option<Signature<'_>> {
+    match &node.kind {
+        ASTNodeKind::Function {
+            public,
+            mutates,
+            constructor_only,
+            params,
+            return_type,
+            ..
+        } => Some((
+            *public,
+            *mutates,
+            *constructor_only,
+            params
+                .iter()
+                .filter_map(ASTNode::as_param)
+                .map(|(_, var_type)| var_type)
+                .collect(),
+            return_type.as_ref(),
+        )),
+        _ => None,
+    }
+}
+
+/// A procedure's declaration as written in source, without its body.
+fn describe(node: &ASTNode) -> String {
+    let ASTNodeKind::Function {
+        name,
+        public,
+        mutates,
+        constructor_only,
+        params,
+        return_type,
+        ..
+    } = &node.kind
+    else {
+        return String::new();
+    };
+
+    let mut declaration = String::new();
+    if *constructor_only {
+        declaration.push_str("init ");
+    }
+    if *public {
+        declaration.push_str("pub ");
+    }
+    if *mutates && !constructor_only {
+        declaration.push_str("mut ");
+    }
+    if let Some(return_type) = return_type {
+        declaration.push_str(&format!("{} ", return_type));
+    }
+    let params: Vec<String> = params
+        .iter()
+        .filter_map(ASTNode::as_param)
+        .map(|(name, var_type)| format!("{} {}", var_type, name))
+        .collect();
+    declaration.push_str(&format!("{}({})", name, params.join(", ")));
+    declaration
+}
+
+/// Injects the schemes of a contract using the built-in presets and runs
+/// name resolution and type checking over the result, for tests of the
+/// injector and of the presets.
+#[cfg(test)]
+pub(crate) fn check_source(
+    input: &str,
+) -> (ASTNode, crate::semantics::SymbolTable, Vec<Diagnostic>) {
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::semantics;
+
+    let sources = SourceMap::new();
+    let lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+    let root = Parser::new(lexer).parse().unwrap();
+
+    let (root, mut diagnostics) = inject(root, &PresetRegistry::with_builtin(), &sources);
+    let (symbols, analyze_diagnostics) = semantics::analyze(&root, &sources);
+    diagnostics.extend(analyze_diagnostics);
+    diagnostics.extend(semantics::check_types(&root, &symbols, &sources).1);
+    diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+    (root, symbols, diagnostics)
+}
+
+/// The messages of the diagnostics `check_source` reports, in source order.
+#[cfg(test)]
+pub(crate) fn check_messages(input: &str) -> Vec<String> {
+    let (_, _, diagnostics) = check_source(input);
+    diagnostics.into_iter().map(|d| d.message).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Returns the injected contract and every message reported for it.
+    fn inject_source(input: &str) -> (ASTNode, Vec<String>) {
+        let (root, _, diagnostics) = check_source(input);
+        let messages = diagnostics.into_iter().map(|d| d.message).collect();
+        (root, messages)
+    }
+
+    fn names(block: &ASTNode) -> Vec<String> {
+        match &block.kind {
+            ASTNodeKind::State(nodes)
+            | ASTNodeKind::Consts(nodes)
+            | ASTNodeKind::Procedures(nodes) => nodes.iter().filter_map(declared_name).collect(),
+            other => panic!("Expected a block, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_inject() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = { decimals = 12 total_supply = 10e12 * 5 name = ["coolium", "COOL"] }
+            }
+          ]
+        }
+        init() {
+          this.scheme.distTokens(this.caller, 10e12);
+        }
+        $program {
+          pub override mut bool transfer(address to, u128 amount) {
+            return this.state.token.balances[this.caller] >= amount;
+          }
+        }
+        "#;
+
+        let (root, messages) = inject_source(input);
+        assert!(messages.is_empty(), "{:?}", messages);
+
+        let ASTNodeKind::Root(items) = &root.kind else {
+            panic!("Expected a root node");
+        };
+        // $define, the injected state, consts and procedures, init, $program
+        assert_eq!(items.len(), 6);
+        assert_eq!(
+            names(&items[1]),
+            vec!["token.balances", "token.allowances", "token.supply"]
+        );
+        assert_eq!(
+            names(&items[2]),
+            vec![
+                "token.decimals",
+                "token.max_supply",
+                "token.name",
+                "token.symbol"
+            ]
+        );
+        assert_eq!(
+            names(&items[3]),
+            vec![
+                "token.distTokens",
+                "token.total_supply",
+                "token.balance_of",
+                "token.allowance",
+                "token.approve",
+                "token.transfer_from"
+            ]
+        );
+        assert_eq!(names(&items[5]), vec!["token.transfer"]);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = { decimals = 12 total_supply = 5 name = ["coolium", "COOL"] }
+            },
+            {
+              alias = points
+              preset = "token@^0.1"
+              params = { decimals = 0 total_supply = 100 name = ["points", "PTS"] }
+            },
+            {
+              alias = points
+              preset = "token@0.1.0"
+              params = { decimals = 0 total_supply = 100 name = ["again", "AGN"] }
+            }
+          ]
+        }
+        $program {
+          pub u128 held(address owner) {
+            u8 decimals = this.schemes.token.decimals;
+            return this.state.token.balances[owner] + this.schemes.points.balance_of(owner);
+          }
+
+          pub u128 missing() {
+            return this.schemes.reward.total_supply();
+          }
+
+          pub mut bool send(address to) {
+            return this.scheme.transfer(to, 1);
+          }
+
+          pub override mut bool approve(address spender, u128 amount) {
+            return false;
+          }
+
+          pub override mut bool points.approve(address spender, u128 amount) {
+            return false;
+          }
+        }
+        "#;
+
+        let (_, messages) = inject_source(input);
+        assert_eq!(
+            messages,
+            vec![
+                "Alias `points` is used by more than one scheme",
+                "No scheme named `reward`",
+                "`transfer` is provided by more than one scheme",
+                "`approve` is provided by more than one scheme",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_define() {
+        let input = r#"
+        $define {
+          version = "^9.0"
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = { decimals = 12 total_supply = 5 name = ["coolium", "COOL"] }
+            },
+            {
+              alias = second
+              preset = "token@^0.1"
+              params = { decimals = 40 total_supply = 5 name = ["coolium", "COOL"] }
+            },
+            { preset = "token@^0.3" params = {} },
+            { preset = "vesting@1.0.0" params = {} }
+          ]
+        }
+        "#;
+
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+        let root = Parser::new(lexer).parse().unwrap();
+        let (_, diagnostics) = inject(root, &PresetRegistry::with_builtin(), &sources);
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                format!(
+                    "This contract requires compiler version `^9.0`, but this is {}",
+                    crate::VERSION
+                )
+                .as_str(),
+                "Parameter `decimals` must be at most 36",
+                "No version of preset `token` matches `^0.3`",
+                "Unknown preset `vesting`",
+            ]
+        );
+        assert_eq!(diagnostics[2].notes, vec!["available versions: 0.1.0"]);
+        assert_eq!(
+            diagnostics[3].notes,
+            vec!["available presets: access, governance, multisig, nft, ownable, token"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = { decimals = 12 total_supply = 5 name = ["coolium", "COOL"] }
+            }
+          ]
+        }
+        $program {
+          pub override bool approve(address spender) {
+            return false;
+          }
+
+          pub override mint() {}
+        }
+        "#;
+
+        let (_, messages) = inject_source(input);
+        assert_eq!(
+            messages,
+            vec![
+                "`approve` does not match the signature of the procedure it overrides",
+                "`mint` is marked `override`, but no scheme provides it",
+            ]
+        );
+    }
+}
@@ -1,26 +1,40 @@
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::source::{FileId, SourceMap, Span};
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token<'a> {
     Define,
+    Preset,
     Version,
     Schemes,
     State,
     Consts,
     Include(&'a str),
-    Procedures,
+    Program,
+    Init,
     Address,
     U128,
     U8,
     Bool,
+    StringType,
     Table,
     PubFModifier,
     MutFModifier,
+    OverrideFModifier,
     Return,
+    If,
+    Else,
+    While,
+    True,
+    False,
+    As,
     Number(String), // String so we don't need to box leak it
     Identifier(&'a str),
+    Placeholder(&'a str), // `$name` in preset files, without the `$`
     Operator(&'a str),
     Comment(&'a str),
     String(&'a str),
@@ -37,47 +51,97 @@ pub enum Token<'a> {
     Eof,
 }
 
+/// A token together with the span it was read from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken<'a> {
+    pub token: Token<'a>,
+    pub span: Span,
+}
+
 pub struct Lexer<'a> {
+    sources: &'a SourceMap,
+    file: FileId,
     input: &'a str,
     pos: usize,
+    token_start: usize,
     inner_lexer: Option<Box<Lexer<'a>>>,
-    included_files: Vec<String>, // Store owned Strings
-    working_dir: &'a str,
+    include_paths: &'a [PathBuf],
+    include_chain: Vec<FileId>, // Files currently being lexed, outermost first
     keywords: &'static HashMap<&'static str, Token<'static>>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Lexer<'a> {
     fn build_keyword_map() -> HashMap<&'static str, Token<'static>> {
         let mut keywords = HashMap::new();
         keywords.insert("$define", Token::Define);
+        keywords.insert("$preset", Token::Preset);
         keywords.insert("version", Token::Version);
         keywords.insert("schemes", Token::Schemes);
         keywords.insert("$state", Token::State);
         keywords.insert("$consts", Token::Consts);
-        keywords.insert("$procedures", Token::Procedures);
+        keywords.insert("$program", Token::Program);
+        keywords.insert("init", Token::Init);
         keywords.insert("address", Token::Address);
         keywords.insert("table", Token::Table);
         keywords.insert("u128", Token::U128);
         keywords.insert("u8", Token::U8);
         keywords.insert("bool", Token::Bool);
+        keywords.insert("string", Token::StringType);
         keywords.insert("pub", Token::PubFModifier);
         keywords.insert("mut", Token::MutFModifier);
+        keywords.insert("override", Token::OverrideFModifier);
         keywords.insert("return", Token::Return);
+        keywords.insert("if", Token::If);
+        keywords.insert("else", Token::Else);
+        keywords.insert("while", Token::While);
+        keywords.insert("true", Token::True);
+        keywords.insert("false", Token::False);
+        keywords.insert("as", Token::As);
         keywords
     }
 
-    pub fn new(input: &'a str, working_dir: &'a str) -> Self {
+    /// Creates a lexer over `file`, which must have been added to `sources`.
+    /// Included headers are loaded into the same source map, and are looked
+    /// up relative to the including file first and then in `include_paths`.
+    pub fn new(sources: &'a SourceMap, file: FileId, include_paths: &'a [PathBuf]) -> Self {
         static KEYWORDS: OnceLock<HashMap<&'static str, Token<'static>>> = OnceLock::new();
         let keywords = KEYWORDS.get_or_init(Self::build_keyword_map);
 
+        let input = sources
+            .get(file)
+            .map_or("", |source| source.content.as_str());
+
         Lexer {
+            sources,
+            file,
             input,
             pos: 0,
+            token_start: 0,
             inner_lexer: None,
-            included_files: Vec::with_capacity(10),
-            working_dir,
+            include_paths,
+            include_chain: vec![file],
             keywords,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Creates a diagnostic for a span in any file of the source map.
+    pub fn diagnostic(
+        &self,
+        span: Span,
+        severity: Severity,
+        message: impl Into<String>,
+    ) -> Diagnostic {
+        Diagnostic::new(severity, message, span, self.sources)
+    }
+
+    /// Drains the diagnostics reported while tokenizing, such as unreadable includes.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        if let Some(inner) = self.inner_lexer.as_mut() {
+            self.diagnostics.append(&mut inner.take_diagnostics());
         }
+        std::mem::take(&mut self.diagnostics)
     }
 
     fn current_char(&self) -> Option<char> {
@@ -91,22 +155,32 @@ impl<'a> Lexer<'a> {
     }
 
     fn skip_whitespace(&mut self) {
-        while self.current_char().map_or(false, |c| c.is_whitespace()) {
+        while self.current_char().is_some_and(|c| c.is_whitespace()) {
             self.advance();
         }
     }
 
-    pub fn next_token(&mut self) -> Token<'a> {
+    pub fn next_token(&mut self) -> SpannedToken<'a> {
         if let Some(inner) = self.inner_lexer.as_mut() {
             let token = inner.next_token();
-            if token == Token::Eof {
+            if token.token == Token::Eof {
+                self.diagnostics.append(&mut inner.diagnostics);
                 self.inner_lexer = None;
                 return self.next_token();
             }
             return token;
         }
 
+        let token = self.lex_token();
+        SpannedToken {
+            token,
+            span: Span::new(self.file, self.token_start, self.pos),
+        }
+    }
+
+    fn lex_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
+        self.token_start = self.pos;
 
         if self.pos >= self.input.len() {
             return Token::Eof;
@@ -116,7 +190,7 @@ impl<'a> Lexer<'a> {
 
         if current_char == '/' && self.input[self.pos..].starts_with("//") {
             let start_pos = self.pos;
-            while self.current_char().map_or(false, |c| c != '\n') {
+            while self.current_char().is_some_and(|c| c != '\n') {
                 self.advance();
             }
             return Token::Comment(&self.input[start_pos..self.pos]);
@@ -126,7 +200,7 @@ impl<'a> Lexer<'a> {
             let start_pos = self.pos;
             while self
                 .current_char()
-                .map_or(false, |c| c.is_alphanumeric() || c == '_' || c == '$')
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '$')
             {
                 self.advance();
             }
@@ -140,25 +214,53 @@ impl<'a> Lexer<'a> {
                 return token.clone();
             }
 
+            if let Some(name) = identifier.strip_prefix('$') {
+                return Token::Placeholder(name);
+            }
+
             return Token::Identifier(identifier);
         }
 
         if current_char == '"' {
             self.advance();
             let start_pos = self.pos;
-            while self.current_char().map_or(false, |c| c != '"') {
+            while self.current_char().is_some_and(|c| c != '"') {
                 self.advance();
             }
             let end_pos = self.pos;
+            if self.current_char().is_none() {
+                let span = Span::new(self.file, self.token_start, self.pos);
+                let diagnostic =
+                    self.diagnostic(span, Severity::Error, "Unterminated string literal");
+                self.diagnostics.push(diagnostic);
+            }
             self.advance();
             return Token::String(&self.input[start_pos..end_pos]);
         }
 
-        if current_char.is_digit(10) || current_char == '.' {
+        // A leading '.' only starts a number when a digit follows, otherwise
+        // member access such as `this.example` would be read as an exponent.
+        let next_is_digit = self.input[self.pos..]
+            .chars()
+            .nth(1)
+            .is_some_and(|c| c.is_ascii_digit());
+        if current_char.is_ascii_digit() || (current_char == '.' && next_is_digit) {
             return self.tokenize_number();
         }
 
+        if let Some(op) = self.tokenize_compound_operator() {
+            return op;
+        }
+
         self.advance();
+        if !current_char.is_ascii_punctuation() {
+            let span = Span::new(self.file, self.token_start, self.pos);
+            let message = format!("Unexpected character `{}`", current_char);
+            let diagnostic = self.diagnostic(span, Severity::Error, message);
+            self.diagnostics.push(diagnostic);
+            return self.lex_token();
+        }
+
         match current_char {
             '{' => Token::LeftBrace,
             '}' => Token::RightBrace,
@@ -169,10 +271,23 @@ impl<'a> Lexer<'a> {
             ',' => Token::Comma,
             ';' => Token::SemiColon,
             '.' => Token::Period,
-            _ => Token::Operator(&self.input[self.pos - 1..self.pos]),
+            _ => Token::Operator(&self.input[self.token_start..self.pos]),
         }
     }
 
+    /// Tokenizes two character operators such as `==`, `&&` and `<<`.
+    fn tokenize_compound_operator(&mut self) -> Option<Token<'a>> {
+        const COMPOUND_OPERATORS: [&str; 9] =
+            ["==", "!=", "<=", ">=", "&&", "||", "<<", ">>", "^^"];
+
+        let rest = &self.input[self.pos..];
+        let op = COMPOUND_OPERATORS.iter().find(|op| rest.starts_with(*op))?;
+
+        let start_pos = self.pos;
+        self.pos += op.len();
+        Some(Token::Operator(&self.input[start_pos..self.pos]))
+    }
+
     fn tokenize_include(&mut self) -> Token<'a> {
         self.skip_whitespace();
 
@@ -181,7 +296,7 @@ impl<'a> Lexer<'a> {
         }
 
         let start_pos = self.pos;
-        while self.current_char().map_or(false, |c| c != '"') {
+        while self.current_char().is_some_and(|c| c != '"') {
             self.advance();
         }
 
@@ -193,24 +308,95 @@ impl<'a> Lexer<'a> {
     }
 
     fn load_header(&mut self, filename: &str) {
-        let included_file_path = Path::new(self.working_dir).join(filename);
-        let file_content =
-            std::fs::read_to_string(included_file_path).expect("Failed to read included file");
+        let span = Span::new(self.file, self.token_start, self.pos);
+
+        let Some(included_file_path) = self.resolve_include(filename) else {
+            let mut diagnostic = self.diagnostic(
+                span,
+                Severity::Error,
+                format!("Could not find included file '{}'", filename),
+            );
+            for dir in self.include_dirs() {
+                diagnostic = diagnostic.with_note(format!("searched in {}", dir.display()));
+            }
+            self.diagnostics.push(diagnostic);
+            return;
+        };
+
+        if let Some(file) = self.sources.find(&included_file_path) {
+            if self.include_chain.contains(&file) {
+                let chain = self
+                    .include_chain
+                    .iter()
+                    .chain(std::iter::once(&file))
+                    .filter_map(|id| self.sources.get(*id))
+                    .map(|source| source.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+
+                let diagnostic = self
+                    .diagnostic(
+                        span,
+                        Severity::Error,
+                        format!("Include cycle detected while including '{}'", filename),
+                    )
+                    .with_note(format!("include chain: {}", chain));
+                self.diagnostics.push(diagnostic);
+            }
 
-        // Create a new lexer with a static reference
-        let content = Box::leak(file_content.into_boxed_str());
-        self.included_files.push(content.to_string()); // Store for potential cleanup
+            // Every file is only lexed once, later includes are no-ops
+            return;
+        }
 
-        self.inner_lexer = Some(Box::new(Lexer::new(content, self.working_dir)));
+        let file = match self.sources.load(&included_file_path) {
+            Ok(file) => file,
+            Err(err) => {
+                let diagnostic = self
+                    .diagnostic(
+                        span,
+                        Severity::Error,
+                        format!("Failed to read included file '{}'", filename),
+                    )
+                    .with_note(format!("{}: {}", included_file_path.display(), err));
+                self.diagnostics.push(diagnostic);
+                return;
+            }
+        };
+
+        let mut inner = Lexer::new(self.sources, file, self.include_paths);
+        inner.include_chain = self.include_chain.clone();
+        inner.include_chain.push(file);
+        self.inner_lexer = Some(Box::new(inner));
+    }
+
+    /// Directories searched for includes: the directory of the current file
+    /// followed by the configured include paths.
+    fn include_dirs(&self) -> impl Iterator<Item = &Path> {
+        let current_dir = self
+            .sources
+            .get(self.file)
+            .and_then(|source| source.path.as_deref())
+            .and_then(Path::parent);
+
+        current_dir
+            .into_iter()
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+    }
+
+    fn resolve_include(&self, filename: &str) -> Option<PathBuf> {
+        self.include_dirs()
+            .map(|dir| dir.join(filename))
+            .find(|path| path.is_file())
     }
 
     fn tokenize_number(&mut self) -> Token<'a> {
         let start_pos = self.pos;
         let mut has_exponent = false;
 
-        while self.current_char().map_or(false, |c| {
-            c.is_digit(10) || c == '.' || c == 'e' || c == 'E'
-        }) {
+        while self
+            .current_char()
+            .is_some_and(|c| c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E')
+        {
             if matches!(self.current_char(), Some('e' | 'E')) {
                 has_exponent = true;
             }
@@ -260,12 +446,13 @@ mod tests {
         let w_path = "../../examples/create_token";
         let main_path = format!("{}/main.se", w_path);
 
-        let input = std::fs::read_to_string(main_path).unwrap();
-        let mut lexer = Lexer::new(&input, w_path);
+        let sources = SourceMap::new();
+        let file = sources.load(Path::new(&main_path)).unwrap();
+        let mut lexer = Lexer::new(&sources, file, &[]);
         let mut token_count = 0;
 
         loop {
-            let token = lexer.next_token();
+            let token = lexer.next_token().token;
             println!("{:?}", token);
             if token == Token::Eof {
                 break;
@@ -278,10 +465,175 @@ mod tests {
 
     #[test]
     fn test_numbers_and_scientific_notation() {
-        let input = "123 1e5";
-        let mut lexer = Lexer::new(input, "");
+        let sources = SourceMap::new();
+        let mut lexer = Lexer::new(&sources, sources.add("<input>", "123 1e5"), &[]);
+
+        assert_eq!(lexer.next_token().token, Token::Number("123".to_string()));
+        assert_eq!(
+            lexer.next_token().token,
+            Token::Number("100000".to_string())
+        );
+    }
+
+    #[test]
+    fn test_compound_operators() {
+        let sources = SourceMap::new();
+        let file = sources.add("<input>", "a <= b && !c != d ^^ 2 ^ 3");
+        let mut lexer = Lexer::new(&sources, file, &[]);
+
+        assert_eq!(lexer.next_token().token, Token::Identifier("a"));
+        assert_eq!(lexer.next_token().token, Token::Operator("<="));
+        assert_eq!(lexer.next_token().token, Token::Identifier("b"));
+        assert_eq!(lexer.next_token().token, Token::Operator("&&"));
+        assert_eq!(lexer.next_token().token, Token::Operator("!"));
+        assert_eq!(lexer.next_token().token, Token::Identifier("c"));
+        assert_eq!(lexer.next_token().token, Token::Operator("!="));
+        assert_eq!(lexer.next_token().token, Token::Identifier("d"));
+        assert_eq!(lexer.next_token().token, Token::Operator("^^"));
+        assert_eq!(lexer.next_token().token, Token::Number("2".to_string()));
+        assert_eq!(lexer.next_token().token, Token::Operator("^"));
+        assert_eq!(lexer.next_token().token, Token::Number("3".to_string()));
+        assert_eq!(lexer.next_token().token, Token::Eof);
+    }
+
+    #[test]
+    fn test_unexpected_character() {
+        let sources = SourceMap::new();
+        let mut lexer = Lexer::new(&sources, sources.add("<input>", "a € .5"), &[]);
+
+        assert_eq!(lexer.next_token().token, Token::Identifier("a"));
+        let number = lexer.next_token();
+        assert_eq!(number.token, Token::Number(".5".to_string()));
+        assert_eq!(lexer.next_token().token, Token::Eof);
+
+        let diagnostics = lexer.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Unexpected character `€`");
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (1, 3));
+    }
+
+    #[test]
+    fn test_spans_and_missing_include() {
+        let input = "$state\n$include \"missing.seh\"";
+        let sources = SourceMap::new();
+        let mut lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+
+        let state = lexer.next_token();
+        assert_eq!(state.token, Token::State);
+        assert_eq!(state.span, Span::new(FileId(0), 0, 6));
+
+        let include = lexer.next_token();
+        assert_eq!(include.token, Token::Include("missing.seh"));
+        assert_eq!(
+            &input[include.span.start..include.span.end],
+            "$include \"missing.seh\""
+        );
+        assert_eq!(lexer.next_token().token, Token::Eof);
+
+        let diagnostics = lexer.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "Could not find included file 'missing.seh'"
+        );
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (2, 1));
+    }
 
-        assert_eq!(lexer.next_token(), Token::Number("123".to_string()));
-        assert_eq!(lexer.next_token(), Token::Number("100000".to_string()));
+    #[test]
+    fn test_included_token_spans() {
+        let w_path = "../../examples/create_token";
+        let sources = SourceMap::new();
+        let file = sources.load(&Path::new(w_path).join("main.se")).unwrap();
+        let mut lexer = Lexer::new(&sources, file, &[]);
+
+        // The first token after the include directive comes from the header
+        assert!(matches!(
+            lexer.next_token().token,
+            Token::Include("main.seh")
+        ));
+        let define = lexer.next_token();
+        assert_eq!(define.token, Token::Define);
+        assert_eq!(define.span, Span::new(FileId(1), 0, "$define".len()));
+
+        let diagnostic = lexer.diagnostic(define.span, Severity::Note, "header");
+        assert!(diagnostic.file_name.ends_with("main.seh"));
+        assert_eq!(diagnostic.source_line, "$define {");
+        assert_eq!(sources.len(), 2);
+    }
+
+    fn lex_all(lexer: &mut Lexer) -> Vec<Token<'static>> {
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer.next_token().token;
+            if token == Token::Eof {
+                return tokens;
+            }
+            // Only keep the tokens we assert on, without borrowing the input
+            match token {
+                Token::State => tokens.push(Token::State),
+                Token::Consts => tokens.push(Token::Consts),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_include_guards_and_search_paths() {
+        let dir = std::env::temp_dir().join(format!("selc_include_guards_{}", std::process::id()));
+        let stdlib = dir.join("stdlib");
+        std::fs::create_dir_all(&stdlib).unwrap();
+
+        std::fs::write(
+            dir.join("main.se"),
+            "$include \"a.seh\" $include \"./a.seh\" $include \"lib.seh\"",
+        )
+        .unwrap();
+        std::fs::write(dir.join("a.seh"), "$state").unwrap();
+        std::fs::write(stdlib.join("lib.seh"), "$consts").unwrap();
+
+        let include_paths = vec![stdlib.clone()];
+        let sources = SourceMap::new();
+        let file = sources.load(&dir.join("main.se")).unwrap();
+        let mut lexer = Lexer::new(&sources, file, &include_paths);
+
+        // a.seh is only lexed once and lib.seh is found through the search path
+        assert_eq!(lex_all(&mut lexer), vec![Token::State, Token::Consts]);
+        assert!(lexer.take_diagnostics().is_empty());
+        assert_eq!(sources.len(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = std::env::temp_dir().join(format!("selc_include_cycle_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(dir.join("main.se"), "$include \"a.seh\"").unwrap();
+        std::fs::write(dir.join("a.seh"), "$include \"b.seh\"").unwrap();
+        std::fs::write(dir.join("b.seh"), "$state $include \"a.seh\"").unwrap();
+
+        let sources = SourceMap::new();
+        let file = sources.load(&dir.join("main.se")).unwrap();
+        let mut lexer = Lexer::new(&sources, file, &[]);
+
+        assert_eq!(lex_all(&mut lexer), vec![Token::State]);
+        let diagnostics = lexer.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "Include cycle detected while including 'a.seh'"
+        );
+        assert!(diagnostics[0].file_name.ends_with("b.seh"));
+
+        let chain = &diagnostics[0].notes[0];
+        assert!(chain.starts_with("include chain: "));
+        let names: Vec<&str> = chain["include chain: ".len()..]
+            .split(" -> ")
+            .map(|name| name.rsplit(['/', '\\']).next().unwrap())
+            .collect();
+        assert_eq!(names, vec!["main.se", "a.seh", "b.seh", "a.seh"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,3 +1,4 @@
+pub mod diagnostics;
 pub mod generator;
 pub mod injector;
 pub mod lexer;
@@ -5,3 +6,7 @@ pub mod optimizer;
 pub mod parser;
 pub mod presets;
 pub mod semantics;
+pub mod source;
+
+/// Version of the compiler, checked against `$define { version = "..." }`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -1 +1,26 @@
+use crate::parser::{ASTNode, ASTNodeKind, BinaryOp};
+use crate::source::Span;
 
+/// Folds arithmetic between two number literals into a single literal, e.g.
+/// `10e12 * 5` becomes `50000000000000`. Returns `None` when either side is
+/// not a literal or the operation would overflow, leaving it for runtime.
+pub fn fold_binary(op: BinaryOp, left: &ASTNode, right: &ASTNode, span: Span) -> Option<ASTNode> {
+    let (ASTNodeKind::Number(left), ASTNodeKind::Number(right)) = (&left.kind, &right.kind) else {
+        return None;
+    };
+
+    let left = left.parse::<u128>().ok()?;
+    let right = right.parse::<u128>().ok()?;
+
+    let value = match op {
+        BinaryOp::Add => left.checked_add(right)?,
+        BinaryOp::Sub => left.checked_sub(right)?,
+        BinaryOp::Mul => left.checked_mul(right)?,
+        BinaryOp::Div => left.checked_div(right)?,
+        BinaryOp::Mod => left.checked_rem(right)?,
+        BinaryOp::Pow => left.checked_pow(u32::try_from(right).ok()?)?,
+        _ => return None,
+    };
+
+    Some(ASTNode::new(ASTNodeKind::Number(value.to_string()), span))
+}
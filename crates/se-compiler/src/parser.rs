@@ -1,4 +1,9 @@
+use std::fmt;
+
+use crate::diagnostics::{Diagnostic, Severity};
 use crate::lexer::{Lexer, Token};
+use crate::optimizer;
+use crate::source::Span;
 
 #[allow(unused_macros)]
 macro_rules! log_current_token {
@@ -7,7 +12,7 @@ macro_rules! log_current_token {
     };
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum VariableType {
     U128,
     U8,
@@ -15,23 +20,341 @@ pub enum VariableType {
     String,
     Bool,
     Array(Box<VariableType>),
+    Table(Box<VariableType>, Box<VariableType>), // Key and value type
+}
+
+impl VariableType {
+    pub fn is_integer(&self) -> bool {
+        matches!(self, VariableType::U128 | VariableType::U8)
+    }
+
+    /// Whether values of this type can be used to look up table entries.
+    pub fn is_table_key(&self) -> bool {
+        matches!(
+            self,
+            VariableType::Address | VariableType::U128 | VariableType::U8 | VariableType::Bool
+        )
+    }
 }
 
-#[derive(Debug)]
-pub enum ASTNode {
+impl fmt::Display for VariableType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VariableType::U128 => write!(f, "u128"),
+            VariableType::U8 => write!(f, "u8"),
+            VariableType::Address => write!(f, "address"),
+            VariableType::String => write!(f, "string"),
+            VariableType::Bool => write!(f, "bool"),
+            VariableType::Array(element) => write!(f, "[{}]", element),
+            VariableType::Table(key, value) => write!(f, "table({}, {})", key, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+}
+
+impl BinaryOp {
+    pub fn from_operator(op: &str) -> Option<BinaryOp> {
+        let op = match op {
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Mod,
+            "^" => BinaryOp::Pow,
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            "<" => BinaryOp::Lt,
+            "<=" => BinaryOp::Le,
+            ">" => BinaryOp::Gt,
+            ">=" => BinaryOp::Ge,
+            "&&" => BinaryOp::And,
+            "||" => BinaryOp::Or,
+            "&" => BinaryOp::BitAnd,
+            "|" => BinaryOp::BitOr,
+            "^^" => BinaryOp::BitXor,
+            "<<" => BinaryOp::Shl,
+            ">>" => BinaryOp::Shr,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// The operator as written in source.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Pow => "^",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^^",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+        }
+    }
+
+    /// Binding power of the operator, higher binds tighter.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::BitOr => 3,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 10,
+            BinaryOp::Pow => 11,
+        }
+    }
+
+    pub fn is_right_associative(&self) -> bool {
+        matches!(self, BinaryOp::Pow)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+/// A node in the syntax tree along with the source it was parsed from.
+#[derive(Debug, Clone)]
+pub struct ASTNode {
+    pub kind: ASTNodeKind,
+    pub span: Span,
+}
+
+/// Tells the nodes of one tree apart, even nodes that share a span such as
+/// the copies of a preset param or nodes built by the compiler. Ids are taken
+/// from the node's address, so they only hold while the tree is not changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+impl ASTNode {
+    pub fn new(kind: ASTNodeKind, span: Span) -> Self {
+        ASTNode { kind, span }
+    }
+
+    pub fn id(&self) -> NodeId {
+        NodeId(self as *const ASTNode as usize)
+    }
+
+    /// The name and type of a procedure param, `None` for other nodes.
+    pub fn as_param(&self) -> Option<(&str, &VariableType)> {
+        match &self.kind {
+            ASTNodeKind::Param { name, var_type } => Some((name, var_type)),
+            _ => None,
+        }
+    }
+
+    /// The nodes directly below this one, in source order.
+    pub fn children(&self) -> Vec<&ASTNode> {
+        match &self.kind {
+            ASTNodeKind::Binary { left, right, .. }
+            | ASTNodeKind::Index {
+                object: left,
+                index: right,
+            }
+            | ASTNodeKind::Assignment {
+                target: left,
+                value: right,
+            } => vec![left, right],
+            ASTNodeKind::Unary { operand, .. }
+            | ASTNodeKind::Cast { value: operand, .. }
+            | ASTNodeKind::Member {
+                object: operand, ..
+            }
+            | ASTNodeKind::ConstDeclaration { value: operand, .. }
+            | ASTNodeKind::LocalVariableDeclaration { value: operand, .. }
+            | ASTNodeKind::ParamDeclaration {
+                default: Some(operand),
+                ..
+            }
+            | ASTNodeKind::Return(Some(operand)) => vec![operand],
+            ASTNodeKind::Call { callee, args } => std::iter::once(&**callee).chain(args).collect(),
+            ASTNodeKind::If {
+                condition,
+                body,
+                else_body,
+            } => std::iter::once(&**condition)
+                .chain(body)
+                .chain(else_body)
+                .collect(),
+            ASTNodeKind::While { condition, body } => {
+                std::iter::once(&**condition).chain(body).collect()
+            }
+            ASTNodeKind::Function { params, body, .. } => params.iter().chain(body).collect(),
+            ASTNodeKind::Array(nodes)
+            | ASTNodeKind::Root(nodes)
+            | ASTNodeKind::Preset(nodes)
+            | ASTNodeKind::Define { schemes: nodes, .. }
+            | ASTNodeKind::State(nodes)
+            | ASTNodeKind::Consts(nodes)
+            | ASTNodeKind::Procedures(nodes) => nodes.iter().collect(),
+            ASTNodeKind::Scheme { params, .. } => params.iter().map(|(_, value)| value).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The nodes directly below this one, in source order.
+    pub fn children_mut(&mut self) -> Vec<&mut ASTNode> {
+        match &mut self.kind {
+            ASTNodeKind::Binary { left, right, .. }
+            | ASTNodeKind::Index {
+                object: left,
+                index: right,
+            }
+            | ASTNodeKind::Assignment {
+                target: left,
+                value: right,
+            } => vec![left, right],
+            ASTNodeKind::Unary { operand, .. }
+            | ASTNodeKind::Cast { value: operand, .. }
+            | ASTNodeKind::Member {
+                object: operand, ..
+            }
+            | ASTNodeKind::ConstDeclaration { value: operand, .. }
+            | ASTNodeKind::LocalVariableDeclaration { value: operand, .. }
+            | ASTNodeKind::ParamDeclaration {
+                default: Some(operand),
+                ..
+            }
+            | ASTNodeKind::Return(Some(operand)) => vec![operand],
+            ASTNodeKind::Call { callee, args } => {
+                std::iter::once(&mut **callee).chain(args).collect()
+            }
+            ASTNodeKind::If {
+                condition,
+                body,
+                else_body,
+            } => std::iter::once(&mut **condition)
+                .chain(body)
+                .chain(else_body)
+                .collect(),
+            ASTNodeKind::While { condition, body } => {
+                std::iter::once(&mut **condition).chain(body).collect()
+            }
+            ASTNodeKind::Function { params, body, .. } => params.iter_mut().chain(body).collect(),
+            ASTNodeKind::Array(nodes)
+            | ASTNodeKind::Root(nodes)
+            | ASTNodeKind::Preset(nodes)
+            | ASTNodeKind::Define { schemes: nodes, .. }
+            | ASTNodeKind::State(nodes)
+            | ASTNodeKind::Consts(nodes)
+            | ASTNodeKind::Procedures(nodes) => nodes.iter_mut().collect(),
+            ASTNodeKind::Scheme { params, .. } => {
+                params.iter_mut().map(|(_, value)| value).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Calls `f` on this node and everything below it, parents before their
+    /// children.
+    pub fn visit(&self, f: &mut impl FnMut(&ASTNode)) {
+        f(self);
+        for child in self.children() {
+            child.visit(f);
+        }
+    }
+
+    /// Like `visit`, but `f` may modify or replace nodes. Children are
+    /// visited after `f` returns, so those of a replacement are visited too.
+    pub fn visit_mut(&mut self, f: &mut impl FnMut(&mut ASTNode)) {
+        f(self);
+        for child in self.children_mut() {
+            child.visit_mut(f);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ASTNodeKind {
     Number(String),
     StringLiteral(String),
+    Bool(bool),
     Comment(String),
     Array(Vec<ASTNode>),
     Address(String),
+    Identifier(String),
+    Placeholder(String), // A preset param, replaced when the preset is instantiated
+
+    Binary {
+        op: BinaryOp,
+        left: Box<ASTNode>,
+        right: Box<ASTNode>,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<ASTNode>,
+    },
+    Member {
+        object: Box<ASTNode>,
+        property: String,
+    },
+    Index {
+        object: Box<ASTNode>,
+        index: Box<ASTNode>,
+    },
+    Call {
+        callee: Box<ASTNode>,
+        args: Vec<ASTNode>,
+    },
+    Cast {
+        value: Box<ASTNode>,
+        target: VariableType,
+    },
 
     Root(Vec<ASTNode>),
+    Preset(Vec<ASTNode>), // The params of a preset file
+    ParamDeclaration {
+        name: String,
+        var_type: VariableType,
+        default: Option<Box<ASTNode>>,
+    },
     Define {
         version: Option<String>,
         schemes: Vec<ASTNode>,
     },
-    Schemes(Vec<ASTNode>),
     Scheme {
+        alias: Option<String>, // Defaults to the name of the preset
         preset: String,
         params: Vec<(String, ASTNode)>,
     },
@@ -39,6 +362,7 @@ pub enum ASTNode {
     StateVariableDeclaration {
         name: String,
         var_type: VariableType,
+        mutable: bool,
     },
     Consts(Vec<ASTNode>), // <-- New node type for const declarations
     ConstDeclaration {
@@ -51,19 +375,26 @@ pub enum ASTNode {
         name: String,
         public: bool,
         mutates: bool,
-        params: Vec<(String, VariableType)>,
+        constructor_only: bool,
+        overrides: bool, // Replaces a procedure provided by a scheme
+        params: Vec<ASTNode>,
+        return_type: Option<VariableType>,
         body: Vec<ASTNode>,
     },
+    Param {
+        name: String,
+        var_type: VariableType,
+    },
     LocalVariableDeclaration {
         name: String,
         var_type: VariableType,
         value: Box<ASTNode>,
     },
-    LocalVariableAssignment {
-        name: String,
+    Assignment {
+        target: Box<ASTNode>,
         value: Box<ASTNode>,
     },
-    Return(Box<ASTNode>),
+    Return(Option<Box<ASTNode>>),
     If {
         condition: Box<ASTNode>,
         body: Vec<ASTNode>,
@@ -73,15 +404,17 @@ pub enum ASTNode {
         condition: Box<ASTNode>,
         body: Vec<ASTNode>,
     },
-    Call {
-        name: String,
-        args: Vec<ASTNode>,
-    },
 }
 
+// Boxed as diagnostics are large compared to the nodes being returned
+type ParseResult<T> = Result<T, Box<Diagnostic>>;
+
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
     current_token: Token<'a>,
+    current_span: Span,
+    previous_span: Span, // Span of the last consumed token
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Parser<'a> {
@@ -89,311 +422,1007 @@ impl<'a> Parser<'a> {
         let mut parser = Parser {
             lexer,
             current_token: Token::Eof, // Initialize to end of file
+            current_span: Span::default(),
+            previous_span: Span::default(),
+            diagnostics: Vec::new(),
         };
         parser.next_token(); // Load the first token
         parser
     }
 
-    /// Advances the current token to the next token in the lexer.
+    /// Advances the current token to the next token in the lexer, skipping comments.
     fn next_token(&mut self) {
-        self.current_token = self.lexer.next_token();
+        self.previous_span = self.current_span;
+
+        let mut next = self.lexer.next_token();
+        while let Token::Comment(_) = next.token {
+            next = self.lexer.next_token();
+        }
+
+        self.current_token = next.token;
+        self.current_span = next.span;
+    }
+
+    /// Returns the span from `start` up to the end of the last consumed token.
+    fn span_from(&self, start: Span) -> Span {
+        if self.previous_span.file != start.file || self.previous_span.end < start.start {
+            return start;
+        }
+        Span::new(start.file, start.start, self.previous_span.end)
     }
 
-    /// Parses the entire input into a root represented as an AST.
-    pub fn parse(&mut self) -> ASTNode {
+    /// Creates an error diagnostic pointing at the current token.
+    fn error(&self, message: impl Into<String>) -> Box<Diagnostic> {
+        Box::new(
+            self.lexer
+                .diagnostic(self.current_span, Severity::Error, message),
+        )
+    }
+
+    /// Parses the entire input into a root represented as an AST, or returns
+    /// the diagnostics explaining why it could not be parsed.
+    pub fn parse(&mut self) -> Result<ASTNode, Vec<Diagnostic>> {
+        let (root, diagnostics) = self.parse_partial();
+
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            Err(diagnostics)
+        } else {
+            Ok(root)
+        }
+    }
+
+    /// Parses the entire input, recovering from syntax errors so that every
+    /// error is reported. The returned AST leaves out whatever could not be
+    /// parsed, which is still useful for tooling.
+    pub fn parse_partial(&mut self) -> (ASTNode, Vec<Diagnostic>) {
+        let root = self.parse_root();
+
+        let mut diagnostics = self.lexer.take_diagnostics();
+        diagnostics.append(&mut self.diagnostics);
+        diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+
+        (root, diagnostics)
+    }
+
+    fn parse_root(&mut self) -> ASTNode {
+        let start = self.current_span;
         let mut root = Vec::new();
         while self.current_token != Token::Eof {
+            let block_start = self.current_span;
+            let block = match self.current_token {
+                Token::Define => self.parse_define(),
+                Token::Preset => self.parse_preset_block(),
+                Token::State => self.parse_state_block(),
+                Token::Consts => self.parse_consts_block(),
+                Token::Program => self.parse_program(),
+                Token::Init => self.parse_constructor(),
+                Token::Include(_) => {
+                    self.next_token();
+                    continue;
+                }
+                _ => Err(self
+                    .error(format!(
+                        "Expected a top-level block, found {:?}",
+                        self.current_token
+                    ))
+                    .with_note(
+                        "expected one of `$define`, `$preset`, `$state`, `$consts`, `$program` or `init`",
+                    )
+                    .into()),
+            };
+
+            match block {
+                Ok(block) => root.push(block),
+                Err(diagnostic) => {
+                    self.diagnostics.push(*diagnostic);
+
+                    // Always make progress, even if the error was reported on
+                    // the keyword that started the block.
+                    if self.current_span == block_start {
+                        self.next_token();
+                    }
+                    self.synchronize_top_level();
+                }
+            }
+        }
+        ASTNode::new(ASTNodeKind::Root(root), self.span_from(start))
+    }
+
+    // ============ Error recovery ============
+
+    fn at_top_level_keyword(&self) -> bool {
+        matches!(
+            self.current_token,
+            Token::Define
+                | Token::Preset
+                | Token::State
+                | Token::Consts
+                | Token::Program
+                | Token::Init
+        )
+    }
+
+    /// Whether the current token ends a block, either properly or because a
+    /// new top-level block starts before the current one was closed.
+    fn at_block_end(&self) -> bool {
+        matches!(self.current_token, Token::RightBrace | Token::Eof) || self.at_top_level_keyword()
+    }
+
+    /// Records an error and skips tokens until the next `;` (consumed), the
+    /// end of the enclosing block or a top-level keyword. Nested `{ ... }`
+    /// blocks are skipped as a whole and also end the skipped region.
+    fn recover(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+
+        let mut depth = 0usize;
+        loop {
             match self.current_token {
-                Token::Define => root.push(self.parse_define()),
-                Token::State => root.push(self.parse_state_block()),
-                Token::Consts => root.push(self.parse_consts_block()),
-                Token::Procedures => root.push(self.parse_procedures()),
-                _ => self.next_token(),
+                Token::Eof => return,
+                Token::SemiColon if depth == 0 => {
+                    self.next_token();
+                    return;
+                }
+                Token::LeftBrace => depth += 1,
+                Token::RightBrace if depth == 0 => return,
+                Token::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.next_token();
+                        return;
+                    }
+                }
+                // Top-level keywords never appear inside of blocks
+                _ if self.at_top_level_keyword() => return,
+                _ => {}
             }
+            self.next_token();
+        }
+    }
+
+    /// Consumes the '}' closing a block. A missing brace is reported but does
+    /// not fail the block, so that its contents remain part of the tree.
+    fn expect_closing_brace(&mut self, message: &str) {
+        if self.current_token == Token::RightBrace {
+            self.next_token();
+        } else {
+            let diagnostic = self.error(message);
+            self.diagnostics.push(*diagnostic);
+        }
+    }
+
+    /// Skips tokens until the next top-level keyword.
+    fn synchronize_top_level(&mut self) {
+        while self.current_token != Token::Eof && !self.at_top_level_keyword() {
+            self.next_token();
         }
-        ASTNode::Root(root)
     }
 
     /// Parses a define statement and returns it as an ASTNode.
-    fn parse_define(&mut self) -> ASTNode {
+    fn parse_define(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
         self.next_token();
-        self.expect_token(Token::LeftBrace, "Expected '{' to start define block");
+        self.expect_token(Token::LeftBrace, "Expected '{' to start define block")?;
 
         let mut version = None;
         let mut schemes = Vec::new();
 
         loop {
             match &self.current_token {
-                Token::Version => version = Some(self.parse_version().1),
-                Token::Schemes => schemes = self.parse_schemes(),
+                Token::Version => version = Some(self.parse_version()?.1),
+                Token::Schemes => schemes = self.parse_schemes()?,
                 Token::RightBrace => break, // End of block
-                _ => self.next_token(),
+                _ => {
+                    return Err(self.error(format!(
+                        "Unexpected {:?} in define block",
+                        self.current_token
+                    )))
+                }
             }
         }
 
         self.next_token(); // Move past '}'
-        ASTNode::Define { version, schemes }
+        Ok(ASTNode::new(
+            ASTNodeKind::Define { version, schemes },
+            self.span_from(start),
+        ))
     }
 
     /// Parses a version statement and returns it as an ASTNode.
-    fn parse_version(&mut self) -> (String, String) {
+    fn parse_version(&mut self) -> ParseResult<(String, String)> {
         self.next_token(); // Move past 'version'
 
-        self.expect_operator("=");
-        let value = self.expect_string("Expected string value for version");
+        self.expect_operator("=")?;
+        let value = self.expect_string("Expected string value for version")?;
 
-        ("version".to_owned(), value)
+        Ok(("version".to_owned(), value))
     }
 
     /// Parses schemes from the define statement and returns them as a Vec of ASTNodes.
-    fn parse_schemes(&mut self) -> Vec<ASTNode> {
+    fn parse_schemes(&mut self) -> ParseResult<Vec<ASTNode>> {
         self.next_token();
-        self.expect_operator("=");
+        self.expect_operator("=")?;
 
-        self.expect_token(Token::LeftBracket, "Expected '[' to start schemes");
+        self.expect_token(Token::LeftBracket, "Expected '[' to start schemes")?;
         let mut schemes = Vec::new();
 
         while self.current_token != Token::RightBracket && self.current_token != Token::Eof {
-            if self.current_token == Token::LeftBrace {
-                self.next_token(); // Move past '{'
-                schemes.push(self.parse_scheme()); // Parse each scheme
+            self.expect_token(Token::LeftBrace, "Expected '{' to start scheme")?;
+            schemes.push(self.parse_scheme()?); // Parse each scheme
+            self.expect_token(Token::RightBrace, "Expected '}' to end scheme")?;
 
-                // should end with '}'
-                if self.current_token != Token::RightBrace {
-                    panic!("Expected '}}' to end scheme");
-                }
+            if self.current_token == Token::Comma {
+                self.next_token();
             }
-
-            self.next_token(); // Move to the next token (away from '}')
-                               // TODO: Check for ,
         }
 
-        self.expect_token(Token::RightBracket, "Expected ']' to end schemes");
-        schemes
+        self.expect_token(Token::RightBracket, "Expected ']' to end schemes")?;
+        Ok(schemes)
     }
 
     /// Parses an individual scheme and returns it as an ASTNode.
-    fn parse_scheme(&mut self) -> ASTNode {
-        // A scheme consists of a preset and parameters
-        let preset = self.parse_preset();
-        let params = self.parse_params();
+    fn parse_scheme(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        // A scheme consists of an optional alias, a preset and parameters
+        let alias = self.parse_alias()?;
+        let preset = self.parse_preset()?;
+        let params = self.parse_params()?;
 
-        let scheme: ASTNode = ASTNode::Scheme { preset, params };
-        ASTNode::Schemes(vec![scheme]) // Return a new SchemeNode (update as needed)
+        Ok(ASTNode::new(
+            ASTNodeKind::Scheme {
+                alias,
+                preset,
+                params,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// Parses `alias = name`, the name the scheme is addressed by through
+    /// `this.schemes.name`.
+    fn parse_alias(&mut self) -> ParseResult<Option<String>> {
+        if self.current_token != Token::Identifier("alias") {
+            return Ok(None);
+        }
+
+        self.next_token();
+        self.expect_operator("=")?;
+        self.expect_identifier().map(Some)
     }
 
     /// Parses a preset value from a scheme and returns it as an ASTNode.
-    fn parse_preset(&mut self) -> String {
+    fn parse_preset(&mut self) -> ParseResult<String> {
         self.expect_token(
             Token::Identifier("preset"),
             "Expected 'preset' to start scheme",
-        );
-        self.expect_operator("=");
+        )?;
+        self.expect_operator("=")?;
         self.expect_string("Expected string value for preset")
     }
 
     /// Parses parameters from a scheme and returns them as an ASTNode.
-    fn parse_params(&mut self) -> Vec<(String, ASTNode)> {
+    fn parse_params(&mut self) -> ParseResult<Vec<(String, ASTNode)>> {
         self.expect_token(
             Token::Identifier("params"),
             "Expected 'params' to start scheme",
-        );
+        )?;
 
-        self.expect_operator("=");
-        self.expect_token(Token::LeftBrace, "Expected '{' to start params");
+        self.expect_operator("=")?;
+        self.expect_token(Token::LeftBrace, "Expected '{' to start params")?;
 
         let mut params = Vec::new();
         // Loop for as long as the params are not closed with '}'
         while self.current_token != Token::RightBrace && self.current_token != Token::Eof {
-            let id = self.expect_identifier();
-            self.expect_operator("=");
+            let id = self.expect_identifier()?;
+            self.expect_operator("=")?;
 
-            let value = self.expect_value();
+            let value = self.parse_expression()?;
             params.push((id.to_string(), value));
         }
 
-        params
+        self.expect_token(Token::RightBrace, "Expected '}' to end params")?;
+        Ok(params)
+    }
+
+    /// Parses the `$preset` block of a preset file, which declares the params
+    /// the preset takes, such as `address beneficiary;` or `u128 cliff = 0;`.
+    /// Params with a default are optional.
+    fn parse_preset_block(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::Preset, "Expected '$preset' keyword")?;
+        self.expect_token(Token::LeftBrace, "Expected '{' after '$preset'")?;
+
+        let mut params = Vec::new();
+        while !self.at_block_end() {
+            match self.parse_param_declaration() {
+                Ok(declaration) => params.push(declaration),
+                Err(diagnostic) => self.recover(*diagnostic),
+            }
+        }
+
+        self.expect_closing_brace("Expected '}' at the end of the preset block");
+        Ok(ASTNode::new(
+            ASTNodeKind::Preset(params),
+            self.span_from(start),
+        ))
     }
 
-    fn parse_state_block(&mut self) -> ASTNode {
-        self.expect_token(Token::State, "Expected '$state' keyword");
-        self.expect_token(Token::LeftBrace, "Expected '{' after '$state'");
+    fn parse_param_declaration(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let var_type = self.expect_variable_type()?;
+        let name = self.expect_identifier()?;
+        let default = if self.current_token == Token::Operator("=") {
+            self.next_token();
+            Some(Box::new(self.parse_expression()?))
+        } else {
+            None
+        };
+
+        let declaration = ASTNode::new(
+            ASTNodeKind::ParamDeclaration {
+                name,
+                var_type,
+                default,
+            },
+            self.span_from(start),
+        );
+
+        self.expect_token(
+            Token::SemiColon,
+            "Expected ';' at the end of the param declaration",
+        )?;
+        Ok(declaration)
+    }
+
+    fn parse_state_block(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::State, "Expected '$state' keyword")?;
+        self.expect_token(Token::LeftBrace, "Expected '{' after '$state'")?;
 
         let mut state_variables = Vec::new();
         // Loop for as long as the state is not closed with '}'
-        while self.current_token != Token::RightBrace && self.current_token != Token::Eof {
-            let var_type = self.expect_variable_type();
-            let var_name = self.expect_identifier();
+        while !self.at_block_end() {
+            match self.parse_state_variable() {
+                Ok(declaration) => state_variables.push(declaration),
+                Err(diagnostic) => self.recover(*diagnostic),
+            }
+        }
 
-            state_variables.push(ASTNode::StateVariableDeclaration {
-                name: var_name,
-                var_type,
-            });
+        self.expect_closing_brace("Expected '}' at the end of the state block");
+        Ok(ASTNode::new(
+            ASTNodeKind::State(state_variables),
+            self.span_from(start),
+        ))
+    }
 
-            self.expect_token(
-                Token::SemiColon,
-                "Expected ';' at the end of the state variable declaration",
-            );
+    /// Parses a state variable such as `address creator;` or `mut u128 supply;`.
+    /// Only `mut` state may be assigned outside of the constructor.
+    fn parse_state_variable(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let mutable = self.current_token == Token::MutFModifier;
+        if mutable {
+            self.next_token();
         }
 
-        self.expect_token(
-            Token::RightBrace,
-            "Expected '}' at the end of the state block",
+        let var_type = self.expect_variable_type()?;
+        let var_name = self.expect_identifier()?;
+
+        let declaration = ASTNode::new(
+            ASTNodeKind::StateVariableDeclaration {
+                name: var_name,
+                var_type,
+                mutable,
+            },
+            self.span_from(start),
         );
-        ASTNode::State(state_variables)
+
+        self.expect_token(
+            Token::SemiColon,
+            "Expected ';' at the end of the state variable declaration",
+        )?;
+        Ok(declaration)
     }
 
-    fn parse_consts_block(&mut self) -> ASTNode {
-        self.expect_token(Token::Consts, "Expected '$consts' keyword");
-        self.expect_token(Token::LeftBrace, "Expected '{' after '$consts'");
+    fn parse_consts_block(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::Consts, "Expected '$consts' keyword")?;
+        self.expect_token(Token::LeftBrace, "Expected '{' after '$consts'")?;
 
         let mut const_variables = Vec::new();
         // Loop for as long as the consts block is not closed with '}'
-        while self.current_token != Token::RightBrace && self.current_token != Token::Eof {
-            let var_type = self.expect_variable_type();
-            let var_name = self.expect_identifier();
-            self.expect_operator("=");
-            let value = self.expect_value();
+        while !self.at_block_end() {
+            match self.parse_const() {
+                Ok(declaration) => const_variables.push(declaration),
+                Err(diagnostic) => self.recover(*diagnostic),
+            }
+        }
+
+        self.expect_closing_brace("Expected '}' at the end of the consts block");
+
+        Ok(ASTNode::new(
+            ASTNodeKind::Consts(const_variables),
+            self.span_from(start),
+        ))
+    }
+
+    fn parse_const(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let var_type = self.expect_variable_type()?;
+        let var_name = self.expect_identifier()?;
+        self.expect_operator("=")?;
+        let value = self.parse_expression()?;
 
-            const_variables.push(ASTNode::ConstDeclaration {
+        let declaration = ASTNode::new(
+            ASTNodeKind::ConstDeclaration {
                 name: var_name,
                 var_type,
                 value: Box::new(value),
-            });
+            },
+            self.span_from(start),
+        );
+
+        self.expect_token(
+            Token::SemiColon,
+            "Expected ';' at the end of the const declaration",
+        )?;
+        Ok(declaration)
+    }
+
+    fn parse_program(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::Program, "Expected '$program' keyword")?;
+        self.expect_token(Token::LeftBrace, "Expected '{' after '$program'")?;
+
+        let mut functions = Vec::new();
+        // Loop for as long as the program is not closed with '}'
+        while !self.at_block_end() {
+            match self.parse_function() {
+                Ok(function) => functions.push(function),
+                Err(diagnostic) => self.recover(*diagnostic),
+            }
+        }
+
+        self.expect_closing_brace("Expected '}' at the end of the program block");
+        Ok(ASTNode::new(
+            ASTNodeKind::Procedures(functions),
+            self.span_from(start),
+        ))
+    }
+
+    /// Parses the top-level `init()` constructor into a function node. A name
+    /// after `init`, as in `init distTokens(address to) { ... }`, declares a
+    /// helper that can only be called while the constructor runs.
+    fn parse_constructor(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::Init, "Expected 'init' keyword")?;
+        let name = match self.current_token {
+            Token::Identifier(_) => self.expect_identifier()?,
+            _ => "init".to_owned(),
+        };
+        let params = self.parse_function_params()?;
+        let body = self.parse_block()?;
+
+        // The constructor is never callable from outside, but it is the only
+        // place where immutable state may be assigned, so it always mutates.
+        Ok(ASTNode::new(
+            ASTNodeKind::Function {
+                name,
+                public: false,
+                mutates: true,
+                constructor_only: true,
+                overrides: false,
+                params,
+                return_type: None,
+                body,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// Parses a procedure such as `pub mut name(u128 amount) { ... }` or
+    /// `pub address creator() { ... }`. Procedures replacing one provided by
+    /// a scheme are marked with `override` after `pub`.
+    fn parse_function(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let public = self.current_token == Token::PubFModifier;
+        if public {
+            self.next_token();
+        }
+
+        let overrides = self.current_token == Token::OverrideFModifier;
+        if overrides {
+            self.next_token();
+        }
+
+        let mutates = self.current_token == Token::MutFModifier;
+        if mutates {
+            self.next_token();
+        }
+
+        let return_type = if self.is_variable_type() {
+            Some(self.expect_variable_type()?)
+        } else {
+            None
+        };
+
+        let mut name = self.expect_identifier()?;
+        // Overrides may name the scheme they replace a procedure of, as in
+        // `pub override mut bool token.transfer(...)`
+        if overrides && self.current_token == Token::Period {
+            self.next_token();
+            name = format!("{}.{}", name, self.expect_identifier()?);
+        }
+
+        let params = self.parse_function_params()?;
+        let body = self.parse_block()?;
+
+        Ok(ASTNode::new(
+            ASTNodeKind::Function {
+                name,
+                public,
+                mutates,
+                constructor_only: false,
+                overrides,
+                params,
+                return_type,
+                body,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// Parses a parenthesised, comma separated list of typed parameters.
+    fn parse_function_params(&mut self) -> ParseResult<Vec<ASTNode>> {
+        self.expect_token(Token::LeftParen, "Expected '(' to start parameters")?;
+
+        let mut params = Vec::new();
+        while self.current_token != Token::RightParen && self.current_token != Token::Eof {
+            let start = self.current_span;
+            let var_type = self.expect_variable_type()?;
+            let name = self.expect_identifier()?;
+            params.push(ASTNode::new(
+                ASTNodeKind::Param { name, var_type },
+                self.span_from(start),
+            ));
+
+            if self.current_token == Token::Comma {
+                self.next_token();
+            } else {
+                break;
+            }
+        }
+
+        self.expect_token(Token::RightParen, "Expected ')' to end parameters")?;
+        Ok(params)
+    }
+
+    /// Parses a brace delimited list of statements.
+    fn parse_block(&mut self) -> ParseResult<Vec<ASTNode>> {
+        self.expect_token(Token::LeftBrace, "Expected '{' to start block")?;
+
+        let mut statements = Vec::new();
+        while !self.at_block_end() {
+            match self.parse_statement() {
+                Ok(statement) => statements.push(statement),
+                Err(diagnostic) => self.recover(*diagnostic),
+            }
+        }
+
+        self.expect_closing_brace("Expected '}' at the end of block");
+        Ok(statements)
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        match self.current_token {
+            Token::Return => return self.parse_return(),
+            Token::If => return self.parse_if(),
+            Token::While => return self.parse_while(),
+            _ => {}
+        }
+
+        if self.is_variable_type() {
+            let var_type = self.expect_variable_type()?;
+            let name = self.expect_identifier()?;
+            self.expect_operator("=")?;
+            let value = self.parse_expression()?;
 
             self.expect_token(
                 Token::SemiColon,
-                "Expected ';' at the end of the state variable declaration",
-            );
+                "Expected ';' at the end of the local variable declaration",
+            )?;
+            return Ok(ASTNode::new(
+                ASTNodeKind::LocalVariableDeclaration {
+                    name,
+                    var_type,
+                    value: Box::new(value),
+                },
+                self.span_from(start),
+            ));
         }
 
-        self.expect_token(
-            Token::RightBrace,
-            "Expected '}' at the end of the consts block",
-        );
+        let target = self.parse_expression()?;
+        let statement = if self.current_token == Token::Operator("=") {
+            if !matches!(
+                target.kind,
+                ASTNodeKind::Identifier(_) | ASTNodeKind::Member { .. } | ASTNodeKind::Index { .. }
+            ) {
+                return Err(self
+                    .error("Invalid assignment target")
+                    .with_note("only variables, state members and table entries can be assigned to")
+                    .into());
+            }
+
+            self.next_token(); // Move past '='
+            let value = self.parse_expression()?;
+            ASTNode::new(
+                ASTNodeKind::Assignment {
+                    target: Box::new(target),
+                    value: Box::new(value),
+                },
+                self.span_from(start),
+            )
+        } else if let ASTNodeKind::Call { .. } = target.kind {
+            target
+        } else {
+            return Err(self.error("Expected an assignment or a call statement"));
+        };
 
-        ASTNode::Consts(const_variables)
+        self.expect_token(Token::SemiColon, "Expected ';' at the end of statement")?;
+        Ok(ASTNode::new(statement.kind, self.span_from(start)))
     }
 
-    fn parse_procedures(&mut self) -> ASTNode {
-        // TODO: This
-        ASTNode::Procedures(Vec::new())
+    fn parse_return(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::Return, "Expected 'return' keyword")?;
+
+        let value = if self.current_token == Token::SemiColon {
+            None
+        } else {
+            Some(Box::new(self.parse_expression()?))
+        };
+
+        self.expect_token(Token::SemiColon, "Expected ';' after return statement")?;
+        Ok(ASTNode::new(
+            ASTNodeKind::Return(value),
+            self.span_from(start),
+        ))
     }
 
-    // ============ Helper functions ============
-    fn expect_value(&mut self) -> ASTNode {
-        // It could be an array so we need to check for '['
-        if self.current_token == Token::LeftBracket {
-            // Now we must parse this array
-            self.next_token(); // Move past '['
-
-            let mut array = Vec::new();
-            while self.current_token != Token::RightBracket {
-                if let Token::String(value) = self.current_token {
-                    array.push(ASTNode::StringLiteral(value.to_owned()));
-                }
-                self.next_token(); // Move to the next token
-            }
-            self.next_token(); // Move past ']'
-            return ASTNode::Array(array);
-        } else if let Token::Number(ref value) = self.current_token {
-            let mut value = value.clone();
+    fn parse_if(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::If, "Expected 'if' keyword")?;
+        let condition = self.parse_expression()?;
+        let body = self.parse_block()?;
+
+        let else_body = if self.current_token == Token::Else {
             self.next_token();
+            if self.current_token == Token::If {
+                // `else if` is sugar for an else block holding a single if
+                vec![self.parse_if()?]
+            } else {
+                self.parse_block()?
+            }
+        } else {
+            Vec::new()
+        };
 
-            // Unify the following
-            while let Token::Operator(op) = self.current_token {
-                self.next_token();
-                if let Token::Number(ref next_value) = self.current_token {
-                    let original = value.parse::<u128>().unwrap();
-                    let next = next_value.parse::<u128>().unwrap();
-
-                    value = match op {
-                        "+" => (original + next).to_string(),
-                        "-" => (original - next).to_string(),
-                        "*" => (original * next).to_string(),
-                        "/" => (original / next).to_string(),
-                        "%" => (original % next).to_string(),
-                        "^" => original.pow(next as u32).to_string(),
-                        _ => panic!("Unknown operator"),
+        Ok(ASTNode::new(
+            ASTNodeKind::If {
+                condition: Box::new(condition),
+                body,
+                else_body,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    fn parse_while(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        self.expect_token(Token::While, "Expected 'while' keyword")?;
+        let condition = self.parse_expression()?;
+        let body = self.parse_block()?;
+
+        Ok(ASTNode::new(
+            ASTNodeKind::While {
+                condition: Box::new(condition),
+                body,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    // ============ Expressions ============
+
+    /// Parses a full expression using precedence climbing.
+    fn parse_expression(&mut self) -> ParseResult<ASTNode> {
+        self.parse_binary(0)
+    }
+
+    /// Parses binary operators whose precedence is above `min_precedence`.
+    fn parse_binary(&mut self, min_precedence: u8) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let mut left = self.parse_cast()?;
+
+        while let Token::Operator(op) = self.current_token {
+            let Some(op) = BinaryOp::from_operator(op) else {
+                break;
+            };
+            let precedence = op.precedence();
+            if precedence <= min_precedence {
+                break;
+            }
+
+            self.next_token(); // Move past the operator
+            let next_min = if op.is_right_associative() {
+                precedence - 1
+            } else {
+                precedence
+            };
+            let right = self.parse_binary(next_min)?;
+
+            let span = self.span_from(start);
+            left = optimizer::fold_binary(op, &left, &right, span).unwrap_or(ASTNode::new(
+                ASTNodeKind::Binary {
+                    op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                span,
+            ));
+        }
+
+        Ok(left)
+    }
+
+    /// Parses a unary expression followed by any number of `as <type>` casts,
+    /// which bind tighter than every binary operator.
+    fn parse_cast(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let mut expr = self.parse_unary()?;
+
+        while self.current_token == Token::As {
+            self.next_token(); // Move past 'as'
+            let target = self.expect_variable_type()?;
+            expr = ASTNode::new(
+                ASTNodeKind::Cast {
+                    value: Box::new(expr),
+                    target,
+                },
+                self.span_from(start),
+            );
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let op = match self.current_token {
+            Token::Operator("-") => UnaryOp::Neg,
+            Token::Operator("!") => UnaryOp::Not,
+            _ => return self.parse_postfix(),
+        };
+
+        self.next_token(); // Move past the operator
+        let operand = self.parse_unary()?;
+        Ok(ASTNode::new(
+            ASTNodeKind::Unary {
+                op,
+                operand: Box::new(operand),
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// Parses member access, indexing and calls following a primary expression.
+    fn parse_postfix(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            match self.current_token {
+                Token::Period => {
+                    self.next_token();
+                    // `schemes` is a keyword in `$define`, but also the
+                    // member of `this` scheme aliases are reached through
+                    let property = if self.current_token == Token::Schemes {
+                        self.next_token();
+                        "schemes".to_owned()
+                    } else {
+                        self.expect_identifier()?
                     };
+                    expr = ASTNode::new(
+                        ASTNodeKind::Member {
+                            object: Box::new(expr),
+                            property,
+                        },
+                        self.span_from(start),
+                    );
+                }
+                Token::LeftBracket => {
+                    self.next_token();
+                    let index = self.parse_expression()?;
+                    self.expect_token(Token::RightBracket, "Expected ']' to end index")?;
+                    expr = ASTNode::new(
+                        ASTNodeKind::Index {
+                            object: Box::new(expr),
+                            index: Box::new(index),
+                        },
+                        self.span_from(start),
+                    );
+                }
+                Token::LeftParen => {
                     self.next_token();
-                } else {
-                    panic!("Expected number after operator");
+                    let args = self.parse_expression_list(Token::RightParen)?;
+                    self.expect_token(Token::RightParen, "Expected ')' to end call arguments")?;
+                    expr = ASTNode::new(
+                        ASTNodeKind::Call {
+                            callee: Box::new(expr),
+                            args,
+                        },
+                        self.span_from(start),
+                    );
                 }
+                _ => break,
             }
+        }
 
-            return ASTNode::Number(value);
-        } else if let Token::String(value) = self.current_token {
-            return ASTNode::StringLiteral(value.to_owned());
-        } else {
-            panic!("Unexpected token in params");
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> ParseResult<ASTNode> {
+        let start = self.current_span;
+        let kind = match self.current_token {
+            Token::Number(ref value) => {
+                let value = value.clone();
+                self.next_token();
+                ASTNodeKind::Number(value)
+            }
+            Token::String(value) => {
+                self.next_token();
+                ASTNodeKind::StringLiteral(value.to_owned())
+            }
+            Token::True | Token::False => {
+                let value = self.current_token == Token::True;
+                self.next_token();
+                ASTNodeKind::Bool(value)
+            }
+            Token::Identifier(_) => ASTNodeKind::Identifier(self.expect_identifier()?),
+            Token::Placeholder(name) => {
+                self.next_token();
+                ASTNodeKind::Placeholder(name.to_owned())
+            }
+            Token::LeftParen => {
+                self.next_token();
+                let expr = self.parse_expression()?;
+                self.expect_token(Token::RightParen, "Expected ')' to close expression")?;
+                // Keep the parentheses in the span of the inner expression
+                return Ok(ASTNode::new(expr.kind, self.span_from(start)));
+            }
+            Token::LeftBracket => {
+                self.next_token();
+                let array = self.parse_expression_list(Token::RightBracket)?;
+                self.expect_token(Token::RightBracket, "Expected ']' to end array")?;
+                ASTNodeKind::Array(array)
+            }
+            _ => {
+                return Err(self.error(format!(
+                    "Expected an expression, found {:?}",
+                    self.current_token
+                )))
+            }
+        };
+
+        Ok(ASTNode::new(kind, self.span_from(start)))
+    }
+
+    /// Parses comma separated expressions up to (but not including) `end`.
+    fn parse_expression_list(&mut self, end: Token<'a>) -> ParseResult<Vec<ASTNode>> {
+        let mut list = Vec::new();
+        while self.current_token != end && self.current_token != Token::Eof {
+            list.push(self.parse_expression()?);
+
+            if self.current_token == Token::Comma {
+                self.next_token();
+            } else {
+                break;
+            }
         }
+        Ok(list)
     }
 
-    fn expect_string(&mut self, message: &str) -> String {
+    // ============ Helper functions ============
+    fn expect_string(&mut self, message: &str) -> ParseResult<String> {
         if let Token::String(value) = self.current_token {
             self.next_token();
-            value.to_owned()
+            Ok(value.to_owned())
         } else {
-            panic!("{}", message);
+            Err(self.error(message))
         }
     }
 
-    fn expect_identifier(&mut self) -> String {
+    fn expect_identifier(&mut self) -> ParseResult<String> {
         if let Token::Identifier(id) = self.current_token {
             self.next_token();
-            id.to_owned()
+            Ok(id.to_owned())
         } else {
-            panic!("Expected an identifier, found {:?}", self.current_token);
+            Err(self.error(format!(
+                "Expected an identifier, found {:?}",
+                self.current_token
+            )))
         }
     }
 
-    fn expect_token(&mut self, expected: Token<'a>, message: &str) {
+    fn expect_token(&mut self, expected: Token<'a>, message: &str) -> ParseResult<()> {
         if self.current_token != expected {
-            panic!("{}", message);
+            return Err(self.error(message));
         }
         self.next_token();
+        Ok(())
     }
 
-    fn expect_operator(&mut self, expected_op: &str) {
-        if let Token::Operator(op) = &self.current_token {
-            if *op == expected_op {
+    fn expect_operator(&mut self, expected_op: &str) -> ParseResult<()> {
+        match self.current_token {
+            Token::Operator(op) if op == expected_op => {
                 self.next_token();
-            } else {
-                panic!("Expected '{}' operator", expected_op);
+                Ok(())
             }
-        } else {
-            panic!("Expected '{}' operator", expected_op);
+            _ => Err(self.error(format!("Expected '{}' operator", expected_op))),
         }
     }
 
-    fn expect_variable_type(&mut self) -> VariableType {
+    fn is_variable_type(&self) -> bool {
+        matches!(
+            self.current_token,
+            Token::Address
+                | Token::U128
+                | Token::U8
+                | Token::Bool
+                | Token::StringType
+                | Token::Table
+        )
+    }
+
+    fn expect_variable_type(&mut self) -> ParseResult<VariableType> {
         let t = match self.current_token {
             Token::Address => VariableType::Address,
             Token::U128 => VariableType::U128,
             Token::U8 => VariableType::U8,
             Token::Bool => VariableType::Bool,
-            _ => panic!("Expected a type identifier"),
+            Token::StringType => VariableType::String,
+            Token::Table => return self.parse_table_type(),
+            _ => {
+                return Err(self.error(format!(
+                    "Expected a type identifier, found {:?}",
+                    self.current_token
+                )))
+            }
         };
 
         self.next_token();
-        t
-    }
-
-    // fn expect_number(&mut self) -> String {
-    //     if let Token::Number(value) = &self.current_token {
-    //         let num = value.clone();
-    //         self.next_token();
-    //         num
-    //     } else {
-    //         panic!("Expected a numeric value");
-    //     }
-    // }
+        Ok(t)
+    }
+
+    /// Parses a `table(K, V)` type, where the value may itself be a table.
+    fn parse_table_type(&mut self) -> ParseResult<VariableType> {
+        self.expect_token(Token::Table, "Expected 'table' keyword")?;
+        self.expect_token(Token::LeftParen, "Expected '(' after 'table'")?;
+        let key = self.expect_variable_type()?;
+        self.expect_token(
+            Token::Comma,
+            "Expected ',' between table key and value types",
+        )?;
+        let value = self.expect_variable_type()?;
+        self.expect_token(Token::RightParen, "Expected ')' to end table type")?;
+
+        Ok(VariableType::Table(Box::new(key), Box::new(value)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::source::SourceMap;
 
     #[test]
     fn test_define_parsing() {
@@ -408,12 +1437,19 @@ mod tests {
                 total_supply = 10e12 * 5
                 name = ["coolium", "COOL"]
               }
+            },
+            {
+              alias = owner
+              preset = "ownable@0.1.0"
+              params = {}
             }
           ]
         }
 
         $state {
             address creator;
+            mut u128 counter;
+            table(address, table(address, u128)) allowances;
         }
 
         $consts {
@@ -421,12 +1457,456 @@ mod tests {
         }
         "#;
 
-        let lexer = Lexer::new(input, "");
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("<input>", input), &[]);
         let mut parser = Parser::new(lexer);
-        let ast = parser.parse();
+        let ast = parser.parse().unwrap();
         // Further assertions can be made here to validate the resulting AST
         println!("{:#?}", ast);
 
+        let ASTNodeKind::Root(root) = &ast.kind else {
+            panic!("Expected a root node");
+        };
+        let ASTNodeKind::Define { schemes, .. } = &root[0].kind else {
+            panic!("Expected a define block");
+        };
+        let aliases: Vec<Option<&str>> = schemes
+            .iter()
+            .map(|scheme| match &scheme.kind {
+                ASTNodeKind::Scheme { alias, .. } => alias.as_deref(),
+                other => panic!("Expected a scheme, found {:?}", other),
+            })
+            .collect();
+        assert_eq!(aliases, vec![None, Some("owner")]);
+
+        let ASTNodeKind::State(state) = &root[1].kind else {
+            panic!("Expected a state block");
+        };
+        let mutability: Vec<bool> = state
+            .iter()
+            .map(|declaration| match &declaration.kind {
+                ASTNodeKind::StateVariableDeclaration { mutable, .. } => *mutable,
+                other => panic!("Expected a state variable, found {:?}", other),
+            })
+            .collect();
+        assert_eq!(mutability, vec![false, true, false]);
+        assert!(matches!(
+            &state[2].kind,
+            ASTNodeKind::StateVariableDeclaration { var_type, .. }
+                if var_type.to_string() == "table(address, table(address, u128))"
+        ));
+
         // assert!(false); // for debug purposes
     }
+
+    fn parse_root(input: &str) -> Vec<ASTNode> {
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("<input>", input), &[]);
+        let mut parser = Parser::new(lexer);
+        let ASTNodeKind::Root(root) = parser.parse().unwrap().kind else {
+            panic!("Expected a root node");
+        };
+        root
+    }
+
+    #[test]
+    fn test_program_parsing() {
+        let input = r#"
+        // Constructor
+        init() {
+          this.scheme.distTokens(this.caller, 10e12);
+          this.state.creator = this.caller;
+        }
+
+        init distTokens(address to, u128 amount) {}
+
+        $program {
+          pub address creator() {
+            return this.state.creator;
+          }
+
+          pub mut set_amount(u128 amount, address target) {
+            u128 doubled = amount;
+            return;
+          }
+        }
+        "#;
+
+        let root = parse_root(input);
+        assert_eq!(root.len(), 3);
+
+        match &root[0].kind {
+            ASTNodeKind::Function {
+                name,
+                public,
+                mutates,
+                constructor_only,
+                params,
+                return_type,
+                body,
+                ..
+            } => {
+                assert_eq!(name, "init");
+                assert!(!public && *mutates && *constructor_only);
+                assert!(params.is_empty() && return_type.is_none());
+                assert!(
+                    matches!(&body[0].kind, ASTNodeKind::Call { callee, args } if matches!(&callee.kind, ASTNodeKind::Member { property, .. } if property == "distTokens") && args.len() == 2)
+                );
+                assert!(
+                    matches!(&body[1].kind, ASTNodeKind::Assignment { target, .. } if matches!(&target.kind, ASTNodeKind::Member { property, .. } if property == "creator"))
+                );
+            }
+            other => panic!("Expected constructor, found {:?}", other),
+        }
+
+        // Named constructor-only helpers share the `init` keyword
+        assert!(matches!(
+            &root[1].kind,
+            ASTNodeKind::Function { name, constructor_only: true, params, .. } if name == "distTokens" && params.len() == 2
+        ));
+
+        let ASTNodeKind::Procedures(functions) = &root[2].kind else {
+            panic!("Expected procedures");
+        };
+        assert!(matches!(
+            &functions[0].kind,
+            ASTNodeKind::Function { name, public: true, mutates: false, return_type: Some(VariableType::Address), .. } if name == "creator"
+        ));
+        match &functions[1].kind {
+            ASTNodeKind::Function {
+                mutates,
+                params,
+                return_type,
+                body,
+                ..
+            } => {
+                assert!(*mutates && return_type.is_none());
+                let params: Vec<_> = params.iter().filter_map(ASTNode::as_param).collect();
+                assert_eq!(
+                    params,
+                    vec![
+                        ("amount", &VariableType::U128),
+                        ("target", &VariableType::Address)
+                    ]
+                );
+                assert!(
+                    matches!(&body[0].kind, ASTNodeKind::LocalVariableDeclaration { name, .. } if name == "doubled")
+                );
+                assert!(matches!(&body[1].kind, ASTNodeKind::Return(None)));
+            }
+            other => panic!("Expected procedure, found {:?}", other),
+        }
+    }
+
+    fn parse_single_expression(input: &str) -> ASTNode {
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("<input>", input), &[]);
+        let mut parser = Parser::new(lexer);
+        parser.parse_expression().unwrap()
+    }
+
+    #[test]
+    fn test_expression_precedence() {
+        // a || b && c + d * e == f  =>  a || (b && ((c + (d * e)) == f))
+        let expr = parse_single_expression("a || b && c + d * e == f");
+        let ASTNodeKind::Binary { op, right, .. } = expr.kind else {
+            panic!("Expected binary expression");
+        };
+        assert_eq!(op, BinaryOp::Or);
+
+        let ASTNodeKind::Binary { op, right, .. } = right.kind else {
+            panic!("Expected binary expression");
+        };
+        assert_eq!(op, BinaryOp::And);
+
+        let ASTNodeKind::Binary { op, left, .. } = right.kind else {
+            panic!("Expected binary expression");
+        };
+        assert_eq!(op, BinaryOp::Eq);
+        assert!(matches!(
+            left.kind,
+            ASTNodeKind::Binary { op: BinaryOp::Add, ref right, .. } if matches!(right.kind, ASTNodeKind::Binary { op: BinaryOp::Mul, .. })
+        ));
+
+        // Exponentiation is right associative and literal arithmetic is folded
+        assert!(
+            matches!(parse_single_expression("2 ^ 3 ^ 2").kind, ASTNodeKind::Number(n) if n == "512")
+        );
+        assert!(
+            matches!(parse_single_expression("(1 + 2) * 3").kind, ASTNodeKind::Number(n) if n == "9")
+        );
+        assert!(matches!(
+            parse_single_expression("!a").kind,
+            ASTNodeKind::Unary {
+                op: UnaryOp::Not,
+                ..
+            }
+        ));
+
+        // `^` is exponentiation, so exclusive or is `^^`, binding between `|`
+        // and `&`: a | (b ^^ (c & d))
+        let expr = parse_single_expression("a | b ^^ c & d");
+        let ASTNodeKind::Binary { op, right, .. } = expr.kind else {
+            panic!("Expected binary expression");
+        };
+        assert_eq!(op, BinaryOp::BitOr);
+        assert!(matches!(
+            right.kind,
+            ASTNodeKind::Binary { op: BinaryOp::BitXor, ref right, .. } if matches!(right.kind, ASTNodeKind::Binary { op: BinaryOp::BitAnd, .. })
+        ));
+
+        // Casts bind tighter than binary operators: a + (b as u128)
+        let expr = parse_single_expression("a + b as u128");
+        assert!(matches!(
+            expr.kind,
+            ASTNodeKind::Binary { op: BinaryOp::Add, ref right, .. } if matches!(right.kind, ASTNodeKind::Cast { target: VariableType::U128, .. })
+        ));
+    }
+
+    #[test]
+    fn test_postfix_expressions() {
+        let expr = parse_single_expression("this.state.example_numbers[this.caller]");
+        let ASTNodeKind::Index { object, index } = expr.kind else {
+            panic!("Expected index expression");
+        };
+        assert!(
+            matches!(object.kind, ASTNodeKind::Member { ref property, .. } if property == "example_numbers")
+        );
+        assert!(
+            matches!(index.kind, ASTNodeKind::Member { ref property, .. } if property == "caller")
+        );
+
+        let expr = parse_single_expression("this.scheme.distTokens(this.caller, 10e12)");
+        let ASTNodeKind::Call { callee, args } = expr.kind else {
+            panic!("Expected call expression");
+        };
+        assert!(
+            matches!(callee.kind, ASTNodeKind::Member { ref property, .. } if property == "distTokens")
+        );
+        assert!(matches!(&args[1].kind, ASTNodeKind::Number(n) if n == "10000000000000"));
+    }
+
+    #[test]
+    fn test_control_flow_statements() {
+        let input = r#"
+        $program {
+          pub mut guard(u128 amount) {
+            if (amount > 10 && this.caller == this.state.creator) {
+              this.state.example_numbers[this.caller] = amount - 1;
+            } else if amount == 0 {
+              return;
+            } else {
+              while amount < 5 {
+                amount = amount + 1;
+              }
+            }
+          }
+        }
+        "#;
+
+        let root = parse_root(input);
+        let ASTNodeKind::Procedures(functions) = &root[0].kind else {
+            panic!("Expected procedures");
+        };
+        let ASTNodeKind::Function { body, .. } = &functions[0].kind else {
+            panic!("Expected function");
+        };
+        let ASTNodeKind::If {
+            condition,
+            body,
+            else_body,
+        } = &body[0].kind
+        else {
+            panic!("Expected if statement");
+        };
+
+        assert!(matches!(
+            condition.kind,
+            ASTNodeKind::Binary {
+                op: BinaryOp::And,
+                ..
+            }
+        ));
+        assert!(
+            matches!(&body[0].kind, ASTNodeKind::Assignment { target, .. } if matches!(target.kind, ASTNodeKind::Index { .. }))
+        );
+        let ASTNodeKind::If { else_body, .. } = &else_body[0].kind else {
+            panic!("Expected else if");
+        };
+        assert!(matches!(&else_body[0].kind, ASTNodeKind::While { .. }));
+    }
+
+    #[test]
+    fn test_preset_parsing() {
+        let input = r#"
+        $preset {
+          u128 total;
+          u128 tranches = 4;
+        }
+
+        $consts {
+          u128 tranche = $total / $tranches;
+        }
+        "#;
+
+        let root = parse_root(input);
+        let ASTNodeKind::Preset(params) = &root[0].kind else {
+            panic!("Expected preset block");
+        };
+        assert!(matches!(
+            &params[0].kind,
+            ASTNodeKind::ParamDeclaration { name, default: None, .. } if name == "total"
+        ));
+        let ASTNodeKind::ParamDeclaration {
+            var_type,
+            default: Some(default),
+            ..
+        } = &params[1].kind
+        else {
+            panic!("Expected param with a default");
+        };
+        assert_eq!(*var_type, VariableType::U128);
+        assert!(matches!(&default.kind, ASTNodeKind::Number(n) if n == "4"));
+
+        let ASTNodeKind::Consts(consts) = &root[1].kind else {
+            panic!("Expected consts");
+        };
+        let ASTNodeKind::ConstDeclaration { value, .. } = &consts[0].kind else {
+            panic!("Expected const declaration");
+        };
+        let ASTNodeKind::Binary { left, right, .. } = &value.kind else {
+            panic!("Expected binary expression");
+        };
+        assert!(matches!(&left.kind, ASTNodeKind::Placeholder(name) if name == "total"));
+        assert!(matches!(&right.kind, ASTNodeKind::Placeholder(name) if name == "tranches"));
+    }
+
+    #[test]
+    fn test_node_spans() {
+        let input = "$program {\n  pub address creator(u128 salt) {\n    return this.state.creator;\n  }\n}";
+        let root = parse_root(input);
+        let text = |node: &ASTNode| &input[node.span.start..node.span.end];
+
+        assert_eq!(text(&root[0]), input);
+        let ASTNodeKind::Procedures(functions) = &root[0].kind else {
+            panic!("Expected procedures");
+        };
+        assert!(text(&functions[0]).starts_with("pub address creator("));
+        assert!(text(&functions[0]).ends_with('}'));
+
+        let ASTNodeKind::Function { params, body, .. } = &functions[0].kind else {
+            panic!("Expected function");
+        };
+        assert_eq!(text(&params[0]), "u128 salt");
+        assert_eq!(text(&body[0]), "return this.state.creator;");
+        let ASTNodeKind::Return(Some(value)) = &body[0].kind else {
+            panic!("Expected return value");
+        };
+        assert_eq!(text(value), "this.state.creator");
+        let ASTNodeKind::Member { object, .. } = &value.kind else {
+            panic!("Expected member access");
+        };
+        assert_eq!(text(object), "this.state");
+    }
+
+    #[test]
+    fn test_syntax_error_diagnostic() {
+        let input = "$state {\n  address creator\n}\n";
+
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("main.seh", input), &[]);
+        let mut parser = Parser::new(lexer);
+        let diagnostics = parser.parse().unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(
+            diagnostic.message,
+            "Expected ';' at the end of the state variable declaration"
+        );
+        assert_eq!(diagnostic.file_name, "main.seh");
+        assert_eq!((diagnostic.line, diagnostic.column), (3, 1));
+        assert_eq!(&input[diagnostic.span.start..diagnostic.span.end], "}");
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let input = r#"
+        $state {
+          address creator
+          u128 total;
+        }
+
+        $consts {
+          u128 a = ;
+          u128 b = 2;
+        }
+
+        $program {
+          pub address creator() {
+            return this.state.creator
+          }
+
+          pub mut increment(u128 x) {
+            x = x + 1;
+          }
+        }
+        "#;
+
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("<input>", input), &[]);
+        let mut parser = Parser::new(lexer);
+        let (root, diagnostics) = parser.parse_partial();
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Expected ';' at the end of the state variable declaration",
+                "Expected an expression, found SemiColon",
+                "Expected ';' after return statement",
+            ]
+        );
+        assert_eq!(
+            diagnostics.iter().map(|d| d.line).collect::<Vec<_>>(),
+            vec![4, 8, 15]
+        );
+
+        // Everything that could be parsed is still part of the tree
+        let ASTNodeKind::Root(root) = root.kind else {
+            panic!("Expected a root node");
+        };
+        assert_eq!(root.len(), 3);
+        assert!(matches!(&root[1].kind, ASTNodeKind::Consts(consts) if consts.len() == 1));
+        assert!(
+            matches!(&root[2].kind, ASTNodeKind::Procedures(functions) if functions.len() == 2)
+        );
+    }
+
+    #[test]
+    fn test_recovery_at_unclosed_block() {
+        // The program block is never closed, the parser should still pick up
+        // the state block that follows it.
+        let input = r#"
+        $program {
+          pub mut broken( {
+        $state {
+          address creator;
+        }
+        "#;
+
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("<input>", input), &[]);
+        let mut parser = Parser::new(lexer);
+        let (root, diagnostics) = parser.parse_partial();
+
+        assert!(!diagnostics.is_empty());
+        let ASTNodeKind::Root(root) = root.kind else {
+            panic!("Expected a root node");
+        };
+        assert!(
+            matches!(&root.last().unwrap().kind, ASTNodeKind::State(state) if state.len() == 1)
+        );
+    }
 }
@@ -0,0 +1,122 @@
+use super::{ParamSchema, Params, Preset, PresetFragments, Version};
+use crate::diagnostics::Diagnostic;
+use crate::source::{SourceMap, Span};
+
+pub const NAME: &str = "access";
+pub const VERSION: Version = Version::new(0, 1, 0);
+
+// Roles are plain numbers picked by the contract, except for `admin_role`
+const SOURCE: &str = r#"
+$consts {
+  // Members of the admin role grant and revoke every role
+  u128 admin_role = 0;
+}
+
+$state {
+  table(u128, table(address, bool)) members;
+}
+
+// Makes `to` an admin, contracts call it from their own init()
+init grant_admin(address to) {
+  this.state.members[admin_role][to] = true;
+}
+
+$program {
+  // Guard for procedures only members of `role` may call
+  bool require_role(u128 role) {
+    return this.state.members[role][this.caller];
+  }
+
+  pub bool has_role(u128 role, address account) {
+    return this.state.members[role][account];
+  }
+
+  pub mut bool grant_role(u128 role, address account) {
+    if !require_role(admin_role) {
+      return false;
+    }
+
+    this.state.members[role][account] = true;
+    return true;
+  }
+
+  pub mut bool revoke_role(u128 role, address account) {
+    if !require_role(admin_role) {
+      return false;
+    }
+
+    this.state.members[role][account] = false;
+    return true;
+  }
+
+  pub mut bool renounce_role(u128 role) {
+    this.state.members[role][this.caller] = false;
+    return true;
+  }
+}
+"#;
+
+pub fn preset() -> Preset {
+    Preset::new(NAME, VERSION, ParamSchema::default(), instantiate)
+}
+
+/// The access preset takes no params, every scheme gets the same fragments.
+fn instantiate(
+    _params: &Params,
+    _span: Span,
+    sources: &SourceMap,
+) -> Result<PresetFragments, Vec<Diagnostic>> {
+    PresetFragments::parse(&format!("{}@{}", NAME, VERSION), SOURCE, sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::injector;
+
+    #[test]
+    fn test_access_preset() {
+        let input = r#"
+        $define {
+          schemes = [
+            { preset = "access@0.1.0" params = {} },
+            { preset = "ownable@0.1.0" params = {} }
+          ]
+        }
+        $consts {
+          u128 minter = 1;
+        }
+        $state {
+          mut u128 minted;
+        }
+        init() {
+          this.scheme.grant_admin(this.caller);
+          this.scheme.set_owner(this.caller);
+        }
+        $program {
+          pub mut bool mint() {
+            if !this.scheme.require_role(minter) {
+              return false;
+            }
+            this.state.minted = this.state.minted + 1;
+            return true;
+          }
+
+          pub mut bool add_minter(address account) {
+            if !this.scheme.require_owner() {
+              return false;
+            }
+            return this.scheme.grant_role(minter, account);
+          }
+        }
+        "#;
+
+        let (_, symbols, diagnostics) = injector::check_source(input);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        assert!(symbols.function("access.require_role").unwrap().guard);
+        assert_eq!(
+            symbols.constant("access.admin_role"),
+            Some(&crate::parser::VariableType::U128)
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+use super::{Param, ParamSchema, Params, Preset, PresetFragments, PresetRegistry, Version};
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::lexer::Lexer;
+use crate::parser::{ASTNode, ASTNodeKind, Parser};
+use crate::source::{FileId, SourceMap, Span};
+
+/// Registers every preset file below `dir`. Preset files are headers named
+/// `<name>@<version>.seh`, with their path relative to `dir` being part of
+/// the name, so `mycorp/vesting@1.2.0.seh` provides `mycorp/vesting@1.2.0`.
+/// Other files are skipped, so presets can include plain headers.
+///
+/// Errors in the preset files are returned as diagnostics, failing to read
+/// the directory or a file within it as an error.
+pub fn load_dir(
+    registry: &mut PresetRegistry,
+    dir: &Path,
+    sources: &SourceMap,
+) -> io::Result<Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let mut files = Vec::new();
+    find_preset_files(dir, "", &mut files)?;
+
+    for (path, name, version) in files {
+        let file = sources.load(&path)?;
+        let start = Span::new(file, 0, 0);
+
+        let version = match version.parse::<Version>() {
+            Ok(version) => version,
+            Err(message) => {
+                diagnostics.push(Diagnostic::new(Severity::Error, message, start, sources));
+                continue;
+            }
+        };
+
+        match load_file(file, name, version, sources) {
+            Ok(preset) => {
+                if let Err(err) = registry.register(preset) {
+                    let message = err.to_string();
+                    diagnostics.push(Diagnostic::new(Severity::Error, message, start, sources));
+                }
+            }
+            Err(errors) => diagnostics.extend(errors),
+        }
+    }
+
+    Ok(diagnostics)
+}
+
+/// Collects `(path, name, version)` for the preset files below `dir`, in a
+/// stable order. `prefix` is the part of the name given by the directories.
+fn find_preset_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(std::path::PathBuf, String, String)>,
+) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+
+        if entry.file_type()?.is_dir() {
+            find_preset_files(&path, &format!("{}{}/", prefix, file_name), files)?;
+            continue;
+        }
+
+        let Some(stem) = file_name.strip_suffix(".seh") else {
+            continue;
+        };
+        if let Some((name, version)) = stem.split_once('@') {
+            files.push((path, format!("{}{}", prefix, name), version.to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a preset file. Its `$preset` block declares the params, which the
+/// rest of the file refers to as `$name` placeholders:
+///
+/// ```text
+/// $preset {
+///   u128 total;
+///   u128 tranches = 4;
+/// }
+///
+/// $consts {
+///   u128 tranche = $total / $tranches;
+/// }
+/// ```
+///
+/// Instantiating the preset replaces every placeholder with the value given
+/// in the scheme, or the default of the param.
+pub fn load_file(
+    file: FileId,
+    name: String,
+    version: Version,
+    sources: &SourceMap,
+) -> Result<Preset, Vec<Diagnostic>> {
+    let root = Parser::new(Lexer::new(sources, file, &[])).parse()?;
+    let ASTNodeKind::Root(items) = root.kind else {
+        return Err(Vec::new());
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut header: Option<Span> = None;
+    let mut params = Vec::new();
+    let mut code = Vec::new();
+    for item in items {
+        match item.kind {
+            ASTNodeKind::Preset(declarations) => {
+                if let Some(first) = header {
+                    let message = "A preset file can only have one `$preset` block";
+                    let note = format!("the first one is at {}", sources.location(first));
+                    diagnostics.push(
+                        Diagnostic::new(Severity::Error, message, item.span, sources)
+                            .with_note(note),
+                    );
+                    continue;
+                }
+                header = Some(item.span);
+                params = declarations;
+            }
+            ASTNodeKind::Define { .. } => {
+                let message = "Preset files cannot declare schemes";
+                diagnostics.push(Diagnostic::new(
+                    Severity::Error,
+                    message,
+                    item.span,
+                    sources,
+                ));
+            }
+            _ => code.push(item),
+        }
+    }
+
+    let preset = format!("{}@{}", name, version);
+    let schema = param_schema(&params, sources, &mut diagnostics);
+    for item in &code {
+        item.visit(&mut |node| {
+            if let ASTNodeKind::Placeholder(param) = &node.kind {
+                if schema.get(param).is_none() {
+                    let message = format!("Unknown parameter `${}` in preset `{}`", param, preset);
+                    let diagnostic = Diagnostic::new(Severity::Error, message, node.span, sources)
+                        .with_note("params are declared in the `$preset` block");
+                    diagnostics.push(diagnostic);
+                }
+            }
+        });
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let instantiate = move |params: &Params, _: Span, _: &SourceMap| {
+        let mut items = code.clone();
+        for item in &mut items {
+            item.visit_mut(&mut |node| {
+                if let ASTNodeKind::Placeholder(param) = &node.kind {
+                    // Validation fills in every param of the schema
+                    if let Some(value) = params.get(param) {
+                        *node = value.clone();
+                    }
+                }
+            });
+        }
+        Ok(PresetFragments::from_items(items))
+    };
+
+    Ok(Preset::new(name, version, schema, instantiate))
+}
+
+/// Builds the schema from the param declarations of a `$preset` block.
+fn param_schema(
+    declarations: &[ASTNode],
+    sources: &SourceMap,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> ParamSchema {
+    let mut names = HashSet::new();
+    let mut params = Vec::new();
+
+    for declaration in declarations {
+        let ASTNodeKind::ParamDeclaration {
+            name,
+            var_type,
+            default,
+        } = &declaration.kind
+        else {
+            continue;
+        };
+
+        if !names.insert(name) {
+            let message = format!("Duplicate parameter `{}`", name);
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                message,
+                declaration.span,
+                sources,
+            ));
+            continue;
+        }
+
+        params.push(match default {
+            Some(default) => Param::optional(name, var_type.clone(), default.kind.clone()),
+            None => Param::required(name, var_type.clone()),
+        });
+    }
+
+    ParamSchema::new(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generator, injector, semantics};
+    use se_opcodes::codes::Opcode;
+
+    #[test]
+    fn test_load_dir() {
+        let sources = SourceMap::new();
+        let mut registry = PresetRegistry::with_builtin();
+        let dir = Path::new("../../examples/vesting/presets");
+        let diagnostics = load_dir(&mut registry, dir, &sources).unwrap();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let preset = registry.resolve("mycorp/vesting@^1.2").unwrap();
+        assert_eq!(preset.version, Version::new(1, 2, 0));
+        assert!(preset.schema().get("tranches").unwrap().default.is_some());
+
+        let scheme = vec![(
+            "total".to_owned(),
+            ASTNode::new(ASTNodeKind::Number("1000".to_owned()), Span::default()),
+        )];
+        let fragments = preset
+            .instantiate(&scheme, Span::default(), &sources)
+            .unwrap();
+
+        let ASTNodeKind::ConstDeclaration { value, .. } = &fragments.consts[0].kind else {
+            panic!("Expected a const declaration");
+        };
+        assert!(matches!(&value.kind, ASTNodeKind::Number(total) if total == "1000"));
+    }
+
+    #[test]
+    fn test_placeholder_widths() {
+        // Every use of `$step` is a copy of the same param node, span included
+        let sources = SourceMap::new();
+        let file = sources.add(
+            "widths@1.0.0.seh",
+            "$preset {\n  u128 step;\n}\n$program {\n  pub u8 small() {\n    return $step;\n  }\n\n  pub u128 large() {\n    return $step;\n  }\n}\n",
+        );
+        let preset = load_file(file, "widths".to_owned(), Version::new(1, 0, 0), &sources).unwrap();
+        let mut registry = PresetRegistry::with_builtin();
+        registry.register(preset).unwrap();
+
+        let input =
+            r#"$define { schemes = [ { preset = "widths@1.0.0" params = { step = 7 } } ] }"#;
+        let lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+        let root = Parser::new(lexer).parse().unwrap();
+        let (root, mut diagnostics) = injector::inject(root, &registry, &sources);
+        let (symbols, analyze_diagnostics) = semantics::analyze(&root, &sources);
+        diagnostics.extend(analyze_diagnostics);
+        let (literals, type_diagnostics) = semantics::check_types(&root, &symbols, &sources);
+        diagnostics.extend(type_diagnostics);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let program = generator::generate(&root, &symbols, &literals, &sources).unwrap();
+        let (_, small) = program.function("widths.small").unwrap();
+        assert_eq!(small.code, vec![Opcode::LOADI8(1, 7), Opcode::RET]);
+        let (_, large) = program.function("widths.large").unwrap();
+        assert_eq!(large.code, vec![Opcode::LOADI(1, 7), Opcode::RET]);
+    }
+
+    #[test]
+    fn test_invalid_preset_file() {
+        let dir = std::env::temp_dir().join(format!("selc_preset_files_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("mycorp")).unwrap();
+
+        std::fs::write(
+            dir.join("mycorp/broken@1.0.0.seh"),
+            "$preset {\n  u128 total;\n  u8 total;\n}\n$consts {\n  u128 x = $totl;\n}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("mycorp/odd@latest.seh"), "$state {}").unwrap();
+        std::fs::write(dir.join("plain.seh"), "$consts {}").unwrap();
+
+        let sources = SourceMap::new();
+        let mut registry = PresetRegistry::new();
+        let diagnostics = load_dir(&mut registry, &dir, &sources).unwrap();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Duplicate parameter `total`",
+                "Unknown parameter `$totl` in preset `mycorp/broken@1.0.0`",
+                "`latest` is not a valid version",
+            ]
+        );
+        assert_eq!(registry.names().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
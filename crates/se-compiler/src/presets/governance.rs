@@ -0,0 +1,212 @@
+use super::{const_declaration, Param, ParamSchema, Params, Preset, PresetFragments, Version};
+use crate::diagnostics::Diagnostic;
+use crate::parser::VariableType;
+use crate::source::{SourceMap, Span};
+
+pub const NAME: &str = "governance";
+pub const VERSION: Version = Version::new(0, 1, 0);
+
+const SOURCE: &str = r#"
+$state {
+  // Voting weight per account, kept in sync by the contract through
+  // set_weight, usually with its token balances
+  table(address, u128) weights;
+  mut u128 total_weight;
+
+  // Proposal ids start at 1, actions are given meaning by the contract
+  mut u128 proposals;
+  table(u128, u128) actions;
+  table(u128, u128) deadlines; // Last block votes are accepted in
+  table(u128, u128) votes_for;
+  table(u128, u128) votes_against;
+  table(u128, table(address, bool)) voted;
+  table(u128, bool) executed;
+}
+
+$program {
+  // Sets the voting weight of `account`
+  mut set_weight(address account, u128 weight) {
+    this.state.total_weight = this.state.total_weight - this.state.weights[account] + weight;
+    this.state.weights[account] = weight;
+  }
+
+  // Guard for procedures only accounts with voting weight may call
+  bool require_voter() {
+    return this.state.weights[this.caller] > 0;
+  }
+
+  pub u128 weight_of(address account) {
+    return this.state.weights[account];
+  }
+
+  // Opens a vote on `action` for `voting_period` blocks, returns the id of
+  // the proposal or 0 when the caller has no voting weight
+  pub mut u128 propose(u128 action) {
+    if !require_voter() {
+      return 0;
+    }
+
+    this.state.proposals = this.state.proposals + 1;
+    u128 id = this.state.proposals;
+    this.state.actions[id] = action;
+    this.state.deadlines[id] = this.block + voting_period;
+    return id;
+  }
+
+  pub bool is_open(u128 id) {
+    return id > 0 && id <= this.state.proposals && this.block <= this.state.deadlines[id];
+  }
+
+  // Votes with the full weight of the caller, once per proposal
+  pub mut bool vote(u128 id, bool support) {
+    if !require_voter() || !is_open(id) || this.state.voted[id][this.caller] {
+      return false;
+    }
+
+    u128 weight = this.state.weights[this.caller];
+    this.state.voted[id][this.caller] = true;
+    if support {
+      this.state.votes_for[id] = this.state.votes_for[id] + weight;
+    } else {
+      this.state.votes_against[id] = this.state.votes_against[id] + weight;
+    }
+    return true;
+  }
+
+  pub u128 action_of(u128 id) {
+    return this.state.actions[id];
+  }
+
+  // Whether voting has ended with a majority in favour and at least
+  // `quorum` percent of the total weight voting
+  pub bool has_passed(u128 id) {
+    if id == 0 || id > this.state.proposals || this.block <= this.state.deadlines[id] {
+      return false;
+    }
+
+    u128 votes = this.state.votes_for[id] + this.state.votes_against[id];
+    if votes * 100 < this.state.total_weight * (quorum as u128) {
+      return false;
+    }
+    return this.state.votes_for[id] > this.state.votes_against[id];
+  }
+
+  // Marks a passed proposal as executed, contracts call it right before
+  // carrying out the action and only go on when it returns true
+  mut bool execute(u128 id) {
+    if this.state.executed[id] || !has_passed(id) {
+      return false;
+    }
+
+    this.state.executed[id] = true;
+    return true;
+  }
+}
+"#;
+
+pub fn preset() -> Preset {
+    let schema = ParamSchema::new(vec![
+        Param::required("voting_period", VariableType::U128).min(1),
+        Param::required("quorum", VariableType::U8).min(1).max(100),
+    ]);
+    Preset::new(NAME, VERSION, schema, instantiate)
+}
+
+/// Instantiates the governance preset for the validated params of a scheme:
+///
+/// ```text
+/// voting_period = 1000   // u128 const `voting_period`, in blocks
+/// quorum = 40            // u8 const `quorum`, a percentage of 1 to 100
+/// ```
+fn instantiate(
+    params: &Params,
+    _span: Span,
+    sources: &SourceMap,
+) -> Result<PresetFragments, Vec<Diagnostic>> {
+    let mut consts = Vec::new();
+    for (name, value) in params.iter() {
+        match name {
+            "voting_period" => consts.push(const_declaration(name, VariableType::U128, value)),
+            "quorum" => consts.push(const_declaration(name, VariableType::U8, value)),
+            _ => {}
+        }
+    }
+
+    let mut fragments = PresetFragments::parse(&format!("{}@{}", NAME, VERSION), SOURCE, sources)?;
+    fragments.consts = consts;
+    Ok(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::injector;
+
+    #[test]
+    fn test_governance_preset() {
+        // A DAO whose token balances are its voting weights
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = { decimals = 0 total_supply = 1000 name = ["dao", "DAO"] }
+            },
+            {
+              preset = "governance@^0.1"
+              params = { voting_period = 100 quorum = 40 }
+            }
+          ]
+        }
+        $state {
+          mut u128 treasury_action;
+        }
+        init() {
+          this.scheme.distTokens(this.caller, 1000);
+          this.scheme.set_weight(this.caller, 1000);
+        }
+        $program {
+          pub override mut bool transfer(address to, u128 amount) {
+            u128 balance = this.state.token.balances[this.caller];
+            if balance < amount {
+              return false;
+            }
+
+            this.state.token.balances[this.caller] = balance - amount;
+            this.state.token.balances[to] = this.state.token.balances[to] + amount;
+            this.scheme.set_weight(this.caller, balance - amount);
+            this.scheme.set_weight(to, this.state.token.balances[to]);
+            return true;
+          }
+
+          pub mut bool run(u128 id) {
+            if !this.schemes.governance.execute(id) {
+              return false;
+            }
+            this.state.treasury_action = this.scheme.action_of(id);
+            return true;
+          }
+        }
+        "#;
+
+        assert_eq!(injector::check_messages(input), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let input = r#"
+        $define {
+          schemes = [
+            { preset = "governance@0.1.0" params = { voting_period = 0 quorum = 120 } }
+          ]
+        }
+        "#;
+
+        assert_eq!(
+            injector::check_messages(input),
+            vec![
+                "Parameter `voting_period` must be at least 1",
+                "Parameter `quorum` must be at most 100",
+            ]
+        );
+    }
+}
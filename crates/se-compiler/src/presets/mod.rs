@@ -1 +1,359 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::lexer::Lexer;
+use crate::parser::{ASTNode, ASTNodeKind, Parser, VariableType};
+use crate::source::{SourceMap, Span};
+
+pub mod access;
+pub mod file;
+pub mod governance;
+pub mod multisig;
+pub mod nft;
+pub mod ownable;
+pub mod params;
 pub mod token;
+pub mod version;
+
+pub use params::{Constraint, Param, ParamSchema, Params};
+pub use version::{Version, VersionReq};
+
+/// The items a preset contributes to a contract, grouped by the block they
+/// belong to so the injector can merge them into the user's own blocks.
+#[derive(Debug, Default)]
+pub struct PresetFragments {
+    pub state: Vec<ASTNode>,     // StateVariableDeclaration nodes
+    pub consts: Vec<ASTNode>,    // ConstDeclaration nodes
+    pub functions: Vec<ASTNode>, // Function nodes, constructor-only helpers included
+}
+
+impl PresetFragments {
+    /// Parses Selenide source into fragments. The source is added to the
+    /// source map under `name`, so diagnostics in preset code can be rendered.
+    pub fn parse(name: &str, source: &str, sources: &SourceMap) -> Result<Self, Vec<Diagnostic>> {
+        let file = sources.add(format!("<preset {}>", name), source);
+        let root = Parser::new(Lexer::new(sources, file, &[])).parse()?;
+
+        match root.kind {
+            ASTNodeKind::Root(items) => Ok(Self::from_items(items)),
+            _ => Ok(PresetFragments::default()),
+        }
+    }
+
+    /// Sorts the top-level items of preset code into fragments, ignoring
+    /// anything that is not state, consts or procedures.
+    pub fn from_items(items: Vec<ASTNode>) -> Self {
+        let mut fragments = PresetFragments::default();
+        for item in items {
+            match item.kind {
+                ASTNodeKind::State(declarations) => fragments.state.extend(declarations),
+                ASTNodeKind::Consts(declarations) => fragments.consts.extend(declarations),
+                ASTNodeKind::Procedures(functions) => fragments.functions.extend(functions),
+                ASTNodeKind::Function { .. } => fragments.functions.push(item),
+                _ => {}
+            }
+        }
+
+        fragments
+    }
+}
+
+type Instantiate = dyn Fn(&Params, Span, &SourceMap) -> Result<PresetFragments, Vec<Diagnostic>>;
+
+/// A named and versioned preset that can be referenced from a scheme.
+pub struct Preset {
+    pub name: String,
+    pub version: Version,
+    schema: ParamSchema,
+    instantiate: Box<Instantiate>,
+}
+
+impl Preset {
+    pub fn new(
+        name: impl Into<String>,
+        version: Version,
+        schema: ParamSchema,
+        instantiate: impl Fn(&Params, Span, &SourceMap) -> Result<PresetFragments, Vec<Diagnostic>>
+            + 'static,
+    ) -> Self {
+        Preset {
+            name: name.into(),
+            version,
+            schema,
+            instantiate: Box::new(instantiate),
+        }
+    }
+
+    pub fn schema(&self) -> &ParamSchema {
+        &self.schema
+    }
+
+    /// Checks the params of a scheme against the schema of the preset,
+    /// `span` being the span of that scheme.
+    pub fn validate(
+        &self,
+        params: &[(String, ASTNode)],
+        span: Span,
+        sources: &SourceMap,
+    ) -> Result<Params, Vec<Diagnostic>> {
+        self.schema
+            .validate(&self.to_string(), params, span, sources)
+    }
+
+    /// Builds the fragments for the params of a scheme, `span` being the
+    /// span of that scheme.
+    pub fn instantiate(
+        &self,
+        params: &[(String, ASTNode)],
+        span: Span,
+        sources: &SourceMap,
+    ) -> Result<PresetFragments, Vec<Diagnostic>> {
+        let params = self.validate(params, span, sources)?;
+        (self.instantiate)(&params, span, sources)
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.name, self.version)
+    }
+}
+
+impl fmt::Debug for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Preset({})", self)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PresetError {
+    InvalidReference(String),
+    InvalidVersion(String),
+    UnknownPreset(String),
+    NoMatchingVersion(String, VersionReq),
+    AlreadyRegistered(String, Version),
+    IncompatibleCompiler(VersionReq, Version),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::InvalidReference(reference) => write!(
+                f,
+                "Invalid preset reference `{}`, expected `name@version`",
+                reference
+            ),
+            PresetError::InvalidVersion(message) => write!(f, "{}", message),
+            PresetError::UnknownPreset(name) => write!(f, "Unknown preset `{}`", name),
+            PresetError::NoMatchingVersion(name, requirement) => write!(
+                f,
+                "No version of preset `{}` matches `{}`",
+                name, requirement
+            ),
+            PresetError::AlreadyRegistered(name, version) => {
+                write!(f, "Preset `{}@{}` is already registered", name, version)
+            }
+            PresetError::IncompatibleCompiler(requirement, version) => write!(
+                f,
+                "This contract requires compiler version `{}`, but this is {}",
+                requirement, version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+/// Every preset known to the compiler, by name and version.
+#[derive(Debug, Default)]
+pub struct PresetRegistry {
+    presets: BTreeMap<String, BTreeMap<Version, Preset>>,
+}
+
+impl PresetRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry holding the presets that ship with the compiler.
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        for preset in [
+            token::preset(),
+            nft::preset(),
+            ownable::preset(),
+            access::preset(),
+            multisig::preset(),
+            governance::preset(),
+        ] {
+            registry
+                .register(preset)
+                .expect("builtin presets have unique versions");
+        }
+        registry
+    }
+
+    pub fn register(&mut self, preset: Preset) -> Result<(), PresetError> {
+        let versions = self.presets.entry(preset.name.clone()).or_default();
+        if versions.contains_key(&preset.version) {
+            return Err(PresetError::AlreadyRegistered(preset.name, preset.version));
+        }
+
+        versions.insert(preset.version, preset);
+        Ok(())
+    }
+
+    /// Resolves a reference such as `token@0.1.0` or `token@^0.1` to the
+    /// newest registered version matching the requirement.
+    pub fn resolve(&self, reference: &str) -> Result<&Preset, PresetError> {
+        let Some((name, requirement)) = reference.split_once('@') else {
+            return Err(PresetError::InvalidReference(reference.to_owned()));
+        };
+        let requirement: VersionReq = requirement.parse().map_err(PresetError::InvalidVersion)?;
+
+        let versions = self
+            .presets
+            .get(name)
+            .ok_or_else(|| PresetError::UnknownPreset(name.to_owned()))?;
+
+        versions
+            .values()
+            .rev()
+            .find(|preset| requirement.matches(&preset.version))
+            .ok_or(PresetError::NoMatchingVersion(name.to_owned(), requirement))
+    }
+
+    /// Registered versions of a preset, oldest first.
+    pub fn versions(&self, name: &str) -> Vec<Version> {
+        self.presets
+            .get(name)
+            .map(|versions| versions.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.presets.keys().map(String::as_str)
+    }
+
+    /// Checks a `$define` version requirement against the running compiler,
+    /// which is what the built-in presets are written for.
+    pub fn check_compiler_version(&self, requirement: &str) -> Result<(), PresetError> {
+        let requirement: VersionReq = requirement.parse().map_err(PresetError::InvalidVersion)?;
+        let compiler: Version = crate::VERSION
+            .parse()
+            .expect("the crate version is a valid version");
+
+        if requirement.matches(&compiler) {
+            Ok(())
+        } else {
+            Err(PresetError::IncompatibleCompiler(requirement, compiler))
+        }
+    }
+
+    /// Resolves the preset reference of a scheme, reporting failures at the
+    /// scheme along with what is available instead.
+    pub fn resolve_scheme(
+        &self,
+        reference: &str,
+        span: Span,
+        sources: &SourceMap,
+    ) -> Result<&Preset, Box<Diagnostic>> {
+        self.resolve(reference).map_err(|err| {
+            let diagnostic = Diagnostic::new(Severity::Error, err.to_string(), span, sources);
+            Box::new(self.with_help(diagnostic, &err))
+        })
+    }
+
+    /// Adds a note listing what is available to resolution errors.
+    fn with_help(&self, diagnostic: Diagnostic, err: &PresetError) -> Diagnostic {
+        match err {
+            PresetError::UnknownPreset(_) => {
+                let names: Vec<&str> = self.names().collect();
+                diagnostic.with_note(format!("available presets: {}", names.join(", ")))
+            }
+            PresetError::NoMatchingVersion(name, _) => {
+                let versions: Vec<String> =
+                    self.versions(name).iter().map(Version::to_string).collect();
+                diagnostic.with_note(format!("available versions: {}", versions.join(", ")))
+            }
+            _ => diagnostic,
+        }
+    }
+}
+
+/// Creates a const declaration located at the param value it was built from.
+fn const_declaration(name: &str, var_type: VariableType, value: &ASTNode) -> ASTNode {
+    ASTNode::new(
+        ASTNodeKind::ConstDeclaration {
+            name: name.to_owned(),
+            var_type,
+            value: Box::new(value.clone()),
+        },
+        value.span,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_preset(name: &str, version: Version) -> Preset {
+        Preset::new(name, version, ParamSchema::default(), |_, _, _| {
+            Ok(PresetFragments::default())
+        })
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut registry = PresetRegistry::with_builtin();
+        registry
+            .register(empty_preset("token", Version::new(0, 1, 4)))
+            .unwrap();
+        registry
+            .register(empty_preset("token", Version::new(0, 2, 0)))
+            .unwrap();
+
+        let resolve = |reference| registry.resolve(reference).map(|preset| preset.version);
+        assert_eq!(resolve("token@0.1.0"), Ok(Version::new(0, 1, 0)));
+        assert_eq!(resolve("token@^0.1"), Ok(Version::new(0, 1, 4)));
+        assert_eq!(resolve("token@^0.2.0"), Ok(Version::new(0, 2, 0)));
+        assert_eq!(
+            resolve("token@^1.0"),
+            Err(PresetError::NoMatchingVersion(
+                "token".to_owned(),
+                "^1.0".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            resolve("vesting@0.1.0"),
+            Err(PresetError::UnknownPreset("vesting".to_owned()))
+        );
+        assert_eq!(
+            resolve("token"),
+            Err(PresetError::InvalidReference("token".to_owned()))
+        );
+        assert!(matches!(
+            resolve("token@latest"),
+            Err(PresetError::InvalidVersion(_))
+        ));
+
+        assert_eq!(
+            registry.register(empty_preset("token", Version::new(0, 2, 0))),
+            Err(PresetError::AlreadyRegistered(
+                "token".to_owned(),
+                Version::new(0, 2, 0)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_compiler_version() {
+        let registry = PresetRegistry::new();
+        assert!(registry.check_compiler_version("^0.1.0").is_ok());
+        assert!(matches!(
+            registry.check_compiler_version("^9.0"),
+            Err(PresetError::IncompatibleCompiler(..))
+        ));
+    }
+}
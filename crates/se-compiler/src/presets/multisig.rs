@@ -0,0 +1,198 @@
+use super::{const_declaration, Param, ParamSchema, Params, Preset, PresetFragments, Version};
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::parser::{ASTNodeKind, VariableType};
+use crate::source::{SourceMap, Span};
+
+pub const NAME: &str = "multisig";
+pub const VERSION: Version = Version::new(0, 1, 0);
+
+const SOURCE: &str = r#"
+$state {
+  table(address, bool) registered;
+  mut u8 signer_count;
+
+  // Proposal ids start at 1. An action is a number the contract gives
+  // meaning to, such as an index into its own table of payouts.
+  mut u128 proposals;
+  table(u128, u128) actions;
+  table(u128, u8) approvals;
+  table(u128, table(address, bool)) approved;
+  table(u128, bool) executed;
+}
+
+// Registers one of the signers, contracts call it from their own init().
+// Signers beyond `signers` are ignored.
+init add_signer(address signer) {
+  if !this.state.registered[signer] && this.state.signer_count < signers {
+    this.state.registered[signer] = true;
+    this.state.signer_count = this.state.signer_count + 1;
+  }
+}
+
+$program {
+  // Guard for procedures only signers may call
+  bool require_signer() {
+    return this.state.registered[this.caller];
+  }
+
+  pub bool is_signer(address account) {
+    return this.state.registered[account];
+  }
+
+  // Proposes `action` and approves it for the caller, returns the id of the
+  // proposal or 0 when the caller is not a signer
+  pub mut u128 propose(u128 action) {
+    if !require_signer() {
+      return 0;
+    }
+
+    this.state.proposals = this.state.proposals + 1;
+    u128 id = this.state.proposals;
+    this.state.actions[id] = action;
+    approve(id);
+    return id;
+  }
+
+  pub mut bool approve(u128 id) {
+    if !require_signer() || id == 0 || id > this.state.proposals {
+      return false;
+    }
+    if this.state.executed[id] || this.state.approved[id][this.caller] {
+      return false;
+    }
+
+    this.state.approved[id][this.caller] = true;
+    this.state.approvals[id] = this.state.approvals[id] + 1;
+    return true;
+  }
+
+  pub mut bool revoke(u128 id) {
+    if this.state.executed[id] || !this.state.approved[id][this.caller] {
+      return false;
+    }
+
+    this.state.approved[id][this.caller] = false;
+    this.state.approvals[id] = this.state.approvals[id] - 1;
+    return true;
+  }
+
+  pub u128 action_of(u128 id) {
+    return this.state.actions[id];
+  }
+
+  pub bool is_approved(u128 id) {
+    return this.state.approvals[id] >= threshold;
+  }
+
+  // Marks an approved proposal as executed, contracts call it right before
+  // carrying out the action and only go on when it returns true
+  mut bool execute(u128 id) {
+    if this.state.executed[id] || this.state.approvals[id] < threshold {
+      return false;
+    }
+
+    this.state.executed[id] = true;
+    return true;
+  }
+}
+"#;
+
+pub fn preset() -> Preset {
+    let schema = ParamSchema::new(vec![
+        Param::required("threshold", VariableType::U8).min(1),
+        Param::required("signers", VariableType::U8).min(1),
+    ]);
+    Preset::new(NAME, VERSION, schema, instantiate)
+}
+
+/// Instantiates the multisig preset for the validated params of a scheme,
+/// each param becomes a `u8` const of the same name:
+///
+/// ```text
+/// threshold = 2   // approvals a proposal needs, at most `signers`
+/// signers = 3     // signers registered through add_signer
+/// ```
+fn instantiate(
+    params: &Params,
+    _span: Span,
+    sources: &SourceMap,
+) -> Result<PresetFragments, Vec<Diagnostic>> {
+    let number = |name| match params.get(name).map(|value| &value.kind) {
+        Some(ASTNodeKind::Number(literal)) => literal.parse::<u8>().ok(),
+        _ => None,
+    };
+    if let (Some(threshold), Some(signers)) = (number("threshold"), number("signers")) {
+        if threshold > signers {
+            let message = format!(
+                "Parameter `threshold` must be at most `signers` ({})",
+                signers
+            );
+            let span = params.get("threshold").unwrap().span;
+            let diagnostic = Diagnostic::new(Severity::Error, message, span, sources)
+                .with_note("a proposal could never get enough approvals");
+            return Err(vec![diagnostic]);
+        }
+    }
+
+    let consts = params
+        .iter()
+        .map(|(name, value)| const_declaration(name, VariableType::U8, value))
+        .collect();
+
+    let mut fragments = PresetFragments::parse(&format!("{}@{}", NAME, VERSION), SOURCE, sources)?;
+    fragments.consts = consts;
+    Ok(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::injector;
+
+    #[test]
+    fn test_multisig_preset() {
+        let input = r#"
+        $define {
+          schemes = [
+            { preset = "multisig@^0.1" params = { threshold = 2 signers = 3 } }
+          ]
+        }
+        $state {
+          mut u128 paid;
+        }
+        init() {
+          this.scheme.add_signer(this.caller);
+        }
+        $program {
+          pub mut bool pay(u128 id) {
+            if !this.scheme.execute(id) {
+              return false;
+            }
+            this.state.paid = this.state.paid + this.scheme.action_of(id);
+            return true;
+          }
+        }
+        "#;
+
+        assert_eq!(injector::check_messages(input), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let input = r#"
+        $define {
+          schemes = [
+            { preset = "multisig@0.1.0" params = { threshold = 4 signers = 3 } },
+            { alias = second preset = "multisig@0.1.0" params = { threshold = 0 signers = 3 } }
+          ]
+        }
+        "#;
+
+        assert_eq!(
+            injector::check_messages(input),
+            vec![
+                "Parameter `threshold` must be at most `signers` (3)",
+                "Parameter `threshold` must be at least 1",
+            ]
+        );
+    }
+}
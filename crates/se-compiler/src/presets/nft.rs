@@ -0,0 +1,241 @@
+use super::{const_declaration, Param, ParamSchema, Params, Preset, PresetFragments, Version};
+use crate::diagnostics::Diagnostic;
+use crate::parser::VariableType;
+use crate::source::{SourceMap, Span};
+
+pub const NAME: &str = "nft";
+pub const VERSION: Version = Version::new(0, 1, 0);
+
+const SOURCE: &str = r#"
+$state {
+  // There is no null address, so `minted` tells which ids have an owner
+  table(u128, address) owners;
+  table(u128, bool) minted;
+  table(address, u128) balances;
+
+  // A single approved address per token, set while `approved` is true
+  table(u128, address) approvals;
+  table(u128, bool) approved;
+  table(address, table(address, bool)) operators;
+
+  mut u128 supply;
+}
+
+$program {
+  // Creates token `id` for `to`, fails when the id is already taken. Hooks
+  // do not check the caller, the procedures calling them decide who may.
+  mut bool mint(address to, u128 id) {
+    if this.state.minted[id] {
+      return false;
+    }
+
+    this.state.minted[id] = true;
+    this.state.owners[id] = to;
+    this.state.balances[to] = this.state.balances[to] + 1;
+    this.state.supply = this.state.supply + 1;
+    return true;
+  }
+
+  // Destroys token `id` along with its approval
+  mut bool burn(u128 id) {
+    if !this.state.minted[id] {
+      return false;
+    }
+
+    address owner = this.state.owners[id];
+    this.state.minted[id] = false;
+    this.state.approved[id] = false;
+    this.state.balances[owner] = this.state.balances[owner] - 1;
+    this.state.supply = this.state.supply - 1;
+    return true;
+  }
+
+  pub u128 total_supply() {
+    return this.state.supply;
+  }
+
+  pub u128 balance_of(address owner) {
+    return this.state.balances[owner];
+  }
+
+  pub bool exists(u128 id) {
+    return this.state.minted[id];
+  }
+
+  // Only meaningful for ids that exist
+  pub address owner_of(u128 id) {
+    return this.state.owners[id];
+  }
+
+  pub bool is_approved_for_all(address owner, address operator) {
+    return this.state.operators[owner][operator];
+  }
+
+  // Moves token `id` to `to`, allowed for its owner, the approved address
+  // and operators of the owner. The approval does not carry over.
+  pub mut bool transfer(address to, u128 id) {
+    address owner = this.state.owners[id];
+    bool approved = this.state.approved[id] && this.state.approvals[id] == this.caller;
+    if !this.state.minted[id] {
+      return false;
+    }
+    if this.caller != owner && !approved && !this.state.operators[owner][this.caller] {
+      return false;
+    }
+
+    this.state.approved[id] = false;
+    this.state.owners[id] = to;
+    this.state.balances[owner] = this.state.balances[owner] - 1;
+    this.state.balances[to] = this.state.balances[to] + 1;
+    return true;
+  }
+
+  // Lets `spender` transfer token `id`, allowed for its owner and operators
+  pub mut bool approve(address spender, u128 id) {
+    address owner = this.state.owners[id];
+    if !this.state.minted[id] {
+      return false;
+    }
+    if this.caller != owner && !this.state.operators[owner][this.caller] {
+      return false;
+    }
+
+    this.state.approvals[id] = spender;
+    this.state.approved[id] = true;
+    return true;
+  }
+
+  pub mut bool set_approval_for_all(address operator, bool approved) {
+    this.state.operators[this.caller][operator] = approved;
+    return true;
+  }
+}
+"#;
+
+pub fn preset() -> Preset {
+    let schema = ParamSchema::new(vec![
+        Param::required("name", VariableType::String),
+        Param::required("symbol", VariableType::String),
+        Param::required("base_uri", VariableType::String),
+    ]);
+    Preset::new(NAME, VERSION, schema, instantiate)
+}
+
+/// Instantiates the nft preset for the validated params of a scheme, each
+/// param becomes a string const of the same name:
+///
+/// ```text
+/// name = "Kitties"                         // const `name`
+/// symbol = "KIT"                           // const `symbol`
+/// base_uri = "https://kitties.io/token/"   // const `base_uri`
+/// ```
+fn instantiate(
+    params: &Params,
+    _span: Span,
+    sources: &SourceMap,
+) -> Result<PresetFragments, Vec<Diagnostic>> {
+    let consts = params
+        .iter()
+        .map(|(name, value)| const_declaration(name, VariableType::String, value))
+        .collect();
+
+    let mut fragments = PresetFragments::parse(&format!("{}@{}", NAME, VERSION), SOURCE, sources)?;
+    fragments.consts = consts;
+    Ok(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::injector;
+    use crate::parser::{ASTNode, ASTNodeKind};
+
+    #[test]
+    fn test_nft_preset() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "nft@^0.1"
+              params = {
+                name = "Kitties"
+                symbol = "KIT"
+                base_uri = "https://kitties.io/token/"
+              }
+            }
+          ]
+        }
+        init() {
+          this.scheme.mint(this.caller, 0);
+        }
+        $program {
+          pub mut bool adopt(u128 id) {
+            return this.scheme.mint(this.caller, id);
+          }
+
+          pub mut bool release(u128 id) {
+            if this.scheme.owner_of(id) != this.caller {
+              return false;
+            }
+            return this.scheme.burn(id);
+          }
+        }
+        "#;
+
+        assert_eq!(injector::check_messages(input), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_hooks_need_mut() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "nft@0.1.0"
+              params = { name = "Kitties" symbol = "KIT" base_uri = "" }
+            }
+          ]
+        }
+        $program {
+          pub bool adopt(u128 id) {
+            return this.scheme.mint(this.caller, id);
+          }
+        }
+        "#;
+
+        assert_eq!(
+            injector::check_messages(input),
+            vec!["Cannot call `mut` function `nft.mint` from `adopt`, which is not marked `mut`"]
+        );
+    }
+
+    #[test]
+    fn test_consts() {
+        let sources = SourceMap::new();
+        sources.add("main.se", "");
+        let string = |value: &str| {
+            ASTNode::new(
+                ASTNodeKind::StringLiteral(value.to_owned()),
+                Span::default(),
+            )
+        };
+        let params = vec![
+            ("name".to_owned(), string("Kitties")),
+            ("symbol".to_owned(), string("KIT")),
+            ("base_uri".to_owned(), string("https://kitties.io/token/")),
+        ];
+
+        let fragments = preset()
+            .instantiate(&params, Span::default(), &sources)
+            .unwrap();
+        let consts: Vec<&str> = fragments
+            .consts
+            .iter()
+            .filter_map(|node| match &node.kind {
+                ASTNodeKind::ConstDeclaration { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(consts, vec!["name", "symbol", "base_uri"]);
+    }
+}
@@ -0,0 +1,99 @@
+use super::{ParamSchema, Params, Preset, PresetFragments, Version};
+use crate::diagnostics::Diagnostic;
+use crate::source::{SourceMap, Span};
+
+pub const NAME: &str = "ownable";
+pub const VERSION: Version = Version::new(0, 1, 0);
+
+const SOURCE: &str = r#"
+$state {
+  mut address owner;
+}
+
+// Sets the first owner, contracts call it from their own init()
+init set_owner(address to) {
+  this.state.owner = to;
+}
+
+$program {
+  // Guard for procedures only the owner may call
+  bool require_owner() {
+    return this.caller == this.state.owner;
+  }
+
+  pub address owner() {
+    return this.state.owner;
+  }
+
+  pub mut bool transfer_ownership(address to) {
+    if !require_owner() {
+      return false;
+    }
+
+    this.state.owner = to;
+    return true;
+  }
+}
+"#;
+
+pub fn preset() -> Preset {
+    Preset::new(NAME, VERSION, ParamSchema::default(), instantiate)
+}
+
+/// The ownable preset takes no params, every scheme gets the same fragments.
+fn instantiate(
+    _params: &Params,
+    _span: Span,
+    sources: &SourceMap,
+) -> Result<PresetFragments, Vec<Diagnostic>> {
+    PresetFragments::parse(&format!("{}@{}", NAME, VERSION), SOURCE, sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::injector;
+
+    #[test]
+    fn test_ownable_preset() {
+        let input = r#"
+        $define {
+          schemes = [
+            { preset = "ownable@^0.1" params = {} }
+          ]
+        }
+        $state {
+          mut u128 fee;
+        }
+        init() {
+          this.scheme.set_owner(this.caller);
+        }
+        $program {
+          pub mut bool set_fee(u128 fee) {
+            if !this.scheme.require_owner() {
+              return false;
+            }
+            this.state.fee = fee;
+            return true;
+          }
+
+          pub mut set_fee_unchecked(u128 fee) {
+            this.scheme.require_owner();
+            this.state.fee = fee;
+          }
+        }
+        "#;
+
+        let (_, symbols, diagnostics) = injector::check_source(input);
+
+        assert!(symbols.function("ownable.require_owner").unwrap().guard);
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["The result of guard `ownable.require_owner` is not checked"]
+        );
+        assert_eq!(
+            diagnostics[0].notes,
+            vec!["guards do not stop the procedure, use `if !this.scheme.require_owner(...) { return ...; }`"]
+        );
+    }
+}
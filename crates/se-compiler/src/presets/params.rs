@@ -0,0 +1,390 @@
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::parser::{ASTNode, ASTNodeKind, VariableType};
+use crate::source::{SourceMap, Span};
+
+/// A restriction on a param value on top of its type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    Min(u128),  // Integers only
+    Max(u128),  // Integers only
+    Len(usize), // Arrays only, the exact number of elements
+}
+
+/// A single param a preset takes. Params without a default are required.
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    pub param_type: VariableType,
+    pub default: Option<ASTNodeKind>, // Located at the scheme when used
+    pub constraints: Vec<Constraint>,
+}
+
+impl Param {
+    pub fn required(name: impl Into<String>, param_type: VariableType) -> Self {
+        Param {
+            name: name.into(),
+            param_type,
+            default: None,
+            constraints: Vec::new(),
+        }
+    }
+
+    pub fn optional(
+        name: impl Into<String>,
+        param_type: VariableType,
+        default: ASTNodeKind,
+    ) -> Self {
+        Param {
+            default: Some(default),
+            ..Param::required(name, param_type)
+        }
+    }
+
+    pub fn min(mut self, min: u128) -> Self {
+        self.constraints.push(Constraint::Min(min));
+        self
+    }
+
+    pub fn max(mut self, max: u128) -> Self {
+        self.constraints.push(Constraint::Max(max));
+        self
+    }
+
+    pub fn len(mut self, len: usize) -> Self {
+        self.constraints.push(Constraint::Len(len));
+        self
+    }
+
+    /// The type as written in diagnostics, e.g. `[string; 2]`.
+    fn describe(&self) -> String {
+        let len = self
+            .constraints
+            .iter()
+            .find_map(|constraint| match constraint {
+                Constraint::Len(len) => Some(len),
+                _ => None,
+            });
+        match (&self.param_type, len) {
+            (VariableType::Array(element), Some(len)) => format!("[{}; {}]", element, len),
+            (param_type, _) => param_type.to_string(),
+        }
+    }
+}
+
+/// The params of a scheme after validation, in schema order and with the
+/// defaults filled in.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    values: Vec<(String, ASTNode)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&ASTNode> {
+        self.values
+            .iter()
+            .find(|(id, _)| id == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ASTNode)> {
+        self.values
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+}
+
+/// Every param a preset takes.
+#[derive(Debug, Clone, Default)]
+pub struct ParamSchema {
+    params: Vec<Param>,
+}
+
+impl ParamSchema {
+    pub fn new(params: Vec<Param>) -> Self {
+        ParamSchema { params }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|param| param.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Param> {
+        self.params.iter()
+    }
+
+    /// Checks the params of a scheme against the schema, reporting every
+    /// unknown, duplicate, missing or invalid param. `preset` names the
+    /// preset in diagnostics and `span` is the span of the scheme.
+    pub fn validate(
+        &self,
+        preset: &str,
+        params: &[(String, ASTNode)],
+        span: Span,
+        sources: &SourceMap,
+    ) -> Result<Params, Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+
+        for (index, (name, value)) in params.iter().enumerate() {
+            if self.get(name).is_none() {
+                let message = format!("Unknown parameter `{}` for preset `{}`", name, preset);
+                let diagnostic = Diagnostic::new(Severity::Error, message, value.span, sources);
+                diagnostics.push(diagnostic.with_note(self.suggest(preset, name)));
+            } else if params[..index].iter().any(|(id, _)| id == name) {
+                let message = format!("Parameter `{}` is given more than once", name);
+                diagnostics.push(Diagnostic::new(
+                    Severity::Error,
+                    message,
+                    value.span,
+                    sources,
+                ));
+            }
+        }
+
+        let mut values = Vec::new();
+        for param in &self.params {
+            let value = match params.iter().find(|(id, _)| *id == param.name) {
+                Some((_, value)) => value.clone(),
+                None => match &param.default {
+                    Some(default) => ASTNode::new(default.clone(), span),
+                    None => {
+                        let message =
+                            format!("Missing parameter `{}` for preset `{}`", param.name, preset);
+                        let diagnostic = Diagnostic::new(Severity::Error, message, span, sources)
+                            .with_note(format!("`{}` expects `{}`", param.name, param.describe()));
+                        diagnostics.push(diagnostic);
+                        continue;
+                    }
+                },
+            };
+
+            match check_value(param, &param.param_type, &value) {
+                Ok(()) => values.push((param.name.clone(), value)),
+                Err((span, message)) => {
+                    diagnostics.push(Diagnostic::new(Severity::Error, message, span, sources));
+                }
+            }
+        }
+
+        if diagnostics.is_empty() {
+            Ok(Params { values })
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    /// A note for an unknown param, pointing out a likely typo when there is
+    /// a param with a similar name.
+    fn suggest(&self, preset: &str, name: &str) -> String {
+        let closest = self
+            .params
+            .iter()
+            .map(|param| (edit_distance(name, &param.name), &param.name))
+            .filter(|(distance, _)| *distance <= 2)
+            .min_by_key(|(distance, _)| *distance);
+        if let Some((_, closest)) = closest {
+            return format!("did you mean `{}`?", closest);
+        }
+
+        let names: Vec<String> = self
+            .params
+            .iter()
+            .map(|param| format!("`{}`", param.name))
+            .collect();
+        match names.as_slice() {
+            [] => format!("`{}` takes no parameters", preset),
+            [name] => format!("`{}` takes {}", preset, name),
+            [rest @ .., last] => format!("`{}` takes {} and {}", preset, rest.join(", "), last),
+        }
+    }
+}
+
+/// Checks a value against a type and the constraints of its param, returning
+/// where and why it does not match.
+fn check_value(
+    param: &Param,
+    param_type: &VariableType,
+    value: &ASTNode,
+) -> Result<(), (Span, String)> {
+    let mismatch = || {
+        let message = format!(
+            "Parameter `{}` expects `{}`, found {}",
+            param.name,
+            param.describe(),
+            describe_value(value)
+        );
+        Err((value.span, message))
+    };
+
+    match (param_type, &value.kind) {
+        (VariableType::U8 | VariableType::U128, ASTNodeKind::Number(literal)) => {
+            let fits = match param_type {
+                VariableType::U8 => literal.parse::<u8>().map(u128::from),
+                _ => literal.parse::<u128>(),
+            };
+            let Ok(number) = fits else {
+                let message = format!(
+                    "Parameter `{}` does not fit in `{}`: `{}`",
+                    param.name, param_type, literal
+                );
+                return Err((value.span, message));
+            };
+
+            for constraint in &param.constraints {
+                let message = match constraint {
+                    Constraint::Min(min) if number < *min => {
+                        format!("Parameter `{}` must be at least {}", param.name, min)
+                    }
+                    Constraint::Max(max) if number > *max => {
+                        format!("Parameter `{}` must be at most {}", param.name, max)
+                    }
+                    _ => continue,
+                };
+                return Err((value.span, message));
+            }
+            Ok(())
+        }
+        (VariableType::String, ASTNodeKind::StringLiteral(_))
+        | (VariableType::Bool, ASTNodeKind::Bool(_))
+        | (VariableType::Address, ASTNodeKind::Address(_)) => Ok(()),
+        (VariableType::Array(element), ASTNodeKind::Array(elements)) => {
+            for constraint in &param.constraints {
+                if let Constraint::Len(len) = constraint {
+                    if elements.len() != *len {
+                        return mismatch();
+                    }
+                }
+            }
+            elements
+                .iter()
+                .try_for_each(|value| check_value(param, element, value))
+        }
+        _ => mismatch(),
+    }
+}
+
+/// What kind of value was found, used in type mismatch diagnostics.
+fn describe_value(value: &ASTNode) -> String {
+    match &value.kind {
+        ASTNodeKind::Number(_) => "a number".to_owned(),
+        ASTNodeKind::StringLiteral(_) => "a string".to_owned(),
+        ASTNodeKind::Bool(_) => "a bool".to_owned(),
+        ASTNodeKind::Address(_) => "an address".to_owned(),
+        ASTNodeKind::Array(elements) => format!("an array of {} elements", elements.len()),
+        _ => "an expression that is not a constant".to_owned(),
+    }
+}
+
+/// The number of single character edits needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous + usize::from(a != *b);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(kind: ASTNodeKind) -> ASTNode {
+        ASTNode::new(kind, Span::default())
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = ParamSchema::new(vec![
+            Param::required("decimals", VariableType::U8).max(36),
+            Param::optional("paused", VariableType::Bool, ASTNodeKind::Bool(false)),
+            Param::required("name", VariableType::Array(Box::new(VariableType::String))).len(2),
+        ]);
+        let sources = SourceMap::new();
+        sources.add("main.se", "");
+
+        let string = |value: &str| node(ASTNodeKind::StringLiteral(value.to_owned()));
+        let params = vec![
+            (
+                "decimals".to_owned(),
+                node(ASTNodeKind::Number("12".to_owned())),
+            ),
+            (
+                "name".to_owned(),
+                node(ASTNodeKind::Array(vec![string("coolium"), string("COOL")])),
+            ),
+        ];
+        let values = schema
+            .validate("token@0.1.0", &params, Span::default(), &sources)
+            .unwrap();
+        assert!(matches!(
+            values.get("paused").unwrap().kind,
+            ASTNodeKind::Bool(false)
+        ));
+
+        let params = vec![
+            (
+                "decimal".to_owned(),
+                node(ASTNodeKind::Number("12".to_owned())),
+            ),
+            ("name".to_owned(), string("COOL")),
+            (
+                "paused".to_owned(),
+                node(ASTNodeKind::Number("1".to_owned())),
+            ),
+            ("paused".to_owned(), node(ASTNodeKind::Bool(true))),
+        ];
+        let diagnostics = schema
+            .validate("token@0.1.0", &params, Span::default(), &sources)
+            .unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Unknown parameter `decimal` for preset `token@0.1.0`",
+                "Parameter `paused` is given more than once",
+                "Missing parameter `decimals` for preset `token@0.1.0`",
+                "Parameter `paused` expects `bool`, found a number",
+                "Parameter `name` expects `[string; 2]`, found a string",
+            ]
+        );
+        assert_eq!(diagnostics[0].notes, vec!["did you mean `decimals`?"]);
+
+        let params = vec![
+            (
+                "decimals".to_owned(),
+                node(ASTNodeKind::Number("37".to_owned())),
+            ),
+            (
+                "name".to_owned(),
+                node(ASTNodeKind::Array(vec![string("coolium")])),
+            ),
+        ];
+        let diagnostics = schema
+            .validate("token@0.1.0", &params, Span::default(), &sources)
+            .unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Parameter `decimals` must be at most 36",
+                "Parameter `name` expects `[string; 2]`, found an array of 1 elements",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("decimal", "decimals"), 1);
+        assert_eq!(edit_distance("totl_suply", "total_supply"), 2);
+        assert_eq!(edit_distance("", "name"), 4);
+        assert_eq!(edit_distance("name", "name"), 0);
+    }
+}
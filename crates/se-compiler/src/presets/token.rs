@@ -1,2 +1,245 @@
-// Will contain code based on AST Node type. So state, constructor, program procedures etc. that will
-// be injected by the injector.
+use super::{const_declaration, Param, ParamSchema, Params, Preset, PresetFragments, Version};
+use crate::diagnostics::Diagnostic;
+use crate::parser::{ASTNodeKind, VariableType};
+use crate::source::{SourceMap, Span};
+
+pub const NAME: &str = "token";
+pub const VERSION: Version = Version::new(0, 1, 0);
+
+const SOURCE: &str = r#"
+$state {
+  table(address, u128) balances;
+  table(address, table(address, u128)) allowances;
+
+  // Tokens handed out so far, only distTokens can change it
+  u128 supply;
+}
+
+// Hands out new tokens, distribution beyond max_supply is ignored
+init distTokens(address to, u128 amount) {
+  if amount <= max_supply - this.state.supply {
+    this.state.balances[to] = this.state.balances[to] + amount;
+    this.state.supply = this.state.supply + amount;
+  }
+}
+
+$program {
+  pub u128 total_supply() {
+    return this.state.supply;
+  }
+
+  pub u128 balance_of(address owner) {
+    return this.state.balances[owner];
+  }
+
+  pub u128 allowance(address owner, address spender) {
+    return this.state.allowances[owner][spender];
+  }
+
+  pub mut bool transfer(address to, u128 amount) {
+    u128 balance = this.state.balances[this.caller];
+    if balance < amount {
+      return false;
+    }
+
+    this.state.balances[this.caller] = balance - amount;
+    this.state.balances[to] = this.state.balances[to] + amount;
+    return true;
+  }
+
+  pub mut bool approve(address spender, u128 amount) {
+    this.state.allowances[this.caller][spender] = amount;
+    return true;
+  }
+
+  pub mut bool transfer_from(address from, address to, u128 amount) {
+    u128 allowed = this.state.allowances[from][this.caller];
+    u128 balance = this.state.balances[from];
+    if allowed < amount || balance < amount {
+      return false;
+    }
+
+    this.state.allowances[from][this.caller] = allowed - amount;
+    this.state.balances[from] = balance - amount;
+    this.state.balances[to] = this.state.balances[to] + amount;
+    return true;
+  }
+}
+"#;
+
+pub fn preset() -> Preset {
+    let schema = ParamSchema::new(vec![
+        Param::required("decimals", VariableType::U8).max(36),
+        Param::required("total_supply", VariableType::U128),
+        Param::required("name", VariableType::Array(Box::new(VariableType::String))).len(2),
+    ]);
+    Preset::new(NAME, VERSION, schema, instantiate)
+}
+
+/// Instantiates the token preset for the validated params of a scheme:
+///
+/// ```text
+/// decimals = 12                   // u8 const `decimals`, at most 36
+/// total_supply = 10e12 * 5        // u128 const `max_supply`
+/// name = ["coolium", "COOL"]      // string consts `name` and `symbol`
+/// ```
+fn instantiate(
+    params: &Params,
+    _span: Span,
+    sources: &SourceMap,
+) -> Result<PresetFragments, Vec<Diagnostic>> {
+    let mut consts = Vec::new();
+    for (name, value) in params.iter() {
+        match (name, &value.kind) {
+            ("decimals", _) => consts.push(const_declaration("decimals", VariableType::U8, value)),
+            ("total_supply", _) => {
+                consts.push(const_declaration("max_supply", VariableType::U128, value))
+            }
+            ("name", ASTNodeKind::Array(names)) => {
+                consts.push(const_declaration("name", VariableType::String, &names[0]));
+                consts.push(const_declaration("symbol", VariableType::String, &names[1]));
+            }
+            _ => {}
+        }
+    }
+
+    let mut fragments = PresetFragments::parse(&format!("{}@{}", NAME, VERSION), SOURCE, sources)?;
+    fragments.consts = consts;
+    Ok(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::{ASTNode, Parser};
+    use crate::semantics;
+
+    /// Parses a contract and returns the params of its first scheme along
+    /// with the remaining top-level items.
+    fn parse_contract(sources: &SourceMap, input: &str) -> (ASTNode, Vec<ASTNode>) {
+        let lexer = Lexer::new(sources, sources.add("main.se", input), &[]);
+        let ASTNodeKind::Root(items) = Parser::new(lexer).parse().unwrap().kind else {
+            panic!("Expected a root node");
+        };
+
+        let mut scheme = None;
+        let mut rest = Vec::new();
+        for item in items {
+            match &item.kind {
+                ASTNodeKind::Define { schemes, .. } => scheme = Some(schemes[0].clone()),
+                _ => rest.push(item),
+            }
+        }
+        (scheme.expect("Expected a scheme"), rest)
+    }
+
+    #[test]
+    fn test_token_preset() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = {
+                decimals = 12
+                total_supply = 10e12 * 5
+                name = ["coolium", "COOL"]
+              }
+            }
+          ]
+        }
+        init() {
+          this.scheme.distTokens(this.caller, 10e12);
+        }
+        $program {
+          pub mut bool give(address to) {
+            return this.scheme.transfer(to, this.scheme.balance_of(this.caller));
+          }
+        }
+        "#;
+
+        let sources = SourceMap::new();
+        let (scheme, mut items) = parse_contract(&sources, input);
+        let ASTNodeKind::Scheme { params, .. } = &scheme.kind else {
+            panic!("Expected a scheme");
+        };
+        let fragments = preset().instantiate(params, scheme.span, &sources).unwrap();
+
+        let functions: Vec<&str> = fragments
+            .functions
+            .iter()
+            .filter_map(|function| match &function.kind {
+                ASTNodeKind::Function { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            functions,
+            vec![
+                "distTokens",
+                "total_supply",
+                "balance_of",
+                "allowance",
+                "transfer",
+                "approve",
+                "transfer_from"
+            ]
+        );
+
+        // Merge the fragments by hand and check the result as a whole
+        let span = scheme.span;
+        items.push(ASTNode::new(ASTNodeKind::State(fragments.state), span));
+        items.push(ASTNode::new(ASTNodeKind::Consts(fragments.consts), span));
+        items.push(ASTNode::new(
+            ASTNodeKind::Procedures(fragments.functions),
+            span,
+        ));
+        let root = ASTNode::new(ASTNodeKind::Root(items), span);
+
+        let (symbols, mut diagnostics) = semantics::analyze(&root, &sources);
+        diagnostics.extend(semantics::check_types(&root, &symbols, &sources).1);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        assert_eq!(symbols.constant("symbol"), Some(&VariableType::String));
+        assert!(symbols.function("distTokens").unwrap().constructor_only);
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let input = r#"
+        $define {
+          schemes = [
+            {
+              preset = "token@0.1.0"
+              params = {
+                decimals = 300
+                name = "coolium"
+                supply = 5
+              }
+            }
+          ]
+        }
+        "#;
+
+        let sources = SourceMap::new();
+        let (scheme, _) = parse_contract(&sources, input);
+        let ASTNodeKind::Scheme { params, .. } = &scheme.kind else {
+            panic!("Expected a scheme");
+        };
+
+        let diagnostics = preset()
+            .instantiate(params, scheme.span, &sources)
+            .unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Unknown parameter `supply` for preset `token@0.1.0`",
+                "Parameter `decimals` does not fit in `u8`: `300`",
+                "Missing parameter `total_supply` for preset `token@0.1.0`",
+                "Parameter `name` expects `[string; 2]`, found a string",
+            ]
+        );
+    }
+}
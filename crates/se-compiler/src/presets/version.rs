@@ -0,0 +1,192 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A semantic version such as `0.1.0`. Pre-release and build metadata are
+/// not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_parts(s)? {
+            (major, Some(minor), Some(patch)) => Ok(Version::new(major, minor, patch)),
+            _ => Err(format!(
+                "`{}` is not a version, expected `major.minor.patch`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Exact, // `0.1.0` or `=0.1.0`, missing parts match anything
+    Caret, // `^0.1.0`, compatible updates
+    Tilde, // `~0.1.0`, patch updates
+}
+
+/// A version requirement such as `^0.1.0`, `~1.2` or `0.1.0`. Caret and
+/// tilde requirements follow Cargo: `^0.1` accepts `0.1.x` and `^1.2`
+/// accepts everything below `2.0.0`. A bare version only matches itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+    op: Op,
+    major: u64,
+    minor: Option<u64>,
+    patch: Option<u64>,
+}
+
+impl VersionReq {
+    pub fn matches(&self, version: &Version) -> bool {
+        let minimum = Version::new(self.major, self.minor.unwrap_or(0), self.patch.unwrap_or(0));
+        if *version < minimum {
+            return false;
+        }
+
+        match (self.op, self.minor, self.patch) {
+            (Op::Exact, None, _) => version.major == self.major,
+            (Op::Exact, Some(minor), None) => version.major == self.major && version.minor == minor,
+            (Op::Exact, Some(_), Some(_)) => *version == minimum,
+            (Op::Tilde, None, _) => version.major == self.major,
+            (Op::Tilde, Some(minor), _) => version.major == self.major && version.minor == minor,
+            // The left-most non-zero part may not change
+            (Op::Caret, _, _) if self.major > 0 => version.major == self.major,
+            (Op::Caret, None, _) => version.major == 0,
+            (Op::Caret, Some(minor), _) if minor > 0 => {
+                version.major == 0 && version.minor == minor
+            }
+            (Op::Caret, Some(_), None) => version.major == 0 && version.minor == 0,
+            (Op::Caret, Some(_), Some(_)) => *version == minimum,
+        }
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (op, rest) = if let Some(rest) = s.strip_prefix('^') {
+            (Op::Caret, rest)
+        } else if let Some(rest) = s.strip_prefix('~') {
+            (Op::Tilde, rest)
+        } else if let Some(rest) = s.strip_prefix('=') {
+            (Op::Exact, rest)
+        } else {
+            (Op::Exact, s)
+        };
+
+        let (major, minor, patch) = parse_parts(rest.trim())?;
+        Ok(VersionReq {
+            op,
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.op {
+            Op::Exact => {}
+            Op::Caret => write!(f, "^")?,
+            Op::Tilde => write!(f, "~")?,
+        }
+        write!(f, "{}", self.major)?;
+        if let Some(minor) = self.minor {
+            write!(f, ".{}", minor)?;
+        }
+        if let Some(patch) = self.patch {
+            write!(f, ".{}", patch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `major[.minor[.patch]]`.
+fn parse_parts(s: &str) -> Result<(u64, Option<u64>, Option<u64>), String> {
+    let invalid = || format!("`{}` is not a valid version", s);
+
+    let mut parts = s.split('.').map(|part| {
+        // Leading '+' and the like are accepted by u64::from_str, so only
+        // plain digits are allowed here.
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        part.parse::<u64>().map_err(|_| invalid())
+    });
+
+    let major = parts.next().ok_or_else(invalid)??;
+    let minor = parts.next().transpose()?;
+    let patch = parts.next().transpose()?;
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+
+    Ok((major, minor, patch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(requirement: &str, version: &str) -> bool {
+        let requirement: VersionReq = requirement.parse().unwrap();
+        requirement.matches(&version.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("0.1.0".parse(), Ok(Version::new(0, 1, 0)));
+        assert!("0.1".parse::<Version>().is_err());
+        assert!("0.1.0-beta".parse::<Version>().is_err());
+        assert!("^0.1.+2".parse::<VersionReq>().is_err());
+        assert!("1.2.3.4".parse::<VersionReq>().is_err());
+        assert_eq!("^0.1".parse::<VersionReq>().unwrap().to_string(), "^0.1");
+    }
+
+    #[test]
+    fn test_requirements() {
+        assert!(matches("^0.1.0", "0.1.0"));
+        assert!(matches("^0.1.0", "0.1.7"));
+        assert!(!matches("^0.1.0", "0.2.0"));
+        assert!(matches("^0.1", "0.1.3"));
+        assert!(matches("^1.2", "1.9.0"));
+        assert!(!matches("^1.2", "1.1.0"));
+        assert!(!matches("^1.2", "2.0.0"));
+        assert!(matches("^0.0.3", "0.0.3"));
+        assert!(!matches("^0.0.3", "0.0.4"));
+        assert!(matches("^0", "0.9.9"));
+
+        assert!(matches("~1.2.3", "1.2.9"));
+        assert!(!matches("~1.2.3", "1.3.0"));
+        assert!(matches("~1", "1.5.0"));
+
+        assert!(matches("0.1.0", "0.1.0"));
+        assert!(!matches("0.1.0", "0.1.1"));
+        assert!(matches("=0.1", "0.1.4"));
+    }
+}
@@ -1 +1,1951 @@
+use std::collections::{HashMap, HashSet};
 
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::parser::{ASTNode, ASTNodeKind, BinaryOp, NodeId, UnaryOp, VariableType};
+use crate::source::{SourceMap, Span};
+
+/// What a caller needs to know about a procedure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSignature {
+    pub public: bool,
+    pub mutates: bool,
+    pub constructor_only: bool,
+    pub guard: bool,
+    pub params: Vec<VariableType>,
+    pub return_type: Option<VariableType>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolKind {
+    State {
+        var_type: VariableType,
+        mutable: bool, // Whether it may be assigned outside of the constructor
+    },
+    Const(VariableType),
+    Function(FunctionSignature),
+    Param(VariableType),
+    Local(VariableType),
+}
+
+impl SymbolKind {
+    /// Human readable name of the kind of symbol, used in diagnostics.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SymbolKind::State { .. } => "state variable",
+            SymbolKind::Const(_) => "constant",
+            SymbolKind::Function(_) => "function",
+            SymbolKind::Param(_) => "parameter",
+            SymbolKind::Local(_) => "local variable",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub span: Span,
+}
+
+/// The items declared at the top level of a contract, including the ones
+/// injected by presets. Consts and functions share one namespace, state
+/// variables live in their own as they are only reachable through
+/// `this.state`, but may not collide with consts either.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>, // In declaration order, which is also the state layout
+    state: HashMap<String, usize>,
+    items: HashMap<String, usize>,
+}
+
+impl SymbolTable {
+    /// Adds a symbol, or returns the symbol that already uses its name.
+    fn declare(&mut self, symbol: Symbol) -> Result<(), &Symbol> {
+        let is_const = |index: &&usize| matches!(self.symbols[**index].kind, SymbolKind::Const(_));
+        let is_state = matches!(symbol.kind, SymbolKind::State { .. });
+
+        let existing = if is_state {
+            self.state
+                .get(&symbol.name)
+                .or_else(|| self.items.get(&symbol.name).filter(is_const))
+        } else if let SymbolKind::Const(_) = symbol.kind {
+            self.items
+                .get(&symbol.name)
+                .or_else(|| self.state.get(&symbol.name))
+        } else {
+            self.items.get(&symbol.name)
+        };
+        if let Some(&index) = existing {
+            return Err(&self.symbols[index]);
+        }
+
+        let namespace = if is_state {
+            &mut self.state
+        } else {
+            &mut self.items
+        };
+        namespace.insert(symbol.name.clone(), self.symbols.len());
+        self.symbols.push(symbol);
+        Ok(())
+    }
+
+    /// Looks up a const or function, falling back to a state variable.
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.items
+            .get(name)
+            .or_else(|| self.state.get(name))
+            .map(|&index| &self.symbols[index])
+    }
+
+    pub fn get_state(&self, name: &str) -> Option<&Symbol> {
+        self.state.get(name).map(|&index| &self.symbols[index])
+    }
+
+    pub fn state(&self, name: &str) -> Option<&VariableType> {
+        match &self.get_state(name)?.kind {
+            SymbolKind::State { var_type, .. } => Some(var_type),
+            _ => None,
+        }
+    }
+
+    pub fn constant(&self, name: &str) -> Option<&VariableType> {
+        match &self.get(name)?.kind {
+            SymbolKind::Const(var_type) => Some(var_type),
+            _ => None,
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionSignature> {
+        match &self.get(name)?.kind {
+            SymbolKind::Function(signature) => Some(signature),
+            _ => None,
+        }
+    }
+
+    /// State variables in declaration order.
+    pub fn state_variables(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols
+            .iter()
+            .filter(|symbol| matches!(symbol.kind, SymbolKind::State { .. }))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+}
+
+/// Builds the symbol table for a parsed contract and resolves every name used
+/// in const initialisers and procedure bodies. Undefined names, duplicate
+/// declarations and consts that depend on themselves are reported as errors,
+/// shadowing as warnings. State writes and calls are also checked against the
+/// `mut` and constructor-only rules.
+pub fn analyze(root: &ASTNode, sources: &SourceMap) -> (SymbolTable, Vec<Diagnostic>) {
+    let mut analyzer = Analyzer {
+        sources,
+        symbols: SymbolTable::default(),
+        scopes: Vec::new(),
+        function: None,
+        diagnostics: Vec::new(),
+    };
+
+    let items = match &root.kind {
+        ASTNodeKind::Root(items) => items.as_slice(),
+        _ => std::slice::from_ref(root),
+    };
+
+    // Declare everything first so procedures can call each other in any order
+    for item in items {
+        analyzer.declare_item(item);
+    }
+    for item in items {
+        analyzer.resolve_item(item);
+    }
+    analyzer.check_const_cycles(items);
+
+    let mut diagnostics = analyzer.diagnostics;
+    diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+    (analyzer.symbols, diagnostics)
+}
+
+struct Analyzer<'a> {
+    sources: &'a SourceMap,
+    symbols: SymbolTable,
+    scopes: Vec<HashMap<String, Symbol>>, // Innermost scope last
+    function: Option<FunctionContext>,    // The function being resolved
+    diagnostics: Vec<Diagnostic>,
+}
+
+struct FunctionContext {
+    name: String,
+    mutates: bool,
+    constructor_only: bool,
+}
+
+impl Analyzer<'_> {
+    fn report(&mut self, severity: Severity, span: Span, message: impl Into<String>) {
+        let diagnostic = Diagnostic::new(severity, message, span, self.sources);
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn report_with_note(
+        &mut self,
+        severity: Severity,
+        span: Span,
+        message: impl Into<String>,
+        note: impl Into<String>,
+    ) {
+        let diagnostic = Diagnostic::new(severity, message, span, self.sources).with_note(note);
+        self.diagnostics.push(diagnostic);
+    }
+
+    // ============ Declarations ============
+
+    fn declare_item(&mut self, item: &ASTNode) {
+        match &item.kind {
+            ASTNodeKind::State(declarations)
+            | ASTNodeKind::Consts(declarations)
+            | ASTNodeKind::Procedures(declarations) => {
+                for declaration in declarations {
+                    self.declare_item(declaration);
+                }
+            }
+            ASTNodeKind::StateVariableDeclaration {
+                name,
+                var_type,
+                mutable,
+            } => {
+                let kind = SymbolKind::State {
+                    var_type: var_type.clone(),
+                    mutable: *mutable,
+                };
+                self.declare_global(name, kind, item.span);
+            }
+            ASTNodeKind::ConstDeclaration { name, var_type, .. } => {
+                self.declare_global(name, SymbolKind::Const(var_type.clone()), item.span);
+            }
+            ASTNodeKind::Function {
+                name,
+                public,
+                mutates,
+                constructor_only,
+                params,
+                return_type,
+                ..
+            } => {
+                let signature = FunctionSignature {
+                    public: *public,
+                    mutates: *mutates,
+                    constructor_only: *constructor_only,
+                    guard: is_guard(name),
+                    params: params
+                        .iter()
+                        .filter_map(ASTNode::as_param)
+                        .map(|(_, var_type)| var_type.clone())
+                        .collect(),
+                    return_type: return_type.clone(),
+                };
+                self.declare_global(name, SymbolKind::Function(signature), item.span);
+            }
+            _ => {}
+        }
+    }
+
+    fn declare_global(&mut self, name: &str, kind: SymbolKind, span: Span) {
+        let symbol = Symbol {
+            name: name.to_owned(),
+            kind,
+            span,
+        };
+
+        if let Err(existing) = self.symbols.declare(symbol) {
+            let note = format!(
+                "`{}` was first declared as a {} at {}",
+                name,
+                existing.kind.describe(),
+                self.sources.location(existing.span)
+            );
+            self.report_with_note(
+                Severity::Error,
+                span,
+                format!("Duplicate declaration of `{}`", name),
+                note,
+            );
+        }
+    }
+
+    fn declare_local(&mut self, name: &str, kind: SymbolKind, span: Span) {
+        let (current, outer) = self
+            .scopes
+            .split_last()
+            .expect("locals are only declared inside a scope");
+
+        if let Some(existing) = current.get(name) {
+            let note = format!(
+                "`{}` was first declared as a {} at {}",
+                name,
+                existing.kind.describe(),
+                self.sources.location(existing.span)
+            );
+            self.report_with_note(
+                Severity::Error,
+                span,
+                format!("Duplicate declaration of `{}`", name),
+                note,
+            );
+            return;
+        }
+
+        let shadowed = outer
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .or_else(|| {
+                self.symbols
+                    .get(name)
+                    .filter(|symbol| !matches!(symbol.kind, SymbolKind::State { .. }))
+            });
+        if let Some(shadowed) = shadowed {
+            let message = format!(
+                "`{}` shadows the {} declared at {}",
+                name,
+                shadowed.kind.describe(),
+                self.sources.location(shadowed.span)
+            );
+            self.report(Severity::Warning, span, message);
+        }
+
+        let symbol = Symbol {
+            name: name.to_owned(),
+            kind,
+            span,
+        };
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_owned(), symbol);
+    }
+
+    fn lookup_local(&self, name: &str) -> Option<&Symbol> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    // ============ Resolution ============
+
+    fn resolve_item(&mut self, item: &ASTNode) {
+        match &item.kind {
+            ASTNodeKind::Consts(declarations) | ASTNodeKind::Procedures(declarations) => {
+                for declaration in declarations {
+                    self.resolve_item(declaration);
+                }
+            }
+            ASTNodeKind::Preset(_) => self.report_with_note(
+                Severity::Error,
+                item.span,
+                "`$preset` blocks are only allowed in preset files",
+                "preset files are found through the preset search path",
+            ),
+            ASTNodeKind::ConstDeclaration { value, .. } => {
+                // Consts are evaluated at compile time, so `this` is not available
+                if let Some(span) = find_this(value) {
+                    self.report(
+                        Severity::Error,
+                        span,
+                        "`this` cannot be used in a constant initialiser",
+                    );
+                } else {
+                    self.resolve_expression(value);
+                }
+            }
+            ASTNodeKind::Function {
+                name,
+                mutates,
+                constructor_only,
+                params,
+                return_type,
+                body,
+                ..
+            } => {
+                if is_guard(name) {
+                    self.check_guard(name, *mutates, return_type.as_ref(), item.span);
+                }
+
+                self.function = Some(FunctionContext {
+                    name: name.clone(),
+                    mutates: *mutates,
+                    constructor_only: *constructor_only,
+                });
+                self.scopes.push(HashMap::new());
+                for param in params {
+                    let ASTNodeKind::Param { name, var_type } = &param.kind else {
+                        continue;
+                    };
+                    self.declare_local(name, SymbolKind::Param(var_type.clone()), param.span);
+                }
+                self.resolve_statements(body);
+                self.scopes.pop();
+            }
+            _ => {}
+        }
+    }
+
+    fn resolve_block(&mut self, statements: &[ASTNode]) {
+        self.scopes.push(HashMap::new());
+        self.resolve_statements(statements);
+        self.scopes.pop();
+    }
+
+    fn resolve_statements(&mut self, statements: &[ASTNode]) {
+        for statement in statements {
+            self.resolve_statement(statement);
+        }
+    }
+
+    fn resolve_statement(&mut self, statement: &ASTNode) {
+        match &statement.kind {
+            ASTNodeKind::LocalVariableDeclaration {
+                name,
+                var_type,
+                value,
+            } => {
+                // The initialiser is resolved first, it cannot refer to the new local
+                self.resolve_expression(value);
+                self.declare_local(name, SymbolKind::Local(var_type.clone()), statement.span);
+            }
+            ASTNodeKind::Assignment { target, value } => {
+                self.resolve_expression(target);
+                self.resolve_expression(value);
+                self.check_state_write(target);
+            }
+            ASTNodeKind::Return(value) => {
+                if let Some(value) = value {
+                    self.resolve_expression(value);
+                }
+            }
+            ASTNodeKind::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                self.resolve_expression(condition);
+                self.resolve_block(body);
+                self.resolve_block(else_body);
+            }
+            ASTNodeKind::While { condition, body } => {
+                self.resolve_expression(condition);
+                self.resolve_block(body);
+            }
+            ASTNodeKind::Call { callee, .. } => {
+                self.resolve_expression(statement);
+                self.check_unchecked_guard(callee, statement.span);
+            }
+            _ => self.resolve_expression(statement),
+        }
+    }
+
+    fn resolve_expression(&mut self, expression: &ASTNode) {
+        match &expression.kind {
+            ASTNodeKind::Identifier(name) => self.resolve_identifier(name, expression.span),
+            ASTNodeKind::Placeholder(name) => self.report(
+                Severity::Error,
+                expression.span,
+                format!("Placeholder `${}` can only be used in preset files", name),
+            ),
+            ASTNodeKind::Member { object, property } => {
+                self.resolve_member(object, property, expression.span)
+            }
+            ASTNodeKind::Call { callee, args } => {
+                match &callee.kind {
+                    ASTNodeKind::Identifier(name) => self.resolve_callee(name, callee.span),
+                    _ => self.resolve_expression(callee),
+                }
+                self.check_call(callee, expression.span);
+                for arg in args {
+                    self.resolve_expression(arg);
+                }
+            }
+            ASTNodeKind::Binary { left, right, .. } => {
+                self.resolve_expression(left);
+                self.resolve_expression(right);
+            }
+            ASTNodeKind::Unary { operand, .. } | ASTNodeKind::Cast { value: operand, .. } => {
+                self.resolve_expression(operand)
+            }
+            ASTNodeKind::Index { object, index } => {
+                self.resolve_expression(object);
+                self.resolve_expression(index);
+            }
+            ASTNodeKind::Array(elements) => {
+                for element in elements {
+                    self.resolve_expression(element);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn resolve_identifier(&mut self, name: &str, span: Span) {
+        if name == "this" {
+            self.report_with_note(
+                Severity::Error,
+                span,
+                "`this` cannot be used as a value",
+                "use one of its members such as `this.caller` or `this.state`",
+            );
+            return;
+        }
+
+        if self.lookup_local(name).is_some() {
+            return;
+        }
+
+        match self.symbols.get(name).map(|symbol| &symbol.kind) {
+            Some(SymbolKind::Const(_)) => {}
+            Some(SymbolKind::State { .. }) => self.report_with_note(
+                Severity::Error,
+                span,
+                format!("Cannot find `{}` in this scope", name),
+                format!("state variables are accessed through `this.state.{}`", name),
+            ),
+            Some(SymbolKind::Function(_)) => self.report(
+                Severity::Error,
+                span,
+                format!("Function `{}` can only be called", name),
+            ),
+            _ => self.report(
+                Severity::Error,
+                span,
+                format!("Cannot find `{}` in this scope", name),
+            ),
+        }
+    }
+
+    fn resolve_callee(&mut self, name: &str, span: Span) {
+        if let Some(local) = self.lookup_local(name) {
+            let message = format!("`{}` is a {}, not a function", name, local.kind.describe());
+            self.report(Severity::Error, span, message);
+            return;
+        }
+
+        match self.symbols.get(name).map(|symbol| &symbol.kind) {
+            Some(SymbolKind::Function(_)) => {}
+            Some(kind) => {
+                let message = format!("`{}` is a {}, not a function", name, kind.describe());
+                self.report(Severity::Error, span, message);
+            }
+            None => self.report(
+                Severity::Error,
+                span,
+                format!("Cannot find function `{}` in this scope", name),
+            ),
+        }
+    }
+
+    /// Resolves the members of `this`: `this.caller`, `this.block`,
+    /// `this.state.<name>` and `this.scheme.<name>`. The injector rewrites
+    /// `this.schemes.<alias>` members for every scheme it knows of, so any
+    /// that are left name a scheme that does not exist. Members of other
+    /// values are left to the type checker.
+    fn resolve_member(&mut self, object: &ASTNode, property: &str, span: Span) {
+        if is_this(object) {
+            match property {
+                "caller" | "block" => {}
+                "state" | "scheme" | "schemes" => self.report(
+                    Severity::Error,
+                    span,
+                    format!("`this.{}` cannot be used as a value", property),
+                ),
+                _ => self.report_with_note(
+                    Severity::Error,
+                    span,
+                    format!("Unknown member `this.{}`", property),
+                    "available members are `this.caller`, `this.block`, `this.state`, `this.scheme` and `this.schemes`",
+                ),
+            }
+            return;
+        }
+
+        match this_member(object) {
+            Some("state") => {
+                if self.symbols.state(property).is_none() {
+                    self.report(
+                        Severity::Error,
+                        span,
+                        format!("No state variable named `{}`", property),
+                    );
+                }
+            }
+            Some("scheme") => {
+                // Preset procedures are injected into the contract, so they
+                // live in the same namespace as the user's functions.
+                if self.symbols.function(property).is_none() {
+                    self.report(
+                        Severity::Error,
+                        span,
+                        format!("No scheme provides `{}`", property),
+                    );
+                }
+            }
+            Some("schemes") => self.report(
+                Severity::Error,
+                span,
+                format!("No scheme named `{}`", property),
+            ),
+            _ => self.resolve_expression(object),
+        }
+    }
+
+    // ============ Const cycles ============
+
+    /// Reports consts whose initialisers refer back to themselves, directly
+    /// or through other consts. Consts are inlined where they are used, so
+    /// such a const has no value.
+    fn check_const_cycles(&mut self, items: &[ASTNode]) {
+        let declarations: Vec<&ASTNode> = items
+            .iter()
+            .flat_map(|item| match &item.kind {
+                ASTNodeKind::Consts(declarations) => declarations.as_slice(),
+                _ => std::slice::from_ref(item),
+            })
+            .collect();
+
+        let mut consts = HashMap::new();
+        for declaration in &declarations {
+            if let ASTNodeKind::ConstDeclaration { name, value, .. } = &declaration.kind {
+                consts
+                    .entry(name.as_str())
+                    .or_insert((&**value, declaration.span));
+            }
+        }
+
+        let mut done = HashSet::new();
+        for declaration in declarations {
+            if let ASTNodeKind::ConstDeclaration { name, .. } = &declaration.kind {
+                self.visit_const(name, &consts, &mut Vec::new(), &mut done);
+            }
+        }
+    }
+
+    /// Walks the consts `name` refers to depth first. `path` holds the consts
+    /// being visited, a name already on it closes a cycle.
+    fn visit_const<'n>(
+        &mut self,
+        name: &'n str,
+        consts: &HashMap<&'n str, (&'n ASTNode, Span)>,
+        path: &mut Vec<&'n str>,
+        done: &mut HashSet<&'n str>,
+    ) {
+        if done.contains(name) {
+            return;
+        }
+        let Some(&(value, _)) = consts.get(name) else {
+            return;
+        };
+        if let Some(start) = path.iter().position(|&visited| visited == name) {
+            let cycle: Vec<String> = path[start..]
+                .iter()
+                .chain([&name])
+                .map(|name| format!("`{}`", name))
+                .collect();
+            self.report_with_note(
+                Severity::Error,
+                consts[name].1,
+                format!("Constant `{}` depends on itself", name),
+                format!("the cycle is {}", cycle.join(" -> ")),
+            );
+            return;
+        }
+
+        path.push(name);
+        let mut names = Vec::new();
+        referenced_names(value, &mut names);
+        for referenced in names {
+            self.visit_const(referenced, consts, path, done);
+        }
+        path.pop();
+        done.insert(name);
+    }
+
+    // ============ Mutability rules ============
+
+    /// Checks an assignment against the mutability rules: state may only be
+    /// written from `mut` functions, and state that is not declared `mut` may
+    /// only be assigned while the constructor runs. Table entries are exempt
+    /// from the latter, as tables are filled in by procedures.
+    fn check_state_write(&mut self, target: &ASTNode) {
+        let Some(name) = state_root(target) else {
+            return;
+        };
+        let Some(function) = &self.function else {
+            return;
+        };
+        let Some(SymbolKind::State { var_type, mutable }) =
+            self.symbols.get_state(name).map(|symbol| &symbol.kind)
+        else {
+            return;
+        };
+
+        // Table entries are written at runtime, whatever the declaration says
+        let is_entry = matches!(target.kind, ASTNodeKind::Index { .. })
+            && matches!(var_type, VariableType::Table(..));
+
+        if !function.mutates {
+            let message = format!(
+                "Cannot modify state in `{}`, which is not marked `mut`",
+                function.name
+            );
+            let note = format!(
+                "declare it as `mut {}(...)` to allow state changes",
+                function.name
+            );
+            self.report_with_note(Severity::Error, target.span, message, note);
+        } else if !mutable && !is_entry && !function.constructor_only {
+            let message = format!(
+                "Cannot assign to immutable state `{}` outside of `init()`",
+                name
+            );
+            let note = format!(
+                "declare it as `mut {}` to allow assignments from `mut` procedures",
+                name
+            );
+            self.report_with_note(Severity::Error, target.span, message, note);
+        }
+    }
+
+    // ============ Guards ============
+
+    /// Guards are `bool` procedures named `require_*` that tell whether the
+    /// caller may go on, such as `require_owner()`. They only look at state,
+    /// so they are never `mut`.
+    fn check_guard(
+        &mut self,
+        name: &str,
+        mutates: bool,
+        return_type: Option<&VariableType>,
+        span: Span,
+    ) {
+        if mutates {
+            let message = format!("Guard `{}` cannot be marked `mut`", name);
+            let note = "guards only check state, change it in the procedure calling them";
+            self.report_with_note(Severity::Error, span, message, note);
+        }
+        if return_type != Some(&VariableType::Bool) {
+            let message = format!("Guard `{}` must return `bool`", name);
+            let note = "procedures named `require_*` are guards, their result tells whether the caller may continue";
+            self.report_with_note(Severity::Error, span, message, note);
+        }
+    }
+
+    /// Reports guard calls used as statements. A guard does not stop the
+    /// procedure calling it, so ignoring its result skips the check entirely.
+    fn check_unchecked_guard(&mut self, callee: &ASTNode, span: Span) {
+        let name = match &callee.kind {
+            ASTNodeKind::Identifier(name) if self.lookup_local(name).is_none() => name.as_str(),
+            ASTNodeKind::Member { object, property } if this_member(object) == Some("scheme") => {
+                property.as_str()
+            }
+            _ => return,
+        };
+        if !self
+            .symbols
+            .function(name)
+            .is_some_and(|signature| signature.guard)
+        {
+            return;
+        }
+
+        // The callee as written, which may differ from its qualified name
+        let written = self
+            .sources
+            .get(callee.span.file)
+            .and_then(|source| source.content.get(callee.span.start..callee.span.end))
+            .unwrap_or(name);
+        let message = format!("The result of guard `{}` is not checked", name);
+        let note = format!(
+            "guards do not stop the procedure, use `if !{}(...) {{ return ...; }}`",
+            written
+        );
+        self.report_with_note(Severity::Error, span, message, note);
+    }
+
+    /// Checks that a call respects the caller's restrictions: constructor-only
+    /// functions can only be called while the constructor runs, and functions
+    /// that are not `mut` cannot change state through a `mut` callee.
+    fn check_call(&mut self, callee: &ASTNode, span: Span) {
+        let name = match &callee.kind {
+            ASTNodeKind::Identifier(name) if self.lookup_local(name).is_none() => name.as_str(),
+            ASTNodeKind::Member { object, property } if this_member(object) == Some("scheme") => {
+                property.as_str()
+            }
+            _ => return,
+        };
+        let (Some(function), Some(signature)) = (&self.function, self.symbols.function(name))
+        else {
+            return;
+        };
+
+        if signature.constructor_only && !function.constructor_only {
+            let message = format!("`{}` can only be called from `init()`", name);
+            let note = format!("`{}` is only available while the contract is created", name);
+            self.report_with_note(Severity::Error, span, message, note);
+        } else if signature.mutates && !function.mutates {
+            let message = format!(
+                "Cannot call `mut` function `{}` from `{}`, which is not marked `mut`",
+                name, function.name
+            );
+            self.report(Severity::Error, span, message);
+        }
+    }
+}
+
+/// Returns the state variable an assignment target writes to, for targets
+/// such as `this.state.name` and `this.state.name[key]`.
+fn state_root(node: &ASTNode) -> Option<&str> {
+    match &node.kind {
+        ASTNodeKind::Member { object, property } if this_member(object) == Some("state") => {
+            Some(property)
+        }
+        ASTNodeKind::Index { object, .. } => state_root(object),
+        _ => None,
+    }
+}
+
+/// Whether a procedure is a guard, by the last segment of its name so that
+/// guards injected by schemes count as well.
+fn is_guard(name: &str) -> bool {
+    name.rsplit('.')
+        .next()
+        .is_some_and(|name| name.starts_with("require_"))
+}
+
+fn is_this(node: &ASTNode) -> bool {
+    matches!(&node.kind, ASTNodeKind::Identifier(name) if name == "this")
+}
+
+/// Returns `property` when the node is `this.<property>`.
+pub(crate) fn this_member(node: &ASTNode) -> Option<&str> {
+    match &node.kind {
+        ASTNodeKind::Member { object, property } if is_this(object) => Some(property),
+        _ => None,
+    }
+}
+
+/// Collects the names an expression refers to, in source order.
+fn referenced_names<'n>(node: &'n ASTNode, names: &mut Vec<&'n str>) {
+    match &node.kind {
+        ASTNodeKind::Identifier(name) => names.push(name),
+        ASTNodeKind::Binary { left, right, .. } => {
+            referenced_names(left, names);
+            referenced_names(right, names);
+        }
+        ASTNodeKind::Unary { operand, .. } | ASTNodeKind::Cast { value: operand, .. } => {
+            referenced_names(operand, names)
+        }
+        ASTNodeKind::Member { object, .. } => referenced_names(object, names),
+        ASTNodeKind::Index { object, index } => {
+            referenced_names(object, names);
+            referenced_names(index, names);
+        }
+        ASTNodeKind::Call { callee, args } => {
+            referenced_names(callee, names);
+            for arg in args {
+                referenced_names(arg, names);
+            }
+        }
+        ASTNodeKind::Array(elements) => {
+            for element in elements {
+                referenced_names(element, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the span of the first use of `this` within an expression.
+fn find_this(node: &ASTNode) -> Option<Span> {
+    match &node.kind {
+        ASTNodeKind::Identifier(name) if name == "this" => Some(node.span),
+        ASTNodeKind::Binary { left, right, .. } => find_this(left).or_else(|| find_this(right)),
+        ASTNodeKind::Unary { operand, .. } | ASTNodeKind::Cast { value: operand, .. } => {
+            find_this(operand)
+        }
+        ASTNodeKind::Member { object, .. } => find_this(object),
+        ASTNodeKind::Index { object, index } => find_this(object).or_else(|| find_this(index)),
+        ASTNodeKind::Call { callee, args } => {
+            find_this(callee).or_else(|| args.iter().find_map(find_this))
+        }
+        ASTNodeKind::Array(elements) => elements.iter().find_map(find_this),
+        _ => None,
+    }
+}
+
+/// The type of an expression as seen by the type checker.
+#[derive(Debug, Clone, PartialEq)]
+enum Ty {
+    Value(VariableType),
+    Void,    // Result of calling a procedure without a return type
+    Unknown, // Already reported, e.g. an unresolved name
+}
+
+/// Infers the type of every expression and checks it against declared types:
+/// const initialisers, local declarations, assignments, arguments, returns
+/// and conditions. Integer literals take the type they are used as, but `u8`
+/// and `u128` values are never mixed without an explicit `as` cast.
+///
+/// Names that failed to resolve are ignored, `analyze` already reported them.
+///
+/// Returns the type every integer literal took, by the id of the literal in
+/// `root`, which is what the generator loads it as.
+pub fn check_types(
+    root: &ASTNode,
+    symbols: &SymbolTable,
+    sources: &SourceMap,
+) -> (HashMap<NodeId, VariableType>, Vec<Diagnostic>) {
+    let mut checker = TypeChecker {
+        sources,
+        symbols,
+        scopes: Vec::new(),
+        return_type: None,
+        literals: HashMap::new(),
+        diagnostics: Vec::new(),
+    };
+
+    let items = match &root.kind {
+        ASTNodeKind::Root(items) => items.as_slice(),
+        _ => std::slice::from_ref(root),
+    };
+    for item in items {
+        checker.check_item(item);
+    }
+
+    let mut diagnostics = checker.diagnostics;
+    diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+    (checker.literals, diagnostics)
+}
+
+struct TypeChecker<'a> {
+    sources: &'a SourceMap,
+    symbols: &'a SymbolTable,
+    scopes: Vec<HashMap<String, VariableType>>,
+    return_type: Option<VariableType>, // Of the function being checked
+    literals: HashMap<NodeId, VariableType>, // Type of every integer literal
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl TypeChecker<'_> {
+    fn error(&mut self, span: Span, message: impl Into<String>) -> &mut Diagnostic {
+        let diagnostic = Diagnostic::new(Severity::Error, message, span, self.sources);
+        self.diagnostics.push(diagnostic);
+        self.diagnostics.last_mut().unwrap()
+    }
+
+    fn declare_local(&mut self, name: &str, var_type: &VariableType) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_owned(), var_type.clone());
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Ty {
+        let local = self.scopes.iter().rev().find_map(|scope| scope.get(name));
+        match local.or_else(|| self.symbols.constant(name)) {
+            Some(var_type) => Ty::Value(var_type.clone()),
+            None => Ty::Unknown,
+        }
+    }
+
+    // ============ Items and statements ============
+
+    fn check_item(&mut self, item: &ASTNode) {
+        match &item.kind {
+            ASTNodeKind::Consts(declarations) | ASTNodeKind::Procedures(declarations) => {
+                for declaration in declarations {
+                    self.check_item(declaration);
+                }
+            }
+            ASTNodeKind::State(declarations) => {
+                for declaration in declarations {
+                    if let ASTNodeKind::StateVariableDeclaration { var_type, .. } =
+                        &declaration.kind
+                    {
+                        self.check_declared_type(var_type, declaration.span, true);
+                    }
+                }
+            }
+            ASTNodeKind::ConstDeclaration {
+                var_type, value, ..
+            } => {
+                self.check_declared_type(var_type, item.span, false);
+                self.check_expression(value, var_type);
+            }
+            ASTNodeKind::Function {
+                name,
+                params,
+                return_type,
+                body,
+                ..
+            } => {
+                self.scopes.push(HashMap::new());
+                for param in params {
+                    let ASTNodeKind::Param { name, var_type } = &param.kind else {
+                        continue;
+                    };
+                    self.check_declared_type(var_type, param.span, false);
+                    self.declare_local(name, var_type);
+                }
+                if let Some(return_type) = return_type {
+                    self.check_declared_type(return_type, item.span, false);
+                }
+
+                self.return_type = return_type.clone();
+                self.check_statements(body);
+                self.scopes.pop();
+
+                if let Some(return_type) = return_type {
+                    if !always_returns(body) {
+                        self.error(
+                            item.span,
+                            format!("Function `{}` may end without returning a value", name),
+                        )
+                        .notes
+                        .push(format!(
+                            "`{}` is declared to return `{}`",
+                            name, return_type
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn check_block(&mut self, statements: &[ASTNode]) {
+        self.scopes.push(HashMap::new());
+        self.check_statements(statements);
+        self.scopes.pop();
+    }
+
+    fn check_statements(&mut self, statements: &[ASTNode]) {
+        for statement in statements {
+            self.check_statement(statement);
+        }
+    }
+
+    fn check_statement(&mut self, statement: &ASTNode) {
+        match &statement.kind {
+            ASTNodeKind::LocalVariableDeclaration {
+                name,
+                var_type,
+                value,
+            } => {
+                self.check_declared_type(var_type, statement.span, false);
+                self.check_expression(value, var_type);
+                self.declare_local(name, var_type);
+            }
+            ASTNodeKind::Assignment { target, value } => match self.infer(target) {
+                Ty::Value(VariableType::Table(..)) => {
+                    self.infer(value);
+                    self.error(target.span, "Tables cannot be assigned to as a whole")
+                        .notes
+                        .push("assign individual entries such as `table[key] = value`".to_owned());
+                }
+                Ty::Value(target_type) => self.check_expression(value, &target_type),
+                _ => {
+                    self.infer(value);
+                }
+            },
+            ASTNodeKind::Return(value) => self.check_return(value.as_deref(), statement.span),
+            ASTNodeKind::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                self.check_expression(condition, &VariableType::Bool);
+                self.check_block(body);
+                self.check_block(else_body);
+            }
+            ASTNodeKind::While { condition, body } => {
+                self.check_expression(condition, &VariableType::Bool);
+                self.check_block(body);
+            }
+            _ => {
+                self.infer(statement);
+            }
+        }
+    }
+
+    /// Reports tables outside of state and tables with unsupported keys.
+    /// Tables live in contract storage, so they cannot be passed around.
+    fn check_declared_type(&mut self, var_type: &VariableType, span: Span, allow_table: bool) {
+        match var_type {
+            VariableType::Table(key, value) => {
+                if !allow_table {
+                    self.error(span, "Tables can only be declared in `$state`");
+                    return;
+                }
+                if !key.is_table_key() {
+                    self.error(span, format!("`{}` cannot be used as a table key", key))
+                        .notes
+                        .push("table keys must be `address`, `u128`, `u8` or `bool`".to_owned());
+                }
+                self.check_declared_type(value, span, true);
+            }
+            VariableType::Array(element) => self.check_declared_type(element, span, false),
+            _ => {}
+        }
+    }
+
+    fn check_return(&mut self, value: Option<&ASTNode>, span: Span) {
+        match (self.return_type.clone(), value) {
+            (Some(return_type), Some(value)) => self.check_expression(value, &return_type),
+            (Some(return_type), None) => {
+                self.error(span, format!("Expected a `{}` return value", return_type));
+            }
+            (None, Some(value)) => {
+                self.infer(value);
+                self.error(value.span, "This function does not return a value")
+                    .notes
+                    .push("declare a return type such as `pub u128 name()`".to_owned());
+            }
+            (None, None) => {}
+        }
+    }
+
+    // ============ Expressions ============
+
+    /// Checks that an expression can be used where `expected` is required.
+    fn check_expression(&mut self, expression: &ASTNode, expected: &VariableType) {
+        let found = self.infer_with_hint(expression, &Ty::Value(expected.clone()));
+        self.expect_type(expression, &found, expected);
+    }
+
+    fn expect_type(&mut self, expression: &ASTNode, found: &Ty, expected: &VariableType) {
+        match found {
+            Ty::Value(found) if found != expected => {
+                let diagnostic = self.error(
+                    expression.span,
+                    format!(
+                        "Mismatched types: expected `{}`, found `{}`",
+                        expected, found
+                    ),
+                );
+                if found.is_integer() && expected.is_integer() {
+                    diagnostic.notes.push(format!(
+                        "use an explicit cast such as `value as {}`",
+                        expected
+                    ));
+                }
+            }
+            Ty::Void => {
+                self.error(
+                    expression.span,
+                    "Expected a value, but this call returns nothing",
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Infers the type of an expression, letting literals take the type of
+    /// `hint` when they can.
+    fn infer_with_hint(&mut self, expression: &ASTNode, hint: &Ty) -> Ty {
+        match (&expression.kind, hint) {
+            (ASTNodeKind::Number(value), Ty::Value(var_type)) if var_type.is_integer() => {
+                self.check_literal(value, var_type, expression.span);
+                self.literals.insert(expression.id(), var_type.clone());
+                hint.clone()
+            }
+            (ASTNodeKind::Array(elements), Ty::Value(VariableType::Array(element))) => {
+                for value in elements {
+                    self.check_expression(value, element);
+                }
+                hint.clone()
+            }
+            _ => self.infer(expression),
+        }
+    }
+
+    fn infer(&mut self, expression: &ASTNode) -> Ty {
+        match &expression.kind {
+            ASTNodeKind::Number(value) => {
+                self.check_literal(value, &VariableType::U128, expression.span);
+                self.literals.insert(expression.id(), VariableType::U128);
+                Ty::Value(VariableType::U128)
+            }
+            ASTNodeKind::StringLiteral(_) => Ty::Value(VariableType::String),
+            ASTNodeKind::Bool(_) => Ty::Value(VariableType::Bool),
+            ASTNodeKind::Address(_) => Ty::Value(VariableType::Address),
+            ASTNodeKind::Array(elements) => {
+                let Some((first, rest)) = elements.split_first() else {
+                    return Ty::Unknown; // The element type of `[]` comes from its use
+                };
+                let element = self.infer(first);
+                for value in rest {
+                    let found = self.infer_with_hint(value, &element);
+                    if let Ty::Value(expected) = &element {
+                        self.expect_type(value, &found, expected);
+                    }
+                }
+                match element {
+                    Ty::Value(element) => Ty::Value(VariableType::Array(Box::new(element))),
+                    _ => Ty::Unknown,
+                }
+            }
+            ASTNodeKind::Identifier(name) => self.lookup(name),
+            ASTNodeKind::Binary { op, left, right } => self.infer_binary(*op, left, right),
+            ASTNodeKind::Unary { op, operand } => self.infer_unary(*op, operand, expression.span),
+            ASTNodeKind::Cast { value, target } => self.infer_cast(value, target, expression.span),
+            ASTNodeKind::Member { object, property } => {
+                self.infer_member(object, property, expression.span)
+            }
+            ASTNodeKind::Index { object, index } => self.infer_index(object, index),
+            ASTNodeKind::Call { callee, args } => self.infer_call(callee, args, expression.span),
+            _ => Ty::Unknown,
+        }
+    }
+
+    fn check_literal(&mut self, value: &str, var_type: &VariableType, span: Span) {
+        let max = match var_type {
+            VariableType::U8 => u8::MAX as u128,
+            _ => u128::MAX,
+        };
+
+        if value.contains('.') {
+            self.error(span, format!("Expected an integer, found `{}`", value))
+                .notes
+                .push("fractional numbers are not supported".to_owned());
+        } else if value.parse::<u128>().map_or(true, |value| value > max) {
+            self.error(
+                span,
+                format!("Literal `{}` does not fit in `{}`", value, var_type),
+            )
+            .notes
+            .push(format!("`{}` holds values from 0 to {}", var_type, max));
+        }
+    }
+
+    fn infer_binary(&mut self, op: BinaryOp, left: &ASTNode, right: &ASTNode) -> Ty {
+        // Let a literal on either side take the type of the other operand
+        let (left_type, right_type) = if is_number(left) && !is_number(right) {
+            let right_type = self.infer(right);
+            (self.infer_with_hint(left, &right_type), right_type)
+        } else {
+            let left_type = self.infer(left);
+            (left_type.clone(), self.infer_with_hint(right, &left_type))
+        };
+
+        let (Ty::Value(left_type), Ty::Value(right_type)) = (&left_type, &right_type) else {
+            for (operand, found) in [(left, &left_type), (right, &right_type)] {
+                if *found == Ty::Void {
+                    self.error(
+                        operand.span,
+                        "Expected a value, but this call returns nothing",
+                    );
+                }
+            }
+            return match op {
+                BinaryOp::Eq
+                | BinaryOp::Ne
+                | BinaryOp::Lt
+                | BinaryOp::Le
+                | BinaryOp::Gt
+                | BinaryOp::Ge
+                | BinaryOp::And
+                | BinaryOp::Or => Ty::Value(VariableType::Bool),
+                _ => Ty::Unknown,
+            };
+        };
+
+        let operands_match = left_type == right_type;
+        let (accepted, result) = match op {
+            BinaryOp::And | BinaryOp::Or => (
+                *left_type == VariableType::Bool && operands_match,
+                VariableType::Bool,
+            ),
+            BinaryOp::Eq | BinaryOp::Ne => (
+                operands_match && !matches!(left_type, VariableType::Array(_)),
+                VariableType::Bool,
+            ),
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+                (left_type.is_integer() && operands_match, VariableType::Bool)
+            }
+            _ => (left_type.is_integer() && operands_match, left_type.clone()),
+        };
+
+        if !accepted {
+            let span = Span::new(left.span.file, left.span.start, right.span.end);
+            let diagnostic = self.error(
+                span,
+                format!(
+                    "Operator `{}` cannot be applied to `{}` and `{}`",
+                    op.symbol(),
+                    left_type,
+                    right_type
+                ),
+            );
+            if left_type.is_integer() && right_type.is_integer() {
+                diagnostic.notes.push(format!(
+                    "use an explicit cast such as `value as {}`",
+                    left_type
+                ));
+            }
+
+            // Comparisons still produce a bool, anything else would cascade
+            if result != VariableType::Bool {
+                return Ty::Unknown;
+            }
+        }
+
+        Ty::Value(result)
+    }
+
+    fn infer_unary(&mut self, op: UnaryOp, operand: &ASTNode, span: Span) -> Ty {
+        match op {
+            UnaryOp::Not => {
+                self.check_expression(operand, &VariableType::Bool);
+                Ty::Value(VariableType::Bool)
+            }
+            UnaryOp::Neg => {
+                let found = self.infer(operand);
+                if let Ty::Value(found) = &found {
+                    self.error(span, format!("Cannot negate a value of type `{}`", found))
+                        .notes
+                        .push("all integer types are unsigned".to_owned());
+                }
+                found
+            }
+        }
+    }
+
+    fn infer_cast(&mut self, value: &ASTNode, target: &VariableType, span: Span) -> Ty {
+        // A literal is taken as the target type directly, so `300 as u8` is
+        // still rejected instead of silently truncated.
+        let found = self.infer_with_hint(value, &Ty::Value(target.clone()));
+
+        match &found {
+            Ty::Value(found) if found != target && !(found.is_integer() && target.is_integer()) => {
+                self.error(span, format!("Cannot cast `{}` to `{}`", found, target))
+                    .notes
+                    .push("only `u8` and `u128` can be cast into each other".to_owned());
+            }
+            Ty::Void => {
+                self.error(
+                    value.span,
+                    "Expected a value, but this call returns nothing",
+                );
+            }
+            _ => {}
+        }
+
+        Ty::Value(target.clone())
+    }
+
+    fn infer_member(&mut self, object: &ASTNode, property: &str, span: Span) -> Ty {
+        if is_this(object) {
+            return match property {
+                "caller" => Ty::Value(VariableType::Address),
+                "block" => Ty::Value(VariableType::U128), // The current block number
+                _ => Ty::Unknown,
+            };
+        }
+
+        match this_member(object) {
+            Some("state") => match self.symbols.state(property) {
+                Some(var_type) => Ty::Value(var_type.clone()),
+                None => Ty::Unknown,
+            },
+            Some(_) => Ty::Unknown,
+            None => {
+                if let Ty::Value(found) = self.infer(object) {
+                    self.error(span, format!("`{}` has no member `{}`", found, property));
+                }
+                Ty::Unknown
+            }
+        }
+    }
+
+    fn infer_index(&mut self, object: &ASTNode, index: &ASTNode) -> Ty {
+        let object_type = self.infer(object);
+
+        // Missing table entries read as the default value of the value type
+        if let Ty::Value(VariableType::Table(key, value)) = object_type {
+            self.check_expression(index, &key);
+            return Ty::Value(*value);
+        }
+
+        let index_type = self.infer(index);
+
+        if let Ty::Value(index_type) = &index_type {
+            if !index_type.is_integer() {
+                self.error(
+                    index.span,
+                    format!("Array indices must be integers, found `{}`", index_type),
+                );
+            }
+        }
+
+        match object_type {
+            Ty::Value(VariableType::Array(element)) => Ty::Value(*element),
+            Ty::Value(found) => {
+                self.error(object.span, format!("Cannot index into `{}`", found));
+                Ty::Unknown
+            }
+            _ => Ty::Unknown,
+        }
+    }
+
+    fn infer_call(&mut self, callee: &ASTNode, args: &[ASTNode], span: Span) -> Ty {
+        let name = match &callee.kind {
+            ASTNodeKind::Identifier(name) => Some(name.as_str()),
+            ASTNodeKind::Member { object, property } if this_member(object) == Some("scheme") => {
+                Some(property.as_str())
+            }
+            _ => None,
+        };
+
+        let Some((name, signature)) =
+            name.and_then(|name| Some((name, self.symbols.function(name)?)))
+        else {
+            for arg in args {
+                self.infer(arg);
+            }
+            return Ty::Unknown;
+        };
+
+        if args.len() != signature.params.len() {
+            self.error(
+                span,
+                format!(
+                    "`{}` takes {} argument{} but {} were given",
+                    name,
+                    signature.params.len(),
+                    if signature.params.len() == 1 { "" } else { "s" },
+                    args.len()
+                ),
+            );
+        }
+        for (arg, param) in args.iter().zip(&signature.params) {
+            self.check_expression(arg, param);
+        }
+        for arg in args.iter().skip(signature.params.len()) {
+            self.infer(arg);
+        }
+
+        match &signature.return_type {
+            Some(return_type) => Ty::Value(return_type.clone()),
+            None => Ty::Void,
+        }
+    }
+}
+
+fn is_number(node: &ASTNode) -> bool {
+    matches!(node.kind, ASTNodeKind::Number(_))
+}
+
+/// Whether every path through the statements ends in a return.
+fn always_returns(statements: &[ASTNode]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        ASTNodeKind::Return(_) => true,
+        ASTNodeKind::If {
+            body, else_body, ..
+        } => always_returns(body) && always_returns(else_body),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn analyze_source(input: &str) -> (SymbolTable, Vec<Diagnostic>) {
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+        let root = Parser::new(lexer).parse().unwrap();
+        analyze(&root, &sources)
+    }
+
+    fn messages(diagnostics: &[Diagnostic]) -> Vec<(Severity, &str)> {
+        diagnostics
+            .iter()
+            .map(|d| (d.severity, d.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_symbol_table() {
+        let input = r#"
+        $state {
+          address creator;
+          mut u128 supply;
+        }
+        $consts {
+          u128 MAX = 1000;
+          u128 HALF = MAX / 2;
+        }
+        init() {
+          this.state.creator = this.caller;
+          this.state.supply = HALF;
+        }
+        $program {
+          pub address creator() {
+            return this.state.creator;
+          }
+          pub mut grow(u128 amount) {
+            u128 next = this.state.supply + amount;
+            if next < MAX {
+              this.state.supply = capped(next);
+            }
+          }
+          u128 capped(u128 value) {
+            return value;
+          }
+        }
+        "#;
+
+        let (symbols, diagnostics) = analyze_source(input);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let state: Vec<&str> = symbols
+            .state_variables()
+            .map(|symbol| symbol.name.as_str())
+            .collect();
+        assert_eq!(state, vec!["creator", "supply"]);
+        assert_eq!(symbols.constant("HALF"), Some(&VariableType::U128));
+        assert_eq!(
+            symbols.function("grow"),
+            Some(&FunctionSignature {
+                public: true,
+                mutates: true,
+                constructor_only: false,
+                guard: false,
+                params: vec![VariableType::U128],
+                return_type: None,
+            })
+        );
+        assert!(symbols.function("init").is_some());
+        assert_eq!(symbols.iter().count(), 8);
+    }
+
+    #[test]
+    fn test_undefined_names() {
+        let input = r#"
+        $state { address creator; }
+        init() {
+          this.state.owner = this.caller;
+          this.scheme.distTokens(this.caller, 10);
+        }
+        $program {
+          pub mut run() {
+            u128 x = missing + 1;
+            unknown(x);
+            x(1);
+            this.sender = creator;
+          }
+        }
+        "#;
+
+        let (_, diagnostics) = analyze_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (Severity::Error, "No state variable named `owner`"),
+                (Severity::Error, "No scheme provides `distTokens`"),
+                (Severity::Error, "Cannot find `missing` in this scope"),
+                (
+                    Severity::Error,
+                    "Cannot find function `unknown` in this scope"
+                ),
+                (Severity::Error, "`x` is a local variable, not a function"),
+                (Severity::Error, "Unknown member `this.sender`"),
+                (Severity::Error, "Cannot find `creator` in this scope"),
+            ]
+        );
+        assert_eq!(
+            diagnostics[6].notes,
+            vec!["state variables are accessed through `this.state.creator`"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_declarations() {
+        let input = "$state {\n  u128 total;\n}\n$consts {\n  u128 total = 5;\n  u8 this_const = this.caller;\n}\n$program {\n  this_const() {}\n  run(u128 a, u8 a) {}\n}\n";
+
+        let (_, diagnostics) = analyze_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (Severity::Error, "Duplicate declaration of `total`"),
+                (
+                    Severity::Error,
+                    "`this` cannot be used in a constant initialiser"
+                ),
+                (Severity::Error, "Duplicate declaration of `this_const`"),
+                (Severity::Error, "Duplicate declaration of `a`"),
+            ]
+        );
+        assert_eq!(diagnostics[0].line, 5);
+        assert_eq!(
+            diagnostics[0].notes,
+            vec!["`total` was first declared as a state variable at main.se:2:3"]
+        );
+        assert_eq!(diagnostics[2].line, 9);
+        assert_eq!((diagnostics[3].line, diagnostics[3].column), (10, 15));
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let input = "$consts {\n  u128 LIMIT = 10;\n}\n$program {\n  run(u128 amount) {\n    u128 LIMIT = amount;\n    if amount > 1 {\n      u128 amount = 2;\n    }\n    u128 amount = 3;\n  }\n}\n";
+
+        let (_, diagnostics) = analyze_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (
+                    Severity::Warning,
+                    "`LIMIT` shadows the constant declared at main.se:2:3"
+                ),
+                (
+                    Severity::Warning,
+                    "`amount` shadows the parameter declared at main.se:5:7"
+                ),
+                (Severity::Error, "Duplicate declaration of `amount`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_const_cycles() {
+        let input = "$consts {\n  u128 A = B;\n  u128 B = A + 1;\n  u128 C = C * 2;\n  u128 D = A + 4;\n}\n$program {\n  u128 run() {\n    return A;\n  }\n}\n";
+
+        let (_, diagnostics) = analyze_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (Severity::Error, "Constant `A` depends on itself"),
+                (Severity::Error, "Constant `C` depends on itself"),
+            ]
+        );
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!(diagnostics[0].notes, vec!["the cycle is `A` -> `B` -> `A`"]);
+        assert_eq!(diagnostics[1].notes, vec!["the cycle is `C` -> `C`"]);
+    }
+
+    fn check_source(input: &str) -> Vec<Diagnostic> {
+        let sources = SourceMap::new();
+        let lexer = Lexer::new(&sources, sources.add("main.se", input), &[]);
+        let root = Parser::new(lexer).parse().unwrap();
+        let (symbols, mut diagnostics) = analyze(&root, &sources);
+        diagnostics.extend(check_types(&root, &symbols, &sources).1);
+        diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+        diagnostics
+    }
+
+    #[test]
+    fn test_well_typed_program() {
+        let input = r#"
+        $state {
+          mut u128 supply;
+          mut u8 level;
+        }
+        $consts {
+          u8 MAX_LEVEL = 255;
+          u128 CAP = 10e12 * 5;
+        }
+        $program {
+          pub mut raise(u8 by) {
+            u8 next = this.state.level + by;
+            if next <= MAX_LEVEL && !(next == 0) {
+              this.state.level = next;
+              this.state.supply = this.state.supply + next as u128 * 2;
+            }
+          }
+          pub u128 remaining() {
+            if this.state.supply >= CAP {
+              return 0;
+            } else {
+              return CAP - this.state.supply;
+            }
+          }
+          bool is_caller(address who) {
+            return who == this.caller;
+          }
+        }
+        "#;
+
+        let diagnostics = check_source(input);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn test_const_initialisers() {
+        let input = "$consts {\n  u8 SMALL = 300;\n  u8 FIT = 255;\n  u128 FLAG = true;\n  bool ON = 1 < 2;\n  u8 HALF = 1.5;\n}\n";
+
+        let diagnostics = check_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (Severity::Error, "Literal `300` does not fit in `u8`"),
+                (
+                    Severity::Error,
+                    "Mismatched types: expected `u128`, found `bool`"
+                ),
+                (Severity::Error, "Expected an integer, found `1.5`"),
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].notes,
+            vec!["`u8` holds values from 0 to 255"]
+        );
+        assert_eq!(diagnostics[0].line, 2);
+    }
+
+    #[test]
+    fn test_integer_mixing_requires_cast() {
+        let input = r#"
+        $program {
+          run(u8 small, u128 big) {
+            u128 sum = small + big;
+            u128 widened = small;
+            u8 narrowed = big as u8;
+            u128 ok = small as u128 + big;
+            bool flag = small as bool;
+          }
+        }
+        "#;
+
+        let diagnostics = check_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (
+                    Severity::Error,
+                    "Operator `+` cannot be applied to `u8` and `u128`"
+                ),
+                (
+                    Severity::Error,
+                    "Mismatched types: expected `u128`, found `u8`"
+                ),
+                (Severity::Error, "Cannot cast `u8` to `bool`"),
+            ]
+        );
+        assert_eq!(
+            diagnostics[1].notes,
+            vec!["use an explicit cast such as `value as u128`"]
+        );
+    }
+
+    #[test]
+    fn test_return_types() {
+        let input = r#"
+        $program {
+          u128 missing_value() {
+            return;
+          }
+          u8 wrong_type() {
+            return true;
+          }
+          nothing() {
+            return 5;
+          }
+          u128 falls_through(bool flag) {
+            if flag {
+              return 1;
+            }
+          }
+          pub u128 uses_void() {
+            return nothing();
+          }
+          u8 bad_call() {
+            return wrong_type(1);
+          }
+        }
+        "#;
+
+        let diagnostics = check_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (Severity::Error, "Expected a `u128` return value"),
+                (
+                    Severity::Error,
+                    "Mismatched types: expected `u8`, found `bool`"
+                ),
+                (Severity::Error, "This function does not return a value"),
+                (
+                    Severity::Error,
+                    "Function `falls_through` may end without returning a value"
+                ),
+                (
+                    Severity::Error,
+                    "Expected a value, but this call returns nothing"
+                ),
+                (
+                    Severity::Error,
+                    "`wrong_type` takes 0 arguments but 1 were given"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mutability_rules() {
+        let input = r#"
+        $state {
+          address creator;
+          mut u128 counter;
+        }
+        init() {
+          this.state.creator = this.caller;
+          grant(this.caller);
+        }
+        init grant(address to) {
+          this.state.counter = 1;
+        }
+        $program {
+          pub mut bump() {
+            this.state.counter = this.state.counter + 1;
+            this.state.creator = this.caller;
+            grant(this.caller);
+          }
+          pub peek() {
+            this.state.counter = 0;
+            bump();
+          }
+        }
+        "#;
+
+        let diagnostics = check_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (
+                    Severity::Error,
+                    "Cannot assign to immutable state `creator` outside of `init()`"
+                ),
+                (Severity::Error, "`grant` can only be called from `init()`"),
+                (
+                    Severity::Error,
+                    "Cannot modify state in `peek`, which is not marked `mut`"
+                ),
+                (
+                    Severity::Error,
+                    "Cannot call `mut` function `bump` from `peek`, which is not marked `mut`"
+                ),
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].notes,
+            vec!["declare it as `mut creator` to allow assignments from `mut` procedures"]
+        );
+    }
+
+    #[test]
+    fn test_guards() {
+        let input = r#"
+        $state {
+          address creator;
+          mut u128 counter;
+        }
+        init() {
+          this.state.creator = this.caller;
+        }
+        $program {
+          bool require_creator() {
+            return this.caller == this.state.creator;
+          }
+          mut bool require_bump() {
+            this.state.counter = this.state.counter + 1;
+            return true;
+          }
+          u128 require_count() {
+            return this.state.counter;
+          }
+
+          pub mut bool reset() {
+            if !require_creator() {
+              return false;
+            }
+            this.state.counter = 0;
+            return true;
+          }
+          pub mut reset_unchecked() {
+            require_creator();
+            this.state.counter = 0;
+          }
+        }
+        "#;
+
+        let (symbols, diagnostics) = analyze_source(input);
+        assert!(symbols.function("require_creator").unwrap().guard);
+        assert!(!symbols.function("reset").unwrap().guard);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (
+                    Severity::Error,
+                    "Guard `require_bump` cannot be marked `mut`"
+                ),
+                (Severity::Error, "Guard `require_count` must return `bool`"),
+                (
+                    Severity::Error,
+                    "The result of guard `require_creator` is not checked"
+                ),
+            ]
+        );
+        assert_eq!(
+            diagnostics[2].notes,
+            vec![
+                "guards do not stop the procedure, use `if !require_creator(...) { return ...; }`"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_types() {
+        let input = r#"
+        $state {
+          table(address, u128) balances;
+          table(address, table(address, u128)) allowances;
+          table(table(address, u8), u8) by_table;
+        }
+        $program {
+          pub u128 balance_of(address owner) {
+            return this.state.balances[owner];
+          }
+          pub mut approve(address spender, u128 amount) {
+            this.state.allowances[this.caller][spender] = amount;
+            this.state.balances[this.caller] = true;
+            this.state.balances[1] = amount;
+          }
+          pub mut reset(table(address, u8) copy) {
+            this.state.balances = this.state.balances;
+          }
+        }
+        "#;
+
+        let diagnostics = check_source(input);
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (
+                    Severity::Error,
+                    "`table(address, u8)` cannot be used as a table key"
+                ),
+                (
+                    Severity::Error,
+                    "Mismatched types: expected `u128`, found `bool`"
+                ),
+                (
+                    Severity::Error,
+                    "Mismatched types: expected `address`, found `u128`"
+                ),
+                (Severity::Error, "Tables can only be declared in `$state`"),
+                (
+                    Severity::Error,
+                    "Cannot assign to immutable state `balances` outside of `init()`"
+                ),
+                (Severity::Error, "Tables cannot be assigned to as a whole"),
+            ]
+        );
+    }
+}
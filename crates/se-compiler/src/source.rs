@@ -0,0 +1,171 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use elsa::FrozenVec;
+
+/// Identifies a single source file taking part in a compilation, the entry
+/// file always being `FileId(0)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct FileId(pub u32);
+
+/// A byte range within a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: FileId, start: usize, end: usize) -> Self {
+        Span { file, start, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// A file loaded into a [`SourceMap`].
+#[derive(Debug)]
+pub struct SourceFile {
+    pub id: FileId,
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub content: String,
+}
+
+/// Owns the contents of every file taking part in a compilation, so tokens
+/// and diagnostics can borrow from them for as long as the map lives.
+///
+/// Files are only ever appended, which is what allows [`SourceMap::add`] to
+/// take `&self`: the lexer discovers `$include`s while other tokens still
+/// borrow from files that were loaded earlier.
+#[derive(Default)]
+pub struct SourceMap {
+    files: FrozenVec<Box<SourceFile>>, // Boxed so files keep their address
+    by_path: RefCell<HashMap<PathBuf, FileId>>, // Keyed by canonical path
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// Adds a file that does not exist on disk, such as an in-memory buffer.
+    pub fn add(&self, name: impl Into<String>, content: impl Into<String>) -> FileId {
+        self.insert(name.into(), None, content.into())
+    }
+
+    /// Reads a file from disk and adds it to the map. Loading a file that
+    /// is already part of the map returns the existing id.
+    pub fn load(&self, path: &Path) -> io::Result<FileId> {
+        let canonical = path.canonicalize()?;
+        if let Some(id) = self.by_path.borrow().get(&canonical) {
+            return Ok(*id);
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        let name = path.to_string_lossy().into_owned();
+        let id = self.insert(name, Some(path.to_path_buf()), content);
+
+        self.by_path.borrow_mut().insert(canonical, id);
+        Ok(id)
+    }
+
+    /// Looks up a file previously loaded from disk, regardless of the
+    /// relative path it was loaded through.
+    pub fn find(&self, path: &Path) -> Option<FileId> {
+        let canonical = path.canonicalize().ok()?;
+        self.by_path.borrow().get(&canonical).copied()
+    }
+
+    fn insert(&self, name: String, path: Option<PathBuf>, content: String) -> FileId {
+        let id = FileId(self.files.len() as u32);
+        self.files.push(Box::new(SourceFile {
+            id,
+            name,
+            path,
+            content,
+        }));
+        id
+    }
+
+    pub fn get(&self, id: FileId) -> Option<&SourceFile> {
+        self.files.get(id.0 as usize)
+    }
+
+    /// Formats the start of a span as `file:line:column`, for use in notes.
+    pub fn location(&self, span: Span) -> String {
+        match self.get(span.file) {
+            Some(file) => {
+                let (line, column) = line_col(&file.content, span.start);
+                format!("{}:{}:{}", file.name, line, column)
+            }
+            None => "<unknown>".to_owned(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// Resolves a byte offset into a 1-based line and column (counted in characters).
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = source[..offset].matches('\n').count() + 1;
+    let column = source[line_start..offset].chars().count() + 1;
+    (line, column)
+}
+
+/// Returns the full line of source that contains the byte offset, without the newline.
+pub fn line_at(source: &str, offset: usize) -> &str {
+    let offset = offset.min(source.len());
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    &source[line_start..line_end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_outlive_later_additions() {
+        let sources = SourceMap::new();
+        let main = sources.add("main.se", "$include \"main.seh\"");
+        let content = &sources.get(main).unwrap().content;
+
+        // Adding more files must not invalidate borrows of earlier ones
+        for i in 0..64 {
+            sources.add(format!("header_{}.seh", i), "$state {}");
+        }
+
+        assert_eq!(content, "$include \"main.seh\"");
+        assert_eq!(sources.len(), 65);
+        assert_eq!(sources.get(FileId(64)).unwrap().name, "header_63.seh");
+        assert!(sources.get(FileId(65)).is_none());
+    }
+
+    #[test]
+    fn test_line_col() {
+        let source = "a\nbc\n";
+        assert_eq!(line_col(source, 0), (1, 1));
+        assert_eq!(line_col(source, 3), (2, 2));
+        assert_eq!(line_at(source, 3), "bc");
+    }
+}
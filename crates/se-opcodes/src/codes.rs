@@ -1,6 +1,6 @@
 use crate::errors::OpcodeError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
     // Arithmetic operations
     ADD(u8, u8), // Add two registers (operands are register indices)
@@ -10,6 +10,7 @@ pub enum Opcode {
     MOD(u8, u8), // Modulo two registers
     SQRT(u8),    // Square root of a register
     EXP(u8, u8), // Exponentiation of two registers
+    NARROW(u8),  // Narrow a register to a u8, keeping its lowest byte (e.g. 300 becomes 44)
 
     // Memory operations
     LOAD(u8, u8),  // Load value from a register into a local variable
@@ -20,103 +21,109 @@ pub enum Opcode {
     SSET(u8, u8), // Push register value into state (e.g. SSET(9, 0) -> From register 9 to state 0)
     SMGET(u8, u8, u8), // Load value from a state map into register (e.g., SMGET(0, 0, 9) -> From state index 0, key index 0 to register 9)
     SMSET(u8, u8, u8), // Push register value into state map (e.g., SMSET(9, 0, 0) -> From register 9 to state index 0, key index 0)
+    KEY(u8, u8), // Join the key in the second register onto the key in the first, for entries of nested tables (e.g. KEY(1, 2) -> The key of [r1][r2] into register 1)
+    BLOCK(u8),   // Load the current block number into a register
 
     // Function operations
     CALL(u8), // Call a function by index
     RET,      // Return from a function
+
+    // Comparison operations, the result is a bool in the first register
+    EQ(u8, u8), // Whether two registers are equal
+    NE(u8, u8), // Whether two registers differ
+    LT(u8, u8), // Whether the first register is less than the second
+    LE(u8, u8), // Whether the first register is less than or equal to the second
+    GT(u8, u8), // Whether the first register is greater than the second
+    GE(u8, u8), // Whether the first register is greater than or equal to the second
+
+    // Logical and bitwise operations, logical on bools and bitwise on integers
+    AND(u8, u8), // And of two registers
+    OR(u8, u8),  // Or of two registers
+    NOT(u8),     // Not of a register
+    XOR(u8, u8), // Exclusive or of two registers
+    SHL(u8, u8), // Shift the first register left by the second
+    SHR(u8, u8), // Shift the first register right by the second
+
+    // Control flow, targets are byte offsets into the function's code
+    JMP(u16),        // Jump to an offset
+    JMPIF(u8, u16),  // Jump to an offset if a register holds true
+    JMPNOT(u8, u16), // Jump to an offset if a register holds false
+
+    // Immediate operations, loading a value into a register (e.g. LOADI(1, 10) -> 10 as u128 into register 1)
+    LOADI(u8, u8),  // Load a u128 of at most 255 into a register
+    LOADI8(u8, u8), // Load a u8 into a register
+    LOADB(u8, u8),  // Load a bool into a register, any value other than 0 is true
+    LOADC(u8, u8), // Load an entry of the constant pool into a register (e.g. LOADC(1, 0) -> Constant 0 into register 1)
 }
 
 impl Opcode {
-    pub fn from_hex(hex: u8, operands: &[u8]) -> Result<Opcode, OpcodeError> {
+    /// The number of operand bytes following an opcode byte, or `None` for
+    /// bytes that are not an opcode.
+    pub fn operand_count(hex: u8) -> Option<usize> {
         match hex {
-            0x01 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::ADD(operands[0], operands[1]))
-            }
-            0x02 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::SUB(operands[0], operands[1]))
-            }
-            0x03 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::MUL(operands[0], operands[1]))
-            }
-            0x04 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::DIV(operands[0], operands[1]))
-            }
-            0x05 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::MOD(operands[0], operands[1]))
-            }
-            0x06 => {
-                if operands.len() != 1 {
-                    return Err(OpcodeError::OperandLenghtMismatch(1, operands.len()));
-                }
-                Ok(Opcode::SQRT(operands[0]))
-            }
-            0x07 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::EXP(operands[0], operands[1]))
-            }
-            0x08 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::LOAD(operands[0], operands[1]))
-            }
-            0x09 => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::STORE(operands[0], operands[1]))
-            }
-            0x0A => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::SGET(operands[0], operands[1]))
-            }
-            0x0B => {
-                if operands.len() != 2 {
-                    return Err(OpcodeError::OperandLenghtMismatch(2, operands.len()));
-                }
-                Ok(Opcode::SSET(operands[0], operands[1]))
-            }
-            0x0C => {
-                if operands.len() != 3 {
-                    return Err(OpcodeError::OperandLenghtMismatch(3, operands.len()));
-                }
-                Ok(Opcode::SMGET(operands[0], operands[1], operands[2]))
-            }
-            0x0D => {
-                if operands.len() != 3 {
-                    return Err(OpcodeError::OperandLenghtMismatch(3, operands.len()));
-                }
-                Ok(Opcode::SMSET(operands[0], operands[1], operands[2]))
-            }
-            0x0E => {
-                if operands.len() != 1 {
-                    return Err(OpcodeError::OperandLenghtMismatch(1, operands.len()));
-                }
-                Ok(Opcode::CALL(operands[0]))
-            }
-            0x0F => Ok(Opcode::RET),
+            0x0F => Some(0),
+            0x06 | 0x0E | 0x18 | 0x24 | 0x25 => Some(1),
+            0x01..=0x05 | 0x07..=0x0B | 0x10..=0x17 | 0x19..=0x1C | 0x1F..=0x23 => Some(2),
+            0x0C | 0x0D | 0x1D | 0x1E => Some(3),
+            _ => None,
+        }
+    }
 
-            _ => Err(OpcodeError::InvalidOpcode(hex)),
+    /// Builds an opcode from its byte and operands. Offsets in errors are
+    /// relative to the opcode, see `decode` for whole programs.
+    pub fn from_hex(hex: u8, operands: &[u8]) -> Result<Opcode, OpcodeError> {
+        let Some(expected) = Self::operand_count(hex) else {
+            return Err(OpcodeError::InvalidOpcode(hex, 0));
+        };
+        if operands.len() != expected {
+            return Err(OpcodeError::OperandLenghtMismatch(
+                expected,
+                operands.len(),
+                0,
+            ));
         }
+
+        let op = |index: usize| operands[index];
+        let target = |index: usize| u16::from_le_bytes([op(index), op(index + 1)]);
+        Ok(match hex {
+            0x01 => Opcode::ADD(op(0), op(1)),
+            0x02 => Opcode::SUB(op(0), op(1)),
+            0x03 => Opcode::MUL(op(0), op(1)),
+            0x04 => Opcode::DIV(op(0), op(1)),
+            0x05 => Opcode::MOD(op(0), op(1)),
+            0x06 => Opcode::SQRT(op(0)),
+            0x07 => Opcode::EXP(op(0), op(1)),
+            0x08 => Opcode::LOAD(op(0), op(1)),
+            0x09 => Opcode::STORE(op(0), op(1)),
+            0x0A => Opcode::SGET(op(0), op(1)),
+            0x0B => Opcode::SSET(op(0), op(1)),
+            0x0C => Opcode::SMGET(op(0), op(1), op(2)),
+            0x0D => Opcode::SMSET(op(0), op(1), op(2)),
+            0x0E => Opcode::CALL(op(0)),
+            0x0F => Opcode::RET,
+            0x10 => Opcode::EQ(op(0), op(1)),
+            0x11 => Opcode::NE(op(0), op(1)),
+            0x12 => Opcode::LT(op(0), op(1)),
+            0x13 => Opcode::LE(op(0), op(1)),
+            0x14 => Opcode::GT(op(0), op(1)),
+            0x15 => Opcode::GE(op(0), op(1)),
+            0x16 => Opcode::AND(op(0), op(1)),
+            0x17 => Opcode::OR(op(0), op(1)),
+            0x18 => Opcode::NOT(op(0)),
+            0x19 => Opcode::XOR(op(0), op(1)),
+            0x1A => Opcode::SHL(op(0), op(1)),
+            0x1B => Opcode::SHR(op(0), op(1)),
+            0x1C => Opcode::JMP(target(0)),
+            0x1D => Opcode::JMPIF(op(0), target(1)),
+            0x1E => Opcode::JMPNOT(op(0), target(1)),
+            0x1F => Opcode::LOADI(op(0), op(1)),
+            0x20 => Opcode::LOADI8(op(0), op(1)),
+            0x21 => Opcode::LOADB(op(0), op(1)),
+            0x22 => Opcode::LOADC(op(0), op(1)),
+            0x23 => Opcode::KEY(op(0), op(1)),
+            0x24 => Opcode::BLOCK(op(0)),
+            _ => Opcode::NARROW(op(0)),
+        })
     }
 
     pub fn to_hex(&self) -> u8 {
@@ -136,6 +143,262 @@ impl Opcode {
             Opcode::SMSET(_, _, _) => 0x0D,
             Opcode::CALL(_) => 0x0E,
             Opcode::RET => 0x0F,
+            Opcode::EQ(_, _) => 0x10,
+            Opcode::NE(_, _) => 0x11,
+            Opcode::LT(_, _) => 0x12,
+            Opcode::LE(_, _) => 0x13,
+            Opcode::GT(_, _) => 0x14,
+            Opcode::GE(_, _) => 0x15,
+            Opcode::AND(_, _) => 0x16,
+            Opcode::OR(_, _) => 0x17,
+            Opcode::NOT(_) => 0x18,
+            Opcode::XOR(_, _) => 0x19,
+            Opcode::SHL(_, _) => 0x1A,
+            Opcode::SHR(_, _) => 0x1B,
+            Opcode::JMP(_) => 0x1C,
+            Opcode::JMPIF(_, _) => 0x1D,
+            Opcode::JMPNOT(_, _) => 0x1E,
+            Opcode::LOADI(_, _) => 0x1F,
+            Opcode::LOADI8(_, _) => 0x20,
+            Opcode::LOADB(_, _) => 0x21,
+            Opcode::LOADC(_, _) => 0x22,
+            Opcode::KEY(_, _) => 0x23,
+            Opcode::BLOCK(_) => 0x24,
+            Opcode::NARROW(_) => 0x25,
+        }
+    }
+
+    /// The operand bytes, in the order they are encoded. Jump targets are
+    /// little-endian.
+    pub fn operands(&self) -> Vec<u8> {
+        match *self {
+            Opcode::SQRT(a)
+            | Opcode::CALL(a)
+            | Opcode::NOT(a)
+            | Opcode::BLOCK(a)
+            | Opcode::NARROW(a) => vec![a],
+            Opcode::ADD(a, b)
+            | Opcode::SUB(a, b)
+            | Opcode::MUL(a, b)
+            | Opcode::DIV(a, b)
+            | Opcode::MOD(a, b)
+            | Opcode::EXP(a, b)
+            | Opcode::LOAD(a, b)
+            | Opcode::STORE(a, b)
+            | Opcode::SGET(a, b)
+            | Opcode::SSET(a, b)
+            | Opcode::EQ(a, b)
+            | Opcode::NE(a, b)
+            | Opcode::LT(a, b)
+            | Opcode::LE(a, b)
+            | Opcode::GT(a, b)
+            | Opcode::GE(a, b)
+            | Opcode::AND(a, b)
+            | Opcode::OR(a, b)
+            | Opcode::XOR(a, b)
+            | Opcode::SHL(a, b)
+            | Opcode::SHR(a, b)
+            | Opcode::LOADI(a, b)
+            | Opcode::LOADI8(a, b)
+            | Opcode::LOADB(a, b)
+            | Opcode::LOADC(a, b)
+            | Opcode::KEY(a, b) => vec![a, b],
+            Opcode::SMGET(a, b, c) | Opcode::SMSET(a, b, c) => vec![a, b, c],
+            Opcode::JMP(target) => target.to_le_bytes().to_vec(),
+            Opcode::JMPIF(a, target) | Opcode::JMPNOT(a, target) => {
+                let [low, high] = target.to_le_bytes();
+                vec![a, low, high]
+            }
+            Opcode::RET => Vec::new(),
         }
     }
+
+    /// The number of bytes the opcode is encoded in.
+    pub fn size(&self) -> usize {
+        1 + self.operands().len()
+    }
+
+    /// The offset a jump continues at, `None` for other opcodes.
+    pub fn jump_target(&self) -> Option<u16> {
+        match *self {
+            Opcode::JMP(target) | Opcode::JMPIF(_, target) | Opcode::JMPNOT(_, target) => {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
+    /// The same jump continuing at `target`, other opcodes are returned as
+    /// they are.
+    pub fn with_jump_target(self, target: u16) -> Opcode {
+        match self {
+            Opcode::JMP(_) => Opcode::JMP(target),
+            Opcode::JMPIF(a, _) => Opcode::JMPIF(a, target),
+            Opcode::JMPNOT(a, _) => Opcode::JMPNOT(a, target),
+            opcode => opcode,
+        }
+    }
+}
+
+/// Serialises a program, every opcode as its byte followed by its operands.
+pub fn encode(program: &[Opcode]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for opcode in program {
+        bytes.push(opcode.to_hex());
+        bytes.extend(opcode.operands());
+    }
+    bytes
+}
+
+/// Reads a program back, along with the byte offset of every opcode. Errors
+/// carry the offset of the opcode that could not be read, and every jump
+/// must land on the first byte of an opcode.
+pub fn decode(bytes: &[u8]) -> Result<Vec<(usize, Opcode)>, OpcodeError> {
+    let mut program = Vec::new();
+    let mut offset = 0;
+
+    while let Some(&hex) = bytes.get(offset) {
+        let Some(count) = Opcode::operand_count(hex) else {
+            return Err(OpcodeError::InvalidOpcode(hex, offset));
+        };
+        let operands = &bytes[offset + 1..];
+        if operands.len() < count {
+            return Err(OpcodeError::OperandLenghtMismatch(
+                count,
+                operands.len(),
+                offset,
+            ));
+        }
+
+        program.push((offset, Opcode::from_hex(hex, &operands[..count])?));
+        offset += 1 + count;
+    }
+
+    for &(offset, opcode) in &program {
+        let Some(target) = opcode.jump_target() else {
+            continue;
+        };
+        let target = usize::from(target);
+        if program
+            .binary_search_by_key(&target, |&(at, _)| at)
+            .is_err()
+        {
+            return Err(OpcodeError::InvalidJumpTarget(target, offset));
+        }
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small xorshift generator, so the property test needs no crates and
+    /// fails the same way on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn byte(&mut self) -> u8 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 24) as u8
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x5e1e_41de);
+
+        for _ in 0..500 {
+            // Programs of random opcodes with random operands, covering
+            // every opcode byte and the edges of the operand range
+            let mut program: Vec<Opcode> = (0..rng.byte() % 32 + 1)
+                .map(|_| {
+                    let hex = rng.byte() % 0x25 + 1;
+                    let count = Opcode::operand_count(hex).unwrap();
+                    let operands: Vec<u8> = (0..count)
+                        .map(|_| match rng.byte() % 4 {
+                            0 => 0,
+                            1 => u8::MAX,
+                            _ => rng.byte(),
+                        })
+                        .collect();
+                    Opcode::from_hex(hex, &operands).unwrap()
+                })
+                .collect();
+
+            // Jumps are pointed at the start of a random opcode
+            let offsets: Vec<usize> = program
+                .iter()
+                .scan(0, |offset, opcode| {
+                    let at = *offset;
+                    *offset += opcode.size();
+                    Some(at)
+                })
+                .collect();
+            for opcode in &mut program {
+                let target = offsets[usize::from(rng.byte()) % offsets.len()];
+                *opcode = opcode.with_jump_target(target as u16);
+            }
+
+            let bytes = encode(&program);
+            let decoded = decode(&bytes).unwrap();
+            let opcodes: Vec<Opcode> = decoded.iter().map(|(_, opcode)| *opcode).collect();
+            assert_eq!(opcodes, program);
+
+            let mut offset = 0;
+            for (at, opcode) in decoded {
+                assert_eq!(at, offset);
+                assert_eq!(bytes[at], opcode.to_hex());
+                offset += opcode.size();
+            }
+        }
+
+        for hex in 0x01..=0x25 {
+            let count = Opcode::operand_count(hex).unwrap();
+            let opcode = Opcode::from_hex(hex, &vec![0; count]).unwrap();
+            assert_eq!(opcode.to_hex(), hex);
+            assert_eq!(decode(&encode(&[opcode])).unwrap(), vec![(0, opcode)]);
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let bytes = encode(&[Opcode::ADD(1, 2), Opcode::CALL(0)]);
+        assert_eq!(
+            decode(&[&bytes[..], &[0x42]].concat()),
+            Err(OpcodeError::InvalidOpcode(0x42, 5))
+        );
+        assert_eq!(
+            decode(&bytes[..4]),
+            Err(OpcodeError::OperandLenghtMismatch(1, 0, 3))
+        );
+        assert_eq!(
+            decode(&[0x0C, 1]),
+            Err(OpcodeError::OperandLenghtMismatch(3, 1, 0))
+        );
+        assert_eq!(decode(&[]), Ok(Vec::new()));
+
+        // Jumps into the operands of an opcode or past the end
+        let bytes = encode(&[Opcode::ADD(1, 2), Opcode::JMPIF(1, 1)]);
+        assert_eq!(decode(&bytes), Err(OpcodeError::InvalidJumpTarget(1, 3)));
+        let bytes = encode(&[Opcode::JMP(3)]);
+        assert_eq!(decode(&bytes), Err(OpcodeError::InvalidJumpTarget(3, 0)));
+        let bytes = encode(&[Opcode::JMPNOT(1, 4), Opcode::RET]);
+        assert_eq!(
+            decode(&bytes).unwrap(),
+            vec![(0, Opcode::JMPNOT(1, 4)), (4, Opcode::RET)]
+        );
+
+        assert_eq!(
+            Opcode::from_hex(0x01, &[1]),
+            Err(OpcodeError::OperandLenghtMismatch(2, 1, 0))
+        );
+        assert_eq!(
+            Opcode::from_hex(0x00, &[]),
+            Err(OpcodeError::InvalidOpcode(0x00, 0))
+        );
+    }
 }
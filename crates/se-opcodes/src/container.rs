@@ -0,0 +1,659 @@
+use crate::errors::ContainerError;
+
+/// Marks a file as a compiled contract.
+pub const MAGIC: [u8; 4] = *b"\0SEL";
+/// The layout described below, bumped on every incompatible change.
+pub const FORMAT_VERSION: u16 = 1;
+
+// Section ids. Every section but debug info is required, in order of id.
+pub const SECTION_FUNCTIONS: u8 = 1;
+pub const SECTION_CODE: u8 = 2;
+pub const SECTION_CONSTANTS: u8 = 3;
+pub const SECTION_STATE: u8 = 4;
+pub const SECTION_DEBUG: u8 = 5;
+
+const FLAG_PUBLIC: u8 = 1;
+const FLAG_MUTATES: u8 = 1 << 1;
+const FLAG_CONSTRUCTOR_ONLY: u8 = 1 << 2;
+
+const MAX_TYPE_DEPTH: usize = 16; // Deeper types are rejected rather than recursed into
+
+/// The type of a state variable, mirroring the compiler's `VariableType`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    U128,
+    Bool,
+    String,
+    Address,
+    Array(Box<ValueType>),
+    Table(Box<ValueType>, Box<ValueType>),
+}
+
+/// A procedure along with its code. Its position in the function table is
+/// the index `CALL` refers to it by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionEntry {
+    pub name: String,
+    pub public: bool,
+    pub mutates: bool,
+    pub constructor_only: bool,
+    pub params: u8,
+    pub code: Vec<u8>,
+}
+
+impl FunctionEntry {
+    /// The id transactions call a public procedure by, see `selector`.
+    pub fn selector(&self) -> u32 {
+        selector(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constant {
+    Uint128(u128),
+    String(String),
+    Address(Vec<u8>),
+    ByteArray(Vec<u8>),
+}
+
+/// A state variable and the slot the state opcodes address it by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateEntry {
+    pub index: u8,
+    pub name: String,
+    pub value_type: ValueType,
+}
+
+/// Where the opcode at `offset` in the code of `function` came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugLocation {
+    pub function: u8,
+    pub offset: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DebugInfo {
+    pub source: String, // Name of the main source file
+    pub locations: Vec<DebugLocation>,
+}
+
+/// A compiled contract as stored on disk:
+///
+/// ```text
+/// magic           4 bytes, `\0SEL`
+/// format version  u16
+/// compiler        string, the version of the compiler that wrote it
+/// section count   u8
+/// sections        id u8, length u32, content
+/// ```
+///
+/// Integers are little-endian, strings and byte arrays are prefixed with
+/// their length as a `u32`. The function table refers to the code section
+/// by offset and length, so code can be read without parsing the table.
+/// The code of each function directly follows that of the one before it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Container {
+    pub compiler_version: String,
+    pub functions: Vec<FunctionEntry>,
+    pub constants: Vec<Constant>,
+    pub state: Vec<StateEntry>,
+    pub debug: Option<DebugInfo>,
+}
+
+impl Container {
+    pub fn write(&self) -> Vec<u8> {
+        let mut table = Writer::default();
+        let mut code = Writer::default();
+        table.u32(self.functions.len());
+        for function in &self.functions {
+            let mut flags = 0;
+            if function.public {
+                flags |= FLAG_PUBLIC;
+            }
+            if function.mutates {
+                flags |= FLAG_MUTATES;
+            }
+            if function.constructor_only {
+                flags |= FLAG_CONSTRUCTOR_ONLY;
+            }
+
+            table.string(&function.name);
+            table.bytes.extend(function.selector().to_le_bytes());
+            table.bytes.push(flags);
+            table.bytes.push(function.params);
+            table.u32(code.bytes.len());
+            table.u32(function.code.len());
+            code.bytes.extend(&function.code);
+        }
+
+        let mut constants = Writer::default();
+        constants.u32(self.constants.len());
+        for constant in &self.constants {
+            match constant {
+                Constant::Uint128(value) => {
+                    constants.bytes.push(0);
+                    constants.bytes.extend(value.to_le_bytes());
+                }
+                Constant::String(value) => {
+                    constants.bytes.push(1);
+                    constants.string(value);
+                }
+                Constant::Address(value) => {
+                    constants.bytes.push(2);
+                    constants.byte_array(value);
+                }
+                Constant::ByteArray(value) => {
+                    constants.bytes.push(3);
+                    constants.byte_array(value);
+                }
+            }
+        }
+
+        let mut state = Writer::default();
+        state.u32(self.state.len());
+        for entry in &self.state {
+            state.bytes.push(entry.index);
+            state.string(&entry.name);
+            state.value_type(&entry.value_type);
+        }
+
+        let mut sections = vec![
+            (SECTION_FUNCTIONS, table),
+            (SECTION_CODE, code),
+            (SECTION_CONSTANTS, constants),
+            (SECTION_STATE, state),
+        ];
+        if let Some(debug) = &self.debug {
+            let mut writer = Writer::default();
+            writer.string(&debug.source);
+            writer.u32(debug.locations.len());
+            for location in &debug.locations {
+                writer.bytes.push(location.function);
+                writer.bytes.extend(location.offset.to_le_bytes());
+                writer.bytes.extend(location.line.to_le_bytes());
+                writer.bytes.extend(location.column.to_le_bytes());
+            }
+            sections.push((SECTION_DEBUG, writer));
+        }
+
+        let mut out = Writer::default();
+        out.bytes.extend(MAGIC);
+        out.bytes.extend(FORMAT_VERSION.to_le_bytes());
+        out.string(&self.compiler_version);
+        out.bytes.push(sections.len() as u8);
+        for (id, section) in sections {
+            out.bytes.push(id);
+            out.byte_array(&section.bytes);
+        }
+        out.bytes
+    }
+
+    /// Reads a container, rejecting anything that `write` would not produce
+    /// as well as functions whose selectors collide, as calls to them could
+    /// not be told apart.
+    pub fn read(bytes: &[u8]) -> Result<Self, ContainerError> {
+        let mut reader = Reader::new(bytes, 0);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(ContainerError::InvalidMagic);
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != FORMAT_VERSION {
+            return Err(ContainerError::UnsupportedVersion(version));
+        }
+        let compiler_version = reader.string()?;
+
+        let mut sections: [Option<Reader>; 5] = Default::default();
+        let mut previous = 0;
+        for _ in 0..reader.u8()? {
+            let id = reader.u8()?;
+            let length = reader.u32()?;
+            let offset = reader.offset();
+            let content = reader.take(length)?;
+
+            let Some(section) = sections.get_mut(usize::from(id).wrapping_sub(1)) else {
+                return Err(ContainerError::UnknownSection(id));
+            };
+            if section.is_some() {
+                return Err(ContainerError::DuplicateSection(id));
+            }
+            if id < previous {
+                return Err(ContainerError::SectionOutOfOrder(id));
+            }
+            previous = id;
+            *section = Some(Reader::new(content, offset));
+        }
+        reader.finish()?;
+
+        let [functions, code, constants, state, debug] = sections;
+        let mut functions = functions.ok_or(ContainerError::MissingSection(SECTION_FUNCTIONS))?;
+        let code = code.ok_or(ContainerError::MissingSection(SECTION_CODE))?;
+        let mut constants = constants.ok_or(ContainerError::MissingSection(SECTION_CONSTANTS))?;
+        let mut state = state.ok_or(ContainerError::MissingSection(SECTION_STATE))?;
+
+        let mut container = Container {
+            compiler_version,
+            ..Container::default()
+        };
+
+        let mut code_end = 0;
+        for _ in 0..functions.u32()? {
+            let name = functions.string()?;
+            let selector = u32::from_le_bytes(functions.array()?);
+            let flags = functions.u8()?;
+            let params = functions.u8()?;
+            let start = functions.u32()?;
+            let length = functions.u32()?;
+
+            if flags & !(FLAG_PUBLIC | FLAG_MUTATES | FLAG_CONSTRUCTOR_ONLY) != 0 {
+                return Err(ContainerError::InvalidFlags(name, flags));
+            }
+            let Some(body) = start
+                .checked_add(length)
+                .and_then(|end| code.bytes.get(start..end))
+            else {
+                return Err(ContainerError::CodeOutOfBounds(name));
+            };
+            if start < code_end {
+                return Err(ContainerError::CodeOverlap(name));
+            }
+            if start > code_end {
+                return Err(ContainerError::UnusedCode(code.offset() + code_end));
+            }
+            code_end += length;
+
+            let function = FunctionEntry {
+                public: flags & FLAG_PUBLIC != 0,
+                mutates: flags & FLAG_MUTATES != 0,
+                constructor_only: flags & FLAG_CONSTRUCTOR_ONLY != 0,
+                params,
+                code: body.to_vec(),
+                name,
+            };
+            if function.selector() != selector {
+                return Err(ContainerError::SelectorMismatch(function.name));
+            }
+            if let Some(other) = container
+                .functions
+                .iter()
+                .find(|f| f.selector() == selector)
+            {
+                let other = other.name.clone();
+                return Err(ContainerError::SelectorCollision(other, function.name));
+            }
+            container.functions.push(function);
+        }
+        functions.finish_section(SECTION_FUNCTIONS)?;
+        if code_end != code.bytes.len() {
+            return Err(ContainerError::UnusedCode(code.offset() + code_end));
+        }
+
+        for _ in 0..constants.u32()? {
+            let offset = constants.offset();
+            let constant = match constants.u8()? {
+                0 => Constant::Uint128(u128::from_le_bytes(constants.array()?)),
+                1 => Constant::String(constants.string()?),
+                2 => Constant::Address(constants.byte_array()?.to_vec()),
+                3 => Constant::ByteArray(constants.byte_array()?.to_vec()),
+                _ => return Err(ContainerError::InvalidConstant(offset)),
+            };
+            container.constants.push(constant);
+        }
+        constants.finish_section(SECTION_CONSTANTS)?;
+
+        for _ in 0..state.u32()? {
+            container.state.push(StateEntry {
+                index: state.u8()?,
+                name: state.string()?,
+                value_type: state.value_type(0)?,
+            });
+        }
+        state.finish_section(SECTION_STATE)?;
+
+        if let Some(mut debug) = debug {
+            let mut info = DebugInfo {
+                source: debug.string()?,
+                locations: Vec::new(),
+            };
+            for _ in 0..debug.u32()? {
+                info.locations.push(DebugLocation {
+                    function: debug.u8()?,
+                    offset: u32::from_le_bytes(debug.array()?),
+                    line: u32::from_le_bytes(debug.array()?),
+                    column: u32::from_le_bytes(debug.array()?),
+                });
+            }
+            debug.finish_section(SECTION_DEBUG)?;
+            container.debug = Some(info);
+        }
+
+        Ok(container)
+    }
+}
+
+/// The selector of a procedure, the 32-bit FNV-1a hash of its name.
+pub fn selector(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: usize) {
+        let value = u32::try_from(value).expect("containers are smaller than 4 GiB");
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn byte_array(&mut self, value: &[u8]) {
+        self.u32(value.len());
+        self.bytes.extend(value);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.byte_array(value.as_bytes());
+    }
+
+    fn value_type(&mut self, value_type: &ValueType) {
+        match value_type {
+            ValueType::U8 => self.bytes.push(0),
+            ValueType::U128 => self.bytes.push(1),
+            ValueType::Bool => self.bytes.push(2),
+            ValueType::String => self.bytes.push(3),
+            ValueType::Address => self.bytes.push(4),
+            ValueType::Array(element) => {
+                self.bytes.push(5);
+                self.value_type(element);
+            }
+            ValueType::Table(key, value) => {
+                self.bytes.push(6);
+                self.value_type(key);
+                self.value_type(value);
+            }
+        }
+    }
+}
+
+/// Reads from `bytes`, which start at `base` in the whole container so that
+/// errors point at the right byte.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    base: usize,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], base: usize) -> Self {
+        Reader {
+            bytes,
+            base,
+            pos: 0,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ContainerError> {
+        let Some(bytes) = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.pos..end))
+        else {
+            return Err(ContainerError::Truncated(self.base + self.bytes.len()));
+        };
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ContainerError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, ContainerError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, ContainerError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn byte_array(&mut self) -> Result<&'a [u8], ContainerError> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, ContainerError> {
+        let offset = self.offset();
+        let bytes = self.byte_array()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ContainerError::InvalidString(offset))
+    }
+
+    fn value_type(&mut self, depth: usize) -> Result<ValueType, ContainerError> {
+        let offset = self.offset();
+        if depth > MAX_TYPE_DEPTH {
+            return Err(ContainerError::InvalidType(offset));
+        }
+
+        Ok(match self.u8()? {
+            0 => ValueType::U8,
+            1 => ValueType::U128,
+            2 => ValueType::Bool,
+            3 => ValueType::String,
+            4 => ValueType::Address,
+            5 => ValueType::Array(Box::new(self.value_type(depth + 1)?)),
+            6 => ValueType::Table(
+                Box::new(self.value_type(depth + 1)?),
+                Box::new(self.value_type(depth + 1)?),
+            ),
+            _ => return Err(ContainerError::InvalidType(offset)),
+        })
+    }
+
+    fn finish(&self) -> Result<(), ContainerError> {
+        if self.pos == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(ContainerError::TrailingBytes(self.offset()))
+        }
+    }
+
+    fn finish_section(&self, id: u8) -> Result<(), ContainerError> {
+        self.finish()
+            .map_err(|_| ContainerError::SectionLengthMismatch(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container() -> Container {
+        Container {
+            compiler_version: "0.1.0".to_owned(),
+            functions: vec![
+                FunctionEntry {
+                    name: "init".to_owned(),
+                    public: false,
+                    mutates: true,
+                    constructor_only: true,
+                    params: 0,
+                    code: vec![0x0F],
+                },
+                FunctionEntry {
+                    name: "token.transfer".to_owned(),
+                    public: true,
+                    mutates: true,
+                    constructor_only: false,
+                    params: 2,
+                    code: vec![0x09, 1, 0, 0x09, 2, 1, 0x0F],
+                },
+            ],
+            constants: vec![
+                Constant::Uint128(10_000_000_000_000),
+                Constant::String("coolium".to_owned()),
+                Constant::Address(vec![7; 32]),
+                Constant::ByteArray(Vec::new()),
+            ],
+            state: vec![
+                StateEntry {
+                    index: 0,
+                    name: "creator".to_owned(),
+                    value_type: ValueType::Address,
+                },
+                StateEntry {
+                    index: 1,
+                    name: "token.allowances".to_owned(),
+                    value_type: ValueType::Table(
+                        Box::new(ValueType::Address),
+                        Box::new(ValueType::Table(
+                            Box::new(ValueType::Address),
+                            Box::new(ValueType::U128),
+                        )),
+                    ),
+                },
+            ],
+            debug: Some(DebugInfo {
+                source: "main.se".to_owned(),
+                locations: vec![DebugLocation {
+                    function: 1,
+                    offset: 3,
+                    line: 12,
+                    column: 5,
+                }],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let container = container();
+        let bytes = container.write();
+        assert_eq!(&bytes[..4], b"\0SEL");
+        assert_eq!(Container::read(&bytes), Ok(container.clone()));
+
+        let without_debug = Container {
+            debug: None,
+            ..container
+        };
+        assert_eq!(Container::read(&without_debug.write()), Ok(without_debug));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let bytes = container().write();
+
+        // Every cut is reported, never a panic or a partial container
+        for len in 0..bytes.len() {
+            assert!(Container::read(&bytes[..len]).is_err(), "cut at {}", len);
+        }
+        assert_eq!(
+            Container::read(&bytes[..10]),
+            Err(ContainerError::Truncated(10))
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Container::read(&trailing),
+            Err(ContainerError::TrailingBytes(bytes.len()))
+        );
+
+        let mut magic = bytes.clone();
+        magic[1] = b'X';
+        assert_eq!(Container::read(&magic), Err(ContainerError::InvalidMagic));
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(
+            Container::read(&version),
+            Err(ContainerError::UnsupportedVersion(9))
+        );
+
+        // The first section header follows the compiler version string
+        let section = 4 + 2 + 4 + "0.1.0".len() + 1;
+        let mut unknown = bytes.clone();
+        unknown[section] = 42;
+        assert_eq!(
+            Container::read(&unknown),
+            Err(ContainerError::UnknownSection(42))
+        );
+
+        let mut duplicate = bytes.clone();
+        duplicate[section] = SECTION_CODE;
+        assert_eq!(
+            Container::read(&duplicate),
+            Err(ContainerError::DuplicateSection(SECTION_CODE))
+        );
+
+        // The code section header follows the function table
+        let table_len = u32::from_le_bytes(bytes[section + 1..section + 5].try_into().unwrap());
+        let code_section = section + 5 + table_len as usize;
+        let mut swapped = bytes.clone();
+        swapped[section] = SECTION_CODE;
+        swapped[code_section] = SECTION_FUNCTIONS;
+        assert_eq!(
+            Container::read(&swapped),
+            Err(ContainerError::SectionOutOfOrder(SECTION_FUNCTIONS))
+        );
+
+        // Table: count, then per function its name, selector, flags, params,
+        // code offset and code length
+        let init_length = section + 5 + 4 + 4 + "init".len() + 4 + 1 + 1 + 4;
+        let transfer_start = init_length + 4 + 4 + "token.transfer".len() + 4 + 1 + 1;
+        let mut overlap = bytes.clone();
+        overlap[transfer_start] = 0;
+        assert_eq!(
+            Container::read(&overlap),
+            Err(ContainerError::CodeOverlap("token.transfer".to_owned()))
+        );
+        let mut unused = bytes.clone();
+        unused[init_length] = 0;
+        assert_eq!(
+            Container::read(&unused),
+            Err(ContainerError::UnusedCode(code_section + 5))
+        );
+
+        let mut collision = container();
+        collision.functions[1].name = "init".to_owned();
+        assert_eq!(
+            Container::read(&collision.write()),
+            Err(ContainerError::SelectorCollision(
+                "init".to_owned(),
+                "init".to_owned()
+            ))
+        );
+
+        let mut flags = container();
+        flags.functions.truncate(1);
+        let mut flagged = flags.write();
+        // Table: count, name length, "init", selector, then the flags
+        let flags_offset = section + 1 + 4 + 4 + 4 + "init".len() + 4;
+        assert_eq!(flagged[flags_offset], FLAG_MUTATES | FLAG_CONSTRUCTOR_ONLY);
+        flagged[flags_offset] = 0x80;
+        assert_eq!(
+            Container::read(&flagged),
+            Err(ContainerError::InvalidFlags("init".to_owned(), 0x80))
+        );
+        flagged[flags_offset - 1] ^= 1;
+        flagged[flags_offset] = 0;
+        assert_eq!(
+            Container::read(&flagged),
+            Err(ContainerError::SelectorMismatch("init".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_selector() {
+        assert_eq!(selector(""), 0x811c_9dc5);
+        assert_eq!(selector("a"), 0xe40c_292c);
+        assert_ne!(selector("transfer"), selector("token.transfer"));
+    }
+}
@@ -1,22 +1,32 @@
 use std::{error::Error, fmt};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum OpcodeError {
-    InvalidOpcode(u8),
+    InvalidOpcode(u8, usize), // The opcode byte and its offset
     InvalidOperand(u8),
-    OperandLenghtMismatch(usize, usize),
+    OperandLenghtMismatch(usize, usize, usize), // Expected, actual and the opcode's offset
+    InvalidJumpTarget(usize, usize),            // The target and the jump's offset
 }
 
 impl fmt::Display for OpcodeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            OpcodeError::InvalidOpcode(opcode) => write!(f, "Invalid opcode: {}", opcode),
+            OpcodeError::InvalidOpcode(opcode, offset) => {
+                write!(f, "Invalid opcode at byte {}: {}", offset, opcode)
+            }
             OpcodeError::InvalidOperand(operand) => write!(f, "Invalid operand: {}", operand),
-            OpcodeError::OperandLenghtMismatch(expected, actual) => {
+            OpcodeError::OperandLenghtMismatch(expected, actual, offset) => {
+                write!(
+                    f,
+                    "Operand length mismatch at byte {}: expected {}, got {}",
+                    offset, expected, actual
+                )
+            }
+            OpcodeError::InvalidJumpTarget(target, offset) => {
                 write!(
                     f,
-                    "Operand length mismatch: expected {}, got {}",
-                    expected, actual
+                    "Invalid jump target at byte {}: {} is not the start of an opcode",
+                    offset, target
                 )
             }
         }
@@ -26,9 +36,10 @@ impl fmt::Display for OpcodeError {
 impl Error for OpcodeError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match *self {
-            OpcodeError::InvalidOpcode(_) => None,
+            OpcodeError::InvalidOpcode(_, _) => None,
             OpcodeError::InvalidOperand(_) => None,
-            OpcodeError::OperandLenghtMismatch(_, _) => None,
+            OpcodeError::OperandLenghtMismatch(_, _, _) => None,
+            OpcodeError::InvalidJumpTarget(_, _) => None,
         }
     }
 }
@@ -74,3 +85,82 @@ impl Error for RegistryError {
         }
     }
 }
+
+#[derive(Debug, PartialEq)]
+pub enum ContainerError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    Truncated(usize),     // Offset at which more bytes were expected
+    TrailingBytes(usize), // Offset of the first unexpected byte
+    InvalidString(usize), // Offset of a string that is not UTF-8
+    UnknownSection(u8),
+    DuplicateSection(u8),
+    SectionOutOfOrder(u8),
+    MissingSection(u8),
+    SectionLengthMismatch(u8), // The section's content ends before its length does
+    InvalidType(usize),        // Offset of an unknown or too deeply nested type tag
+    InvalidFlags(String, u8),  // Function name and flags
+    SelectorMismatch(String),  // Function name
+    CodeOutOfBounds(String),   // Function name
+    CodeOverlap(String),       // Function name
+    UnusedCode(usize),         // Offset of the first byte no function's code covers
+    SelectorCollision(String, String), // Names of the two functions
+    InvalidConstant(usize),    // Offset of an unknown constant tag
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContainerError::InvalidMagic => {
+                write!(f, "Not a compiled contract: invalid magic bytes")
+            }
+            ContainerError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported container format version: {}", version)
+            }
+            ContainerError::Truncated(offset) => {
+                write!(f, "Unexpected end of contract at byte {}", offset)
+            }
+            ContainerError::TrailingBytes(offset) => {
+                write!(f, "Unexpected trailing bytes at byte {}", offset)
+            }
+            ContainerError::InvalidString(offset) => {
+                write!(f, "Invalid UTF-8 string at byte {}", offset)
+            }
+            ContainerError::UnknownSection(id) => write!(f, "Unknown section: {}", id),
+            ContainerError::DuplicateSection(id) => write!(f, "Duplicate section: {}", id),
+            ContainerError::SectionOutOfOrder(id) => write!(f, "Section {} is out of order", id),
+            ContainerError::MissingSection(id) => write!(f, "Missing section: {}", id),
+            ContainerError::SectionLengthMismatch(id) => {
+                write!(f, "Section {} does not match its length", id)
+            }
+            ContainerError::InvalidType(offset) => write!(f, "Invalid type at byte {}", offset),
+            ContainerError::InvalidFlags(ref function, flags) => {
+                write!(f, "Invalid flags for function {}: {:#04x}", function, flags)
+            }
+            ContainerError::SelectorMismatch(ref function) => {
+                write!(f, "Selector does not match function {}", function)
+            }
+            ContainerError::CodeOutOfBounds(ref function) => {
+                write!(f, "Code of function {} is out of bounds", function)
+            }
+            ContainerError::CodeOverlap(ref function) => {
+                write!(f, "Code of function {} overlaps other code", function)
+            }
+            ContainerError::UnusedCode(offset) => {
+                write!(f, "Code at byte {} belongs to no function", offset)
+            }
+            ContainerError::SelectorCollision(ref first, ref second) => {
+                write!(
+                    f,
+                    "Functions {} and {} have the same selector",
+                    first, second
+                )
+            }
+            ContainerError::InvalidConstant(offset) => {
+                write!(f, "Invalid constant at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl Error for ContainerError {}
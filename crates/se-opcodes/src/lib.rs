@@ -1,3 +1,4 @@
 pub mod codes;
+pub mod container;
 pub mod errors;
 pub mod registry;
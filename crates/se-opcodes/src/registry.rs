@@ -9,6 +9,20 @@ pub enum StateValue<'a> {
     String(&'a str),
     Bool(bool),
     ByteArray(Vec<u8>),
+    Map(HashMap<Vec<u8>, StateValue<'a>>), // Table entries by encoded key, nested tables by their keys joined
+}
+
+impl StateValue<'_> {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            StateValue::Uint8(_) => "u8",
+            StateValue::Uint128(_) => "u128",
+            StateValue::String(_) => "string",
+            StateValue::Bool(_) => "bool",
+            StateValue::ByteArray(_) => "bytes",
+            StateValue::Map(_) => "table",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -83,7 +97,7 @@ impl<'a> ExecutionContext<'a> {
     }
 
     // Function to handle GET_STATE, retrieving state by name and type
-    pub fn get_state(&self, key: &str) -> Result<&StateValue, RegistryError> {
+    pub fn get_state(&self, key: &str) -> Result<&StateValue<'_>, RegistryError> {
         match self.state.get(key) {
             Some(value) => Ok(value),
             None => Err(RegistryError::InvalidStateRegister(key.to_owned())),
@@ -105,6 +119,51 @@ impl<'a> ExecutionContext<'a> {
         }
     }
 
+    // Function to handle SMGET, reading an entry from a state map. Entries that
+    // were never set, including those of a map that was never written, read as
+    // `default` so contracts don't have to initialise every key.
+    pub fn get_map_entry(
+        &self,
+        key: &str,
+        entry: &[u8],
+        default: StateValue<'a>,
+    ) -> Result<StateValue<'a>, RegistryError> {
+        match self.state.get(key) {
+            Some(StateValue::Map(map)) => Ok(map.get(entry).cloned().unwrap_or(default)),
+            Some(other) => Err(RegistryError::TypeMismatch(
+                key.to_owned(),
+                "table".to_owned(),
+                other.type_name().to_owned(),
+            )),
+            None => Ok(default),
+        }
+    }
+
+    // Function to handle SMSET, storing a value in a state map
+    pub fn set_map_entry(
+        &mut self,
+        key: &str,
+        entry: Vec<u8>,
+        value: StateValue<'a>,
+    ) -> Result<(), RegistryError> {
+        let map = self
+            .state
+            .entry(key.into())
+            .or_insert_with(|| StateValue::Map(HashMap::new()));
+
+        match map {
+            StateValue::Map(map) => {
+                map.insert(entry, value);
+                Ok(())
+            }
+            other => Err(RegistryError::TypeMismatch(
+                key.to_owned(),
+                "table".to_owned(),
+                other.type_name().to_owned(),
+            )),
+        }
+    }
+
     pub fn malloc(&mut self, value: Value<'a>) -> usize {
         self.memory.push(value);
         self.memory.len() - 1
@@ -123,3 +182,39 @@ impl<'a> ExecutionContext<'a> {
         self.memory.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_entries_default() {
+        let mut context = ExecutionContext::new_empty();
+        let caller = vec![1, 2, 3];
+
+        // Reading from a map that was never written yields the default
+        let value = context
+            .get_map_entry("balances", &caller, StateValue::Uint128(0))
+            .unwrap();
+        assert!(matches!(value, StateValue::Uint128(0)));
+
+        context
+            .set_map_entry("balances", caller.clone(), StateValue::Uint128(50))
+            .unwrap();
+        let value = context
+            .get_map_entry("balances", &caller, StateValue::Uint128(0))
+            .unwrap();
+        assert!(matches!(value, StateValue::Uint128(50)));
+
+        let missing = context
+            .get_map_entry("balances", &[9], StateValue::Uint128(0))
+            .unwrap();
+        assert!(matches!(missing, StateValue::Uint128(0)));
+
+        context.set_state("supply", StateValue::Uint128(1)).unwrap();
+        assert!(matches!(
+            context.set_map_entry("supply", caller, StateValue::Bool(true)),
+            Err(RegistryError::TypeMismatch(..))
+        ));
+    }
+}
@@ -1,3 +1,131 @@
-fn main() {
-    println!("Hello, world!");
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use se_compiler::injector;
+use se_compiler::lexer::Lexer;
+use se_compiler::parser::Parser;
+use se_compiler::presets::{file, PresetRegistry};
+use se_compiler::semantics;
+use se_compiler::source::SourceMap;
+
+const USAGE: &str = "Usage: selc check [-I <dir>]... [-P <dir>]... <file.se>";
+
+/// The arguments of `check`. Include paths are searched for `$include`
+/// headers, preset paths for user-defined presets.
+struct CheckArgs<'a> {
+    path: &'a str,
+    include_paths: Vec<PathBuf>,
+    preset_paths: Vec<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.split_first() {
+        Some((command, rest)) if command == "check" => match parse_check_args(rest) {
+            Some(args) => check(&args),
+            None => usage(),
+        },
+        _ => usage(),
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::FAILURE
+}
+
+/// Splits the arguments of `check` into the contract path and search paths.
+fn parse_check_args(args: &[String]) -> Option<CheckArgs<'_>> {
+    let mut include_paths = Vec::new();
+    let mut preset_paths = Vec::new();
+    let mut path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-I" {
+            include_paths.push(PathBuf::from(args.next()?));
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            include_paths.push(PathBuf::from(dir));
+        } else if arg == "-P" {
+            preset_paths.push(PathBuf::from(args.next()?));
+        } else if let Some(dir) = arg.strip_prefix("-P") {
+            preset_paths.push(PathBuf::from(dir));
+        } else if path.is_none() {
+            path = Some(arg.as_str());
+        } else {
+            return None;
+        }
+    }
+
+    Some(CheckArgs {
+        path: path?,
+        include_paths,
+        preset_paths,
+    })
+}
+
+/// Parses a contract and its includes, reporting every syntax error found.
+/// Contracts that parse cleanly get their schemes injected and are checked
+/// for name and type errors. Presets are the built-in ones plus every preset
+/// file found on the preset paths.
+fn check(args: &CheckArgs) -> ExitCode {
+    let path = args.path;
+    let sources = SourceMap::new();
+    let file = match sources.load(Path::new(path)) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("error: could not read `{}`: {}", path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut registry = PresetRegistry::with_builtin();
+    let mut diagnostics = Vec::new();
+    for dir in &args.preset_paths {
+        match file::load_dir(&mut registry, dir, &sources) {
+            Ok(preset_diagnostics) => diagnostics.extend(preset_diagnostics),
+            Err(err) => {
+                eprintln!(
+                    "error: could not read preset path `{}`: {}",
+                    dir.display(),
+                    err
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let lexer = Lexer::new(&sources, file, &args.include_paths);
+    let (root, mut parse_diagnostics) = Parser::new(lexer).parse_partial();
+    diagnostics.append(&mut parse_diagnostics);
+
+    // Resolving a partial tree would mostly report names lost to syntax errors
+    if !diagnostics.iter().any(|d| d.is_error()) {
+        let (root, mut semantic_diagnostics) = injector::inject(root, &registry, &sources);
+        let (symbols, analyze_diagnostics) = semantics::analyze(&root, &sources);
+        semantic_diagnostics.extend(analyze_diagnostics);
+        let (_, type_diagnostics) = semantics::check_types(&root, &symbols, &sources);
+        semantic_diagnostics.extend(type_diagnostics);
+        semantic_diagnostics.sort_by_key(|d| (d.span.file, d.span.start));
+        diagnostics.append(&mut semantic_diagnostics);
+    }
+
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic.render());
+    }
+
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    if errors > 0 {
+        eprintln!(
+            "error: could not check `{}` due to {} previous error{}",
+            path,
+            errors,
+            if errors == 1 { "" } else { "s" }
+        );
+        return ExitCode::FAILURE;
+    }
+
+    println!("{}: no errors found", path);
+    ExitCode::SUCCESS
 }